@@ -0,0 +1,253 @@
+// Repeat annotations read from the RepeatMasker .out, GFF3 or BED.
+// All three are reduced to the same record, with 1-based inclusive
+// coordinates, so they can be used interchangeably for masking.
+
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use crate::{parse_rmout::RmOutTable, Error, ErrorKind, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnotationFormat {
+    RmOut,
+    Gff3,
+    Bed,
+}
+
+impl AnnotationFormat {
+    // Guess the format from the file extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy().to_lowercase();
+        if name.ends_with(".out") {
+            Some(AnnotationFormat::RmOut)
+        } else if name.ends_with(".gff") || name.ends_with(".gff3") {
+            Some(AnnotationFormat::Gff3)
+        } else if name.ends_with(".bed") {
+            Some(AnnotationFormat::Bed)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Annotation {
+    pub seqid: String,
+    // 1-based, inclusive
+    pub start: u64,
+    // 1-based, inclusive
+    pub end: u64,
+    // the repeat name, e.g. L1MA1 or (CA)n
+    pub repeat: String,
+    // e.g. LINE/L1, if the format records it
+    pub class_family: Option<String>,
+}
+
+impl Annotation {
+    // Zero for an empty interval.
+    pub fn length(&self) -> u64 {
+        (self.end + 1).saturating_sub(self.start)
+    }
+
+    // Simple repeats and low complexity regions, either from the
+    // class, or from the RepeatMasker naming when there is no class,
+    // e.g. (CA)n or A-rich.
+    pub fn is_simple_or_low_complexity(&self) -> bool {
+        match &self.class_family {
+            Some(c) => {
+                let class = c.split('/').next().unwrap_or(c);
+                matches!(class, "Simple_repeat" | "Low_complexity")
+            }
+            None => {
+                (self.repeat.starts_with('(') && self.repeat.ends_with(")n"))
+                    || self.repeat.ends_with("-rich")
+            }
+        }
+    }
+}
+
+pub fn read_annotations(path: &Path, format: AnnotationFormat) -> Result<Vec<Annotation>> {
+    match format {
+        AnnotationFormat::RmOut => {
+            let table = RmOutTable::from_file(path)?;
+            Ok(table
+                .0
+                .into_iter()
+                .map(|r| Annotation {
+                    seqid: r.query,
+                    start: r.query_start,
+                    end: r.query_end,
+                    repeat: r.repeat,
+                    class_family: Some(r.class_family),
+                })
+                .collect())
+        }
+        AnnotationFormat::Gff3 => read_gff(BufReader::new(File::open(path)?)),
+        AnnotationFormat::Bed => read_bed(BufReader::new(File::open(path)?)),
+    }
+}
+
+fn parse_error(format: &str, line_no: usize, msg: &str) -> Error {
+    Error::new(ErrorKind::GenericCli(format!(
+        "Error parsing {} at line {}: {}",
+        format, line_no, msg
+    )))
+}
+
+// Pull the repeat name and class out of the GFF attributes. This
+// handles the GFF3 written by rmOutToGFF3.pl (Target=L1MA1 1 100 +)
+// and the GFF2 written by RepeatMasker -gff (Target "Motif:L1MA1" 1 100).
+fn gff_repeat_and_class(attributes: &str) -> (String, Option<String>) {
+    let mut repeat = None;
+    let mut class = None;
+    for attr in attributes.split(';').map(str::trim) {
+        let (key, value) = match attr.split_once('=') {
+            Some(kv) => kv,
+            None => match attr.split_once(' ') {
+                Some(kv) => kv,
+                None => continue,
+            },
+        };
+        let value = value.trim().trim_matches('"');
+        match key {
+            "Target" => {
+                let target = value.split_whitespace().next().unwrap_or(value);
+                let target = target.trim_matches('"');
+                let target = target.strip_prefix("Motif:").unwrap_or(target);
+                // names may carry the class, e.g. L1MA1#LINE/L1
+                match target.split_once('#') {
+                    Some((name, c)) => {
+                        repeat = Some(name.to_string());
+                        class.get_or_insert(c.to_string());
+                    }
+                    None => repeat = Some(target.to_string()),
+                }
+            }
+            "Name" if repeat.is_none() => repeat = Some(value.to_string()),
+            "class" | "Class" | "classification" => class = Some(value.to_string()),
+            _ => (),
+        }
+    }
+    (repeat.unwrap_or_default(), class)
+}
+
+fn read_gff<R: BufRead>(reader: R) -> Result<Vec<Annotation>> {
+    let mut annotations = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 9 {
+            return Err(parse_error("GFF", i + 1, "expected 9 columns"));
+        }
+        let start = fields[3]
+            .parse()
+            .map_err(|_| parse_error("GFF", i + 1, "invalid start"))?;
+        let end = fields[4]
+            .parse()
+            .map_err(|_| parse_error("GFF", i + 1, "invalid end"))?;
+        if start > end {
+            return Err(parse_error("GFF", i + 1, "start after end"));
+        }
+        let (repeat, class_family) = gff_repeat_and_class(fields[8]);
+        annotations.push(Annotation {
+            seqid: fields[0].to_string(),
+            start,
+            end,
+            repeat,
+            class_family,
+        });
+    }
+    Ok(annotations)
+}
+
+fn read_bed<R: BufRead>(reader: R) -> Result<Vec<Annotation>> {
+    let mut annotations = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.starts_with('#')
+            || line.starts_with("track")
+            || line.starts_with("browser")
+            || line.trim().is_empty()
+        {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 3 {
+            return Err(parse_error("BED", i + 1, "expected at least 3 columns"));
+        }
+        // BED is 0-based, half open
+        let start: u64 = fields[1]
+            .parse()
+            .map_err(|_| parse_error("BED", i + 1, "invalid start"))?;
+        let end: u64 = fields[2]
+            .parse()
+            .map_err(|_| parse_error("BED", i + 1, "invalid end"))?;
+        // zero-length intervals cover no bases
+        match start.cmp(&end) {
+            Ordering::Greater => return Err(parse_error("BED", i + 1, "start after end")),
+            Ordering::Equal => continue,
+            Ordering::Less => (),
+        }
+        let name = fields.get(3).copied().unwrap_or_default();
+        let (repeat, class_family) = match name.split_once('#') {
+            Some((n, c)) => (n.to_string(), Some(c.to_string())),
+            None => (name.to_string(), None),
+        };
+        annotations.push(Annotation {
+            seqid: fields[0].to_string(),
+            start: start + 1,
+            end,
+            repeat,
+            class_family,
+        });
+    }
+    Ok(annotations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_gff_and_bed() {
+        let gff = "##gff-version 3
+chr1\tRepeatMasker\tdispersed_repeat\t11\t72\t463\t+\t.\tID=1;Target=(CCCTAA)n 1 60
+chr1\tRepeatMasker\tsimilarity\t101\t330\t1320\t-\t.\tTarget \"Motif:MER7A\" 103 336
+";
+        let annotations = read_gff(gff.as_bytes()).unwrap();
+        assert_eq!(annotations.len(), 2);
+        assert_eq!(annotations[0].repeat, "(CCCTAA)n");
+        assert!(annotations[0].is_simple_or_low_complexity());
+        assert_eq!(annotations[1].repeat, "MER7A");
+        assert_eq!((annotations[1].start, annotations[1].end), (101, 330));
+
+        let bed = "chr1\t10\t72\tL1MA1#LINE/L1\nchr1\t100\t130\tA-rich\n";
+        let annotations = read_bed(bed.as_bytes()).unwrap();
+        assert_eq!((annotations[0].start, annotations[0].end), (11, 72));
+        assert_eq!(annotations[0].class_family.as_deref(), Some("LINE/L1"));
+        assert!(!annotations[0].is_simple_or_low_complexity());
+        assert!(annotations[1].is_simple_or_low_complexity());
+
+        // an empty interval is skipped, a reversed one rejected
+        let bed = "chr1\t10\t10\tL1MA1\nchr1\t20\t25\tL1MA2\n";
+        let annotations = read_bed(bed.as_bytes()).unwrap();
+        assert_eq!(annotations.len(), 1);
+        assert_eq!(annotations[0].length(), 5);
+        assert!(read_bed("chr1\t30\t20\tL1MA1\n".as_bytes()).is_err());
+        let gff = "chr1\tRM\tsimilarity\t30\t20\t1\t+\t.\tTarget=L1MA1 1 10\n";
+        assert!(read_gff(gff.as_bytes()).is_err());
+        let empty = Annotation {
+            start: 11,
+            end: 10,
+            ..annotations[0].clone()
+        };
+        assert_eq!(empty.length(), 0);
+    }
+}
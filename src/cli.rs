@@ -1,7 +1,44 @@
-use clap::{arg, command, value_parser, ArgAction};
+use clap::{arg, command, value_parser, ArgAction, ArgMatches, Command};
 
-use crate::{error::Result, Error, ErrorKind};
-use std::path::PathBuf;
+use crate::{
+    annotation::AnnotationFormat,
+    consensus::{ConsensusOptions, GapHandling},
+    curate::{BeeOptions, ExtractOptions},
+    decontam::DecontamOptions,
+    dedup::{ClusterMethod, DedupOptions, Representative},
+    edges::EdgeOptions,
+    error::Result,
+    library::LibraryFormat,
+    mafft::{MafftOptions, MafftStrategy},
+    mask::{MaskMode, MaskOptions},
+    qc::QcOptions,
+    structure::StructureOptions,
+    Error, ErrorKind,
+};
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+// the command to run: the full pipeline, or one
+// of the subcommands
+#[derive(Debug, Clone)]
+pub enum RepCommand {
+    Pipeline(CliArgs),
+    MaskFasta(MaskFastaArgs),
+    Curate(CurateArgs),
+    Library(LibraryCommand),
+}
+
+// the `rep library` subcommands
+#[derive(Debug, Clone)]
+pub enum LibraryCommand {
+    Rename(LibraryRenameArgs),
+    Dedup(LibraryDedupArgs),
+    Decontaminate(LibraryDecontamArgs),
+    Merge(LibraryMergeArgs),
+    Convert(LibraryConvertArgs),
+}
 
 // a struct to contain all the CliArgs
 // at the moment we only want the path
@@ -20,13 +57,898 @@ pub struct CliArgs {
     pub rma_threads: u8,
     // run repeat masker only
     pub rma_only: bool,
+    // run the curation pipeline only, against this genome
+    pub curation_only: Option<PathBuf>,
+    // the RepeatModeler library to curate
+    pub curation_rmdl_library: Option<PathBuf>,
     // verbose flag
     pub verbose: bool,
 }
 
-pub fn parse_args() -> Result<CliArgs> {
+// arguments for `rep mask-fasta`
+#[derive(Debug, Clone)]
+pub struct MaskFastaArgs {
+    // the original genome, used to find the staged copy
+    pub fasta_file: PathBuf,
+    // the configured directory
+    pub configure: PathBuf,
+    // the .out, GFF3 or BED annotation
+    pub annotation: PathBuf,
+    // the annotation format, guessed from the extension if not given
+    pub format: Option<AnnotationFormat>,
+    pub options: MaskOptions,
+    // defaults to results/<genome>.<mode>masked.fa
+    pub output: Option<PathBuf>,
+}
+
+// arguments for `rep curate`
+#[derive(Debug, Clone)]
+pub struct CurateArgs {
+    // the original genome, used to find the staged copy
+    pub fasta_file: PathBuf,
+    // the configured directory
+    pub configure: PathBuf,
+    // the RepeatModeler library, found in the configured
+    // directory if not given
+    pub library: Option<PathBuf>,
+    // blastn threads
+    pub threads: u8,
+    // blastn e-value cutoff
+    pub evalue: f64,
+    // which copies to extract, and their flanks
+    pub extract: ExtractOptions,
+    // how to align the copies
+    pub mafft: MafftOptions,
+    // how to call the new consensi from the alignments
+    pub consensus: ConsensusOptions,
+    // how long to keep extending the families
+    pub bee: BeeOptions,
+    // how to find the ends of the element in the alignments
+    pub edges: EdgeOptions,
+    // terminal repeat and TSD detection
+    pub structure: StructureOptions,
+    // tail and ORF checks
+    pub qc: QcOptions,
+}
+
+// arguments for `rep library rename`
+#[derive(Debug, Clone)]
+pub struct LibraryRenameArgs {
+    // the library to rename, found in the configured
+    // directory if not given
+    pub library: Option<PathBuf>,
+    // the configured directory, whose outputs are also renamed
+    pub configure: Option<PathBuf>,
+    // the species code the families are prefixed with
+    pub prefix: String,
+    // defaults to <prefix>-families.fa next to the library
+    pub output: Option<PathBuf>,
+    // other outputs to rename the families in
+    pub outputs: Vec<PathBuf>,
+}
+
+// arguments for `rep library dedup`
+#[derive(Debug, Clone)]
+pub struct LibraryDedupArgs {
+    // the library to cluster, found in the configured
+    // directory if not given
+    pub library: Option<PathBuf>,
+    pub configure: Option<PathBuf>,
+    // defaults to <library>.dedup.fa
+    pub output: Option<PathBuf>,
+    pub options: DedupOptions,
+}
+
+// arguments for `rep library decontaminate`
+#[derive(Debug, Clone)]
+pub struct LibraryDecontamArgs {
+    // the library to screen, found in the configured
+    // directory if not given
+    pub library: Option<PathBuf>,
+    pub configure: Option<PathBuf>,
+    // defaults to <library>.decontam.fa
+    pub output: Option<PathBuf>,
+    // host proteins, searched with blastx
+    pub proteins: Option<PathBuf>,
+    // host transcripts, searched with blastn
+    pub transcripts: Option<PathBuf>,
+    // TE proteins, e.g. RepeatPeps.lib, searched with blastx
+    pub te_proteins: Option<PathBuf>,
+    pub options: DecontamOptions,
+}
+
+// arguments for `rep library merge`
+#[derive(Debug, Clone)]
+pub struct LibraryMergeArgs {
+    // the de novo library, found in the configured
+    // directory if not given
+    pub library: Option<PathBuf>,
+    pub configure: Option<PathBuf>,
+    // Dfam/RepBase FASTA or EMBL libraries, in order of precedence
+    pub curated: Vec<PathBuf>,
+    // defaults to the library RepeatMasker is run with
+    pub output: Option<PathBuf>,
+    // drop de novo families redundant with curated ones
+    pub remove_redundant: bool,
+    // the 80-80-80 thresholds for redundancy
+    pub dedup: DedupOptions,
+}
+
+// arguments for `rep library convert`
+#[derive(Debug, Clone)]
+pub struct LibraryConvertArgs {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    // taken from the extensions if not given
+    pub from: Option<LibraryFormat>,
+    pub to: Option<LibraryFormat>,
+}
+
+fn mask_fasta_command() -> Command {
+    Command::new("mask-fasta")
+        .about("Mask the staged genome from a RepeatMasker .out, GFF3 or BED annotation.")
+        .arg(
+            arg!(<FASTA> "The input genome, as passed to the pipeline.")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(-c --configure <CONFIG_PATH> "The configured directory containing the staged genome.")
+                .required(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(-a --annotation <ANNOTATION> "Annotation to mask from (.out, .gff3 or .bed).")
+                .required(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--format <FORMAT> "Annotation format, if it can't be guessed from the extension.")
+                .value_parser(["out", "gff3", "bed"]),
+        )
+        .arg(
+            arg!(-m --mode <MODE> "Soft mask (lowercase), hard mask (N) or mask to X.")
+                .default_value("soft")
+                .value_parser(["soft", "hard", "x"]),
+        )
+        .arg(
+            arg!(--exclude_simple "Do not mask Simple_repeat and Low_complexity annotations.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--min_length <MIN_LENGTH> "Minimum annotation length to mask.")
+                .default_value("0")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(-o --output <OUTPUT> "Output FASTA. Defaults to the results directory.")
+                .value_parser(value_parser!(PathBuf)),
+        )
+}
+
+fn parse_mask_fasta(matches: &ArgMatches) -> Result<MaskFastaArgs> {
+    let fasta_file = matches
+        .get_one::<PathBuf>("FASTA")
+        .cloned()
+        .expect("errored by clap");
+    let configure = matches
+        .get_one::<PathBuf>("configure")
+        .cloned()
+        .expect("errored by clap");
+    let annotation = matches
+        .get_one::<PathBuf>("annotation")
+        .cloned()
+        .expect("errored by clap");
+
+    if !annotation.exists() {
+        return Err(Error::new(ErrorKind::GenericCli(
+            "Annotation file not found".into(),
+        )));
+    }
+
+    let format = matches
+        .get_one::<String>("format")
+        .map(|f| match f.as_str() {
+            "out" => AnnotationFormat::RmOut,
+            "gff3" => AnnotationFormat::Gff3,
+            _ => AnnotationFormat::Bed,
+        });
+
+    let mode = match matches
+        .get_one::<String>("mode")
+        .expect("errored by clap")
+        .as_str()
+    {
+        "hard" => MaskMode::Hard,
+        "x" => MaskMode::X,
+        _ => MaskMode::Soft,
+    };
+
+    let options = MaskOptions {
+        mode,
+        exclude_simple: matches.get_flag("exclude_simple"),
+        min_length: matches
+            .get_one::<u64>("min_length")
+            .cloned()
+            .expect("errored by clap"),
+    };
+
+    Ok(MaskFastaArgs {
+        fasta_file,
+        configure,
+        annotation,
+        format,
+        options,
+        output: matches.get_one::<PathBuf>("output").cloned(),
+    })
+}
+
+fn curate_command() -> Command {
+    Command::new("curate")
+        .about("Curate the RepeatModeler consensi: BLAST them against the staged genome, extract and align the copies, and extend the consensi.")
+        .arg(
+            arg!(<FASTA> "The input genome, as passed to the pipeline.")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(-c --configure <CONFIG_PATH> "The configured directory containing the staged genome.")
+                .required(true)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(-l --library <LIBRARY> "The RepeatModeler library. Defaults to the consensi.fa.classified in the configured directory.")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(-t --threads <THREADS> "Number of threads to use for blastn.")
+                .default_value("8")
+                .value_parser(value_parser!(u8)),
+        )
+        .arg(
+            arg!(--evalue <EVALUE> "Maximum e-value of the blastn hits.")
+                .default_value("1e-10")
+                .value_parser(value_parser!(f64)),
+        )
+        .arg(
+            arg!(--top_n <TOP_N> "Number of copies of each consensus to extract, best bitscore first.")
+                .default_value("20")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            arg!(--min_coverage <MIN_COVERAGE> "Only extract copies covering this fraction (0-1) of the consensus.")
+                .value_parser(value_parser!(f64)),
+        )
+        .arg(
+            arg!(--left_flank <LEFT_FLANK> "Bases to extract to the left of each copy, relative to the consensus.")
+                .default_value("1000")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(--right_flank <RIGHT_FLANK> "Bases to extract to the right of each copy, relative to the consensus.")
+                .default_value("1000")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(--mafft_strategy <STRATEGY> "MAFFT strategy: --auto, or E-INS-i (--genafpair).")
+                .default_value("auto")
+                .value_parser(["auto", "einsi"]),
+        )
+        .arg(
+            arg!(--max_iterate <MAX_ITERATE> "Iterative refinement cycles for E-INS-i.")
+                .default_value("1000")
+                .value_parser(value_parser!(u32)),
+        )
+        .arg(
+            arg!(--adjust_direction "Let MAFFT reverse complement copies in the wrong orientation.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--min_occupancy <MIN_OCCUPANCY> "Leave out alignment columns where fewer than this fraction of copies have a base.")
+                .default_value("0.5")
+                .value_parser(value_parser!(f64)),
+        )
+        .arg(
+            arg!(--majority <MAJORITY> "Fraction of a column the most common base must reach, otherwise N is called.")
+                .default_value("0.5")
+                .value_parser(value_parser!(f64)),
+        )
+        .arg(
+            arg!(--iupac "Call ties as IUPAC ambiguity codes rather than N.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--count_gaps "Count gaps towards the majority, leaving out columns where the gap is most common.")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--trim_ends <OCCUPANCY> "Trim alignment columns from either end until one has at least this occupancy.")
+                .value_parser(value_parser!(f64)),
+        )
+        .arg(
+            arg!(--max_iterations <MAX_ITERATIONS> "Maximum rounds of blast, extract and extend per family.")
+                .default_value("5")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            arg!(--min_extension <MIN_EXTENSION> "Stop extending a family once a round adds fewer bases than this.")
+                .default_value("10")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(--edge_window <EDGE_WINDOW> "Alignment columns averaged over when looking for the ends of an element.")
+                .default_value("10")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            arg!(--min_conservation <MIN_CONSERVATION> "Mean conservation of a window inside an element.")
+                .default_value("0.75")
+                .value_parser(value_parser!(f64)),
+        )
+        .arg(
+            arg!(--min_terminal <MIN_TERMINAL> "Minimum length of a terminal inverted or long terminal repeat.")
+                .default_value("10")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            arg!(--max_tsd <MAX_TSD> "Maximum length of a target site duplication.")
+                .default_value("20")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            arg!(--min_tail <MIN_TAIL> "Minimum length of a poly-A or simple repeat tail.")
+                .default_value("10")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            arg!(--min_orf <MIN_ORF> "Minimum length of an ORF, in codons.")
+                .default_value("300")
+                .value_parser(value_parser!(usize)),
+        )
+}
+
+// The `rep curate` arguments for `--curation_only`, so the pipeline
+// curates with the same defaults as the subcommand.
+pub(crate) fn curation_args(
+    genome: &Path,
+    configure: &Path,
+    library: Option<&Path>,
+    threads: u8,
+) -> Result<CurateArgs> {
+    let mut argv = vec![
+        OsString::from("curate"),
+        genome.into(),
+        "--configure".into(),
+        configure.into(),
+        "--threads".into(),
+        threads.to_string().into(),
+    ];
+    if let Some(library) = library {
+        argv.extend(["--library".into(), library.into()]);
+    }
+    let matches = curate_command()
+        .try_get_matches_from(argv)
+        .map_err(|e| Error::new(ErrorKind::GenericCli(e.to_string())))?;
+    parse_curate(&matches)
+}
+
+fn parse_curate(matches: &ArgMatches) -> Result<CurateArgs> {
+    let fasta_file = matches
+        .get_one::<PathBuf>("FASTA")
+        .cloned()
+        .expect("errored by clap");
+    let configure = matches
+        .get_one::<PathBuf>("configure")
+        .cloned()
+        .expect("errored by clap");
+    let library = matches.get_one::<PathBuf>("library").cloned();
+
+    if let Some(library) = &library {
+        if !library.exists() {
+            return Err(Error::new(ErrorKind::GenericCli(
+                "RepeatModeler library not found".into(),
+            )));
+        }
+    }
+
+    let threads = matches
+        .get_one::<u8>("threads")
+        .cloned()
+        .expect("errored by clap");
+
+    let strategy = match matches
+        .get_one::<String>("mafft_strategy")
+        .expect("errored by clap")
+        .as_str()
+    {
+        "einsi" => MafftStrategy::Einsi {
+            max_iterate: matches
+                .get_one::<u32>("max_iterate")
+                .cloned()
+                .expect("errored by clap"),
+        },
+        _ => MafftStrategy::Auto,
+    };
+
+    Ok(CurateArgs {
+        fasta_file,
+        configure,
+        library,
+        threads,
+        evalue: matches
+            .get_one::<f64>("evalue")
+            .cloned()
+            .expect("errored by clap"),
+        extract: ExtractOptions {
+            top_n: matches
+                .get_one::<usize>("top_n")
+                .cloned()
+                .expect("errored by clap"),
+            min_coverage: matches.get_one::<f64>("min_coverage").cloned(),
+            left_flank: matches
+                .get_one::<u64>("left_flank")
+                .cloned()
+                .expect("errored by clap"),
+            right_flank: matches
+                .get_one::<u64>("right_flank")
+                .cloned()
+                .expect("errored by clap"),
+        },
+        mafft: MafftOptions {
+            strategy,
+            adjust_direction: matches.get_flag("adjust_direction"),
+            threads,
+        },
+        consensus: ConsensusOptions {
+            min_occupancy: matches
+                .get_one::<f64>("min_occupancy")
+                .cloned()
+                .expect("errored by clap"),
+            majority: matches
+                .get_one::<f64>("majority")
+                .cloned()
+                .expect("errored by clap"),
+            iupac: matches.get_flag("iupac"),
+            gaps: if matches.get_flag("count_gaps") {
+                GapHandling::Count
+            } else {
+                GapHandling::Ignore
+            },
+            trim_ends: matches.get_one::<f64>("trim_ends").cloned(),
+        },
+        bee: BeeOptions {
+            max_iterations: matches
+                .get_one::<usize>("max_iterations")
+                .cloned()
+                .expect("errored by clap"),
+            min_extension: matches
+                .get_one::<u64>("min_extension")
+                .cloned()
+                .expect("errored by clap"),
+            ..Default::default()
+        },
+        edges: EdgeOptions {
+            window: matches
+                .get_one::<usize>("edge_window")
+                .cloned()
+                .expect("errored by clap"),
+            min_conservation: matches
+                .get_one::<f64>("min_conservation")
+                .cloned()
+                .expect("errored by clap"),
+            ..Default::default()
+        },
+        structure: StructureOptions {
+            min_terminal: matches
+                .get_one::<usize>("min_terminal")
+                .cloned()
+                .expect("errored by clap"),
+            max_tsd: matches
+                .get_one::<usize>("max_tsd")
+                .cloned()
+                .expect("errored by clap"),
+            ..Default::default()
+        },
+        qc: QcOptions {
+            min_tail: matches
+                .get_one::<usize>("min_tail")
+                .cloned()
+                .expect("errored by clap"),
+            min_orf: matches
+                .get_one::<usize>("min_orf")
+                .cloned()
+                .expect("errored by clap"),
+            ..Default::default()
+        },
+    })
+}
+
+fn library_command() -> Command {
+    Command::new("library")
+        .about("Work with RepeatModeler/RepeatMasker TE libraries.")
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(
+            Command::new("rename")
+                .about("Prefix the family names with a species code, here and in the outputs already written.")
+                .arg(
+                    arg!(-p --prefix <PREFIX> "The species code, e.g. dmel for dmel_rnd-1_family-12.")
+                        .required(true)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!(-l --library <LIBRARY> "The library to rename. Defaults to the consensi.fa.classified in the configured directory.")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-c --configure <CONFIG_PATH> "The configured directory. Its .out, GFF and BLAST outputs are renamed too.")
+                        .required_unless_present("library")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-o --output <OUTPUT> "Output library. Defaults to <prefix>-families.fa next to the library.")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--outputs <OUTPUTS> "Other .out, GFF or BLAST (.tsv) outputs to rename the families in.")
+                        .num_args(1..)
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            Command::new("dedup")
+                .about("Cluster the consensi by the 80-80-80 rule and keep a representative of each cluster.")
+                .arg(
+                    arg!(-l --library <LIBRARY> "The library to cluster. Defaults to the consensi.fa.classified in the configured directory.")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-c --configure <CONFIG_PATH> "The configured directory to find the library in.")
+                        .required_unless_present("library")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-o --output <OUTPUT> "Output library. Defaults to <library>.dedup.fa.")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--method <METHOD> "Cluster from an all-vs-all blastn, or with cd-hit-est.")
+                        .default_value("blastn")
+                        .value_parser(["blastn", "cd-hit-est"]),
+                )
+                .arg(
+                    arg!(--representative <REPRESENTATIVE> "Keep the longest family of a cluster, or the best classified.")
+                        .default_value("longest")
+                        .value_parser(["longest", "classified"]),
+                )
+                .arg(
+                    arg!(--min_identity <MIN_IDENTITY> "Minimum percent identity.")
+                        .default_value("80")
+                        .value_parser(value_parser!(f64)),
+                )
+                .arg(
+                    arg!(--min_coverage <MIN_COVERAGE> "Minimum fraction of the shorter consensus aligned.")
+                        .default_value("0.8")
+                        .value_parser(value_parser!(f64)),
+                )
+                .arg(
+                    arg!(--min_length <MIN_LENGTH> "Minimum alignment length (blastn only).")
+                        .default_value("80")
+                        .value_parser(value_parser!(u64)),
+                )
+                .arg(
+                    arg!(--evalue <EVALUE> "blastn e-value cutoff.")
+                        .default_value("1e-10")
+                        .value_parser(value_parser!(f64)),
+                )
+                .arg(
+                    arg!(-t --threads <THREADS> "Number of threads for blastn or cd-hit-est.")
+                        .default_value("8")
+                        .value_parser(value_parser!(u8)),
+                ),
+        )
+        .subcommand(
+            Command::new("decontaminate")
+                .about("Remove families hitting host proteins or transcripts, unless they also hit TE proteins.")
+                .arg(
+                    arg!(-l --library <LIBRARY> "The library to screen. Defaults to the consensi.fa.classified in the configured directory.")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-c --configure <CONFIG_PATH> "The configured directory to find the library in.")
+                        .required_unless_present("library")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-o --output <OUTPUT> "Output library. Defaults to <library>.decontam.fa.")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--proteins <PROTEINS> "Host proteins to screen against with blastx.")
+                        .required_unless_present("transcripts")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--transcripts <TRANSCRIPTS> "Host transcripts to screen against with blastn.")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--te_proteins <TE_PROTEINS> "TE proteins, e.g. RepeatPeps.lib. Families hitting these are kept.")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--evalue <EVALUE> "E-value cutoff for a gene hit.")
+                        .default_value("1e-10")
+                        .value_parser(value_parser!(f64)),
+                )
+                .arg(
+                    arg!(--min_bitscore <MIN_BITSCORE> "Minimum bitscore of a gene hit.")
+                        .default_value("100")
+                        .value_parser(value_parser!(f64)),
+                )
+                .arg(
+                    arg!(--te_evalue <TE_EVALUE> "E-value cutoff for a TE protein hit.")
+                        .default_value("1e-5")
+                        .value_parser(value_parser!(f64)),
+                )
+                .arg(
+                    arg!(--flag_only "Keep families with gene hits in the library, only reporting them.")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(-t --threads <THREADS> "Number of threads for BLAST.")
+                        .default_value("8")
+                        .value_parser(value_parser!(u8)),
+                ),
+        )
+        .subcommand(
+            Command::new("merge")
+                .about("Merge the de novo library with curated Dfam/RepBase libraries into the library RepeatMasker is run with.")
+                .arg(
+                    arg!(-l --library <LIBRARY> "The de novo library. Defaults to the consensi.fa.classified in the configured directory.")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-c --configure <CONFIG_PATH> "The configured directory. The merged library is written to data/repeatmasker/merged_library.fa.")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--curated <CURATED> "Curated FASTA or EMBL (.embl/.dat) libraries, in order of precedence.")
+                        .required(true)
+                        .num_args(1..)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-o --output <OUTPUT> "Output library. Defaults to the library RepeatMasker is run with.")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--remove_redundant "Drop de novo families that pass the 80-80-80 rule against a curated family.")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--min_identity <MIN_IDENTITY> "Minimum percent identity for redundancy.")
+                        .default_value("80")
+                        .value_parser(value_parser!(f64)),
+                )
+                .arg(
+                    arg!(--min_coverage <MIN_COVERAGE> "Minimum fraction of the shorter consensus aligned for redundancy.")
+                        .default_value("0.8")
+                        .value_parser(value_parser!(f64)),
+                )
+                .arg(
+                    arg!(--min_length <MIN_LENGTH> "Minimum alignment length for redundancy.")
+                        .default_value("80")
+                        .value_parser(value_parser!(u64)),
+                )
+                .arg(
+                    arg!(-t --threads <THREADS> "Number of threads for blastn.")
+                        .default_value("8")
+                        .value_parser(value_parser!(u8)),
+                ),
+        )
+        .subcommand(
+            Command::new("convert")
+                .about("Convert a library between FASTA, EMBL and Stockholm, keeping seed alignments.")
+                .arg(
+                    arg!(-l --library <LIBRARY> "The library to convert.")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-o --output <OUTPUT> "Output library. Seed alignments go to <output>.seeds.stk unless it is Stockholm.")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--from <FORMAT> "Input format. Defaults to the extension (.embl/.dat, .stk/.sto, otherwise FASTA).")
+                        .value_parser(["fasta", "embl", "stockholm"]),
+                )
+                .arg(
+                    arg!(--to <FORMAT> "Output format. Defaults to the extension.")
+                        .value_parser(["fasta", "embl", "stockholm"]),
+                ),
+        )
+}
+
+fn parse_library(matches: &ArgMatches) -> Result<LibraryCommand> {
+    match matches.subcommand() {
+        Some(("rename", sub_matches)) => {
+            let outputs = sub_matches
+                .get_many::<PathBuf>("outputs")
+                .map(|o| o.cloned().collect::<Vec<_>>())
+                .unwrap_or_default();
+            if let Some(missing) = outputs.iter().find(|o| !o.exists()) {
+                return Err(Error::new(ErrorKind::GenericCli(format!(
+                    "Output to rename not found: {}",
+                    missing.display()
+                ))));
+            }
+            Ok(LibraryCommand::Rename(LibraryRenameArgs {
+                library: sub_matches.get_one::<PathBuf>("library").cloned(),
+                configure: sub_matches.get_one::<PathBuf>("configure").cloned(),
+                prefix: sub_matches
+                    .get_one::<String>("prefix")
+                    .cloned()
+                    .expect("errored by clap"),
+                output: sub_matches.get_one::<PathBuf>("output").cloned(),
+                outputs,
+            }))
+        }
+        Some(("dedup", sub_matches)) => {
+            let get = |name: &str| {
+                sub_matches
+                    .get_one::<String>(name)
+                    .expect("errored by clap")
+                    .as_str()
+            };
+            let options = DedupOptions {
+                method: match get("method") {
+                    "cd-hit-est" => ClusterMethod::CdHitEst,
+                    _ => ClusterMethod::Blastn,
+                },
+                representative: match get("representative") {
+                    "classified" => Representative::BestClassified,
+                    _ => Representative::Longest,
+                },
+                min_identity: sub_matches
+                    .get_one::<f64>("min_identity")
+                    .cloned()
+                    .expect("errored by clap"),
+                min_coverage: sub_matches
+                    .get_one::<f64>("min_coverage")
+                    .cloned()
+                    .expect("errored by clap"),
+                min_length: sub_matches
+                    .get_one::<u64>("min_length")
+                    .cloned()
+                    .expect("errored by clap"),
+                evalue: sub_matches
+                    .get_one::<f64>("evalue")
+                    .cloned()
+                    .expect("errored by clap"),
+                threads: sub_matches
+                    .get_one::<u8>("threads")
+                    .cloned()
+                    .expect("errored by clap"),
+            };
+            Ok(LibraryCommand::Dedup(LibraryDedupArgs {
+                library: sub_matches.get_one::<PathBuf>("library").cloned(),
+                configure: sub_matches.get_one::<PathBuf>("configure").cloned(),
+                output: sub_matches.get_one::<PathBuf>("output").cloned(),
+                options,
+            }))
+        }
+        Some(("decontaminate", sub_matches)) => {
+            let set = |name: &str| -> Result<Option<PathBuf>> {
+                match sub_matches.get_one::<PathBuf>(name).cloned() {
+                    Some(path) if !path.exists() => Err(Error::new(ErrorKind::GenericCli(
+                        format!("{} not found", path.display()),
+                    ))),
+                    path => Ok(path),
+                }
+            };
+            let f64_arg = |name: &str| {
+                sub_matches
+                    .get_one::<f64>(name)
+                    .cloned()
+                    .expect("errored by clap")
+            };
+            Ok(LibraryCommand::Decontaminate(LibraryDecontamArgs {
+                library: sub_matches.get_one::<PathBuf>("library").cloned(),
+                configure: sub_matches.get_one::<PathBuf>("configure").cloned(),
+                output: sub_matches.get_one::<PathBuf>("output").cloned(),
+                proteins: set("proteins")?,
+                transcripts: set("transcripts")?,
+                te_proteins: set("te_proteins")?,
+                options: DecontamOptions {
+                    evalue: f64_arg("evalue"),
+                    min_bitscore: f64_arg("min_bitscore"),
+                    te_evalue: f64_arg("te_evalue"),
+                    flag_only: sub_matches.get_flag("flag_only"),
+                    threads: sub_matches
+                        .get_one::<u8>("threads")
+                        .cloned()
+                        .expect("errored by clap"),
+                },
+            }))
+        }
+        Some(("merge", sub_matches)) => {
+            let curated = sub_matches
+                .get_many::<PathBuf>("curated")
+                .expect("errored by clap")
+                .cloned()
+                .collect::<Vec<_>>();
+            if let Some(missing) = curated.iter().find(|c| !c.exists()) {
+                return Err(Error::new(ErrorKind::GenericCli(format!(
+                    "Curated library not found: {}",
+                    missing.display()
+                ))));
+            }
+            Ok(LibraryCommand::Merge(LibraryMergeArgs {
+                library: sub_matches.get_one::<PathBuf>("library").cloned(),
+                configure: sub_matches.get_one::<PathBuf>("configure").cloned(),
+                curated,
+                output: sub_matches.get_one::<PathBuf>("output").cloned(),
+                remove_redundant: sub_matches.get_flag("remove_redundant"),
+                dedup: DedupOptions {
+                    min_identity: sub_matches
+                        .get_one::<f64>("min_identity")
+                        .cloned()
+                        .expect("errored by clap"),
+                    min_coverage: sub_matches
+                        .get_one::<f64>("min_coverage")
+                        .cloned()
+                        .expect("errored by clap"),
+                    min_length: sub_matches
+                        .get_one::<u64>("min_length")
+                        .cloned()
+                        .expect("errored by clap"),
+                    threads: sub_matches
+                        .get_one::<u8>("threads")
+                        .cloned()
+                        .expect("errored by clap"),
+                    ..Default::default()
+                },
+            }))
+        }
+        Some(("convert", sub_matches)) => {
+            let input = sub_matches
+                .get_one::<PathBuf>("library")
+                .cloned()
+                .expect("errored by clap");
+            if !input.exists() {
+                return Err(Error::new(ErrorKind::GenericCli(format!(
+                    "Library not found: {}",
+                    input.display()
+                ))));
+            }
+            let format = |name: &str| {
+                sub_matches
+                    .get_one::<String>(name)
+                    .and_then(|f| LibraryFormat::from_name(f))
+            };
+            Ok(LibraryCommand::Convert(LibraryConvertArgs {
+                input,
+                output: sub_matches
+                    .get_one::<PathBuf>("output")
+                    .cloned()
+                    .expect("errored by clap"),
+                from: format("from"),
+                to: format("to"),
+            }))
+        }
+        _ => unreachable!("subcommand required by clap"),
+    }
+}
+
+pub fn parse_args() -> Result<RepCommand> {
     let matches = command!()
         .next_line_help(true)
+        // the subcommands don't need the pipeline arguments
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .subcommand(mask_fasta_command())
+        .subcommand(curate_command())
+        .subcommand(library_command())
         // not optional
         .arg(
             arg!(<FASTA> "Input file in fasta format. Must be absolute. Just wrap relative path in $(realpath ...).")
@@ -37,14 +959,14 @@ pub fn parse_args() -> Result<CliArgs> {
         .arg(
             arg!(-c --configure <CONFIG_PATH> "Configure the file system - and create the required directories.")
                 // not required unless you only want to run RepeatMasker
-                .required_unless_present_any(["rma_only", "curation_only"])
+                .required_unless_present("rma_only")
                 .value_parser(value_parser!(PathBuf)),
         )
         .arg(
             arg!(-d --database <DATABASE_NAME> "Name of the database, when building using `BuildDatabase`.")
-                // not required unless you only want to run RepeatMasker
-                // or I think, when you want run the curation pipeline
-                .required_unless_present("rma_only")
+                // not required when only running RepeatMasker
+                // or the curation pipeline
+                .required_unless_present_any(["rma_only", "curation_only"])
                 .value_parser(value_parser!(String)),
         )
         .arg(
@@ -63,9 +985,30 @@ pub fn parse_args() -> Result<CliArgs> {
             arg!(--rma_only "Run RepeatMasker only. Skip RepeatModeler; currently for development.")
                 .action(ArgAction::SetTrue)
         )
+        .arg(
+            arg!(--curation_only <GENOME> "Run the curation pipeline only, against this genome (see `rep curate`).")
+                .requires("configure")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--curation_rmdl_library <LIBRARY> "The RepeatModeler library (consensi.fa.classified) to curate.")
+                .requires("curation_only")
+                .value_parser(value_parser!(PathBuf)),
+        )
         .arg(arg!(--verbose "Print extra debug information").action(ArgAction::SetTrue))
         .get_matches();
 
+    match matches.subcommand() {
+        Some(("mask-fasta", sub_matches)) => {
+            return Ok(RepCommand::MaskFasta(parse_mask_fasta(sub_matches)?))
+        }
+        Some(("curate", sub_matches)) => return Ok(RepCommand::Curate(parse_curate(sub_matches)?)),
+        Some(("library", sub_matches)) => {
+            return Ok(RepCommand::Library(parse_library(sub_matches)?))
+        }
+        _ => (),
+    }
+
     // parse the arguments out
     let fasta = matches
         .get_one::<PathBuf>("FASTA")
@@ -94,16 +1037,22 @@ pub fn parse_args() -> Result<CliArgs> {
 
     let rma_only = matches.get_flag("rma_only");
 
+    let curation_only = matches.get_one::<PathBuf>("curation_only").cloned();
+
+    let curation_rmdl_library = matches.get_one::<PathBuf>("curation_rmdl_library").cloned();
+
     let verbose = matches.get_flag("verbose");
 
     // collect the arguments
-    Ok(CliArgs {
+    Ok(RepCommand::Pipeline(CliArgs {
         fasta_file: fasta,
         configure,
         database,
         rmo_threads,
         rma_threads,
         rma_only,
+        curation_only,
+        curation_rmdl_library,
         verbose,
-    })
+    }))
 }
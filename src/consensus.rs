@@ -0,0 +1,273 @@
+// A majority-rule consensus from a multiple alignment, so a family
+// can be re-called from the alignment of its copies without cons
+// or CIAlign.
+//
+// Each column is called on its own:
+// - columns where too few sequences have a base (insertions in a
+//   minority of copies) are left out;
+// - otherwise the most common base is called, if it makes up enough
+//   of the column. Ties are called as IUPAC codes, or N.
+
+use std::path::Path;
+
+use bio::io::fasta;
+
+use crate::{Error, ErrorKind, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GapHandling {
+    // the majority is of the bases in the column only
+    Ignore,
+    // gaps are counted as a character; a column where the
+    // gap is most common is left out
+    Count,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConsensusOptions {
+    // the fraction of sequences with a base for a column to be called
+    pub min_occupancy: f64,
+    // the fraction of the column the most common base must reach,
+    // otherwise N is called
+    pub majority: f64,
+    // call ties as IUPAC codes rather than N
+    pub iupac: bool,
+    pub gaps: GapHandling,
+    // trim columns from either end until one reaches this occupancy
+    pub trim_ends: Option<f64>,
+}
+
+impl Default for ConsensusOptions {
+    fn default() -> Self {
+        Self {
+            min_occupancy: 0.5,
+            majority: 0.5,
+            iupac: false,
+            gaps: GapHandling::Ignore,
+            trim_ends: None,
+        }
+    }
+}
+
+const BASES: [u8; 4] = [b'A', b'C', b'G', b'T'];
+
+fn is_gap(c: u8) -> bool {
+    c == b'-' || c == b'.'
+}
+
+// The IUPAC code for a set of bases, indexed as BASES.
+pub fn iupac_code(set: [bool; 4]) -> u8 {
+    match set {
+        [true, false, false, false] => b'A',
+        [false, true, false, false] => b'C',
+        [false, false, true, false] => b'G',
+        [false, false, false, true] => b'T',
+        [true, false, true, false] => b'R',
+        [false, true, false, true] => b'Y',
+        [false, true, true, false] => b'S',
+        [true, false, false, true] => b'W',
+        [false, false, true, true] => b'K',
+        [true, true, false, false] => b'M',
+        [false, true, true, true] => b'B',
+        [true, false, true, true] => b'D',
+        [true, true, false, true] => b'H',
+        [true, true, true, false] => b'V',
+        _ => b'N',
+    }
+}
+
+// Per column counts of A, C, G and T, gaps, and sequences.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ColumnCounts {
+    pub bases: [usize; 4],
+    pub gaps: usize,
+    pub depth: usize,
+}
+
+impl ColumnCounts {
+    // The fraction of sequences with a residue (including N) here.
+    pub fn occupancy(&self) -> f64 {
+        if self.depth == 0 {
+            0.0
+        } else {
+            (self.depth - self.gaps) as f64 / self.depth as f64
+        }
+    }
+
+    // Call the column, or None if it should be left out.
+    pub fn call(&self, options: &ConsensusOptions) -> Option<u8> {
+        if self.gaps == self.depth || self.occupancy() < options.min_occupancy {
+            return None;
+        }
+        let top = *self.bases.iter().max().expect("four bases");
+        if options.gaps == GapHandling::Count && self.gaps > top {
+            return None;
+        }
+
+        let total = match options.gaps {
+            GapHandling::Ignore => self.depth - self.gaps,
+            GapHandling::Count => self.depth,
+        };
+        if top == 0 || (top as f64) < options.majority * total as f64 {
+            return Some(b'N');
+        }
+
+        let tied = self.bases.map(|n| n == top);
+        match tied.iter().filter(|&&t| t).count() {
+            1 => Some(iupac_code(tied)),
+            _ if options.iupac => Some(iupac_code(tied)),
+            _ => Some(b'N'),
+        }
+    }
+}
+
+// Count each column of an alignment.
+pub fn column_counts(alignment: &[Vec<u8>]) -> Vec<ColumnCounts> {
+    let width = alignment.first().map(|s| s.len()).unwrap_or(0);
+    let mut columns = vec![
+        ColumnCounts {
+            depth: alignment.len(),
+            ..Default::default()
+        };
+        width
+    ];
+    for seq in alignment {
+        for (column, &c) in columns.iter_mut().zip(seq) {
+            let c = c.to_ascii_uppercase();
+            if is_gap(c) {
+                column.gaps += 1;
+            } else if let Some(i) = BASES.iter().position(|&b| b == c) {
+                column.bases[i] += 1;
+            }
+        }
+    }
+    columns
+}
+
+// Read an aligned FASTA. All sequences must be the same length.
+pub fn read_alignment(path: &Path) -> Result<Vec<Vec<u8>>> {
+    let mut alignment = Vec::new();
+    for record in fasta::Reader::from_file(path)?.records() {
+        let record = record?;
+        alignment.push(record.seq().to_vec());
+    }
+    if alignment.windows(2).any(|w| w[0].len() != w[1].len()) {
+        return Err(Error::new(ErrorKind::GenericCli(format!(
+            "Sequences in {} are not all the same length. Is it aligned?",
+            path.display()
+        ))));
+    }
+    Ok(alignment)
+}
+
+// The consensus of an alignment.
+pub fn consensus(alignment: &[Vec<u8>], options: &ConsensusOptions) -> Vec<u8> {
+    let columns = column_counts(alignment);
+
+    let (mut first, mut last) = (0, columns.len());
+    if let Some(min) = options.trim_ends {
+        while first < last && columns[first].occupancy() < min {
+            first += 1;
+        }
+        while last > first && columns[last - 1].occupancy() < min {
+            last -= 1;
+        }
+    }
+
+    columns[first..last]
+        .iter()
+        .filter_map(|c| c.call(options))
+        .collect()
+}
+
+// Re-call the consensus of a family from the alignment of its
+// copies, keeping the family's name and description.
+pub fn consensus_from_alignment(
+    path: &Path,
+    family: &fasta::Record,
+    options: &ConsensusOptions,
+) -> Result<fasta::Record> {
+    let seq = consensus(&read_alignment(path)?, options);
+    Ok(fasta::Record::with_attrs(family.id(), family.desc(), &seq))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alignment(seqs: &[&str]) -> Vec<Vec<u8>> {
+        seqs.iter().map(|s| s.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_consensus_options() {
+        let aln = alignment(&["aCGT-A-", "ACGTTA-", "AGGA-AC", "ACTA-A-"]);
+
+        // column 5 is an insertion in one copy, 7 in another
+        let options = ConsensusOptions::default();
+        assert_eq!(consensus(&aln, &options), b"ACGNA");
+
+        // the tie in column 4 as IUPAC
+        let options = ConsensusOptions {
+            iupac: true,
+            ..Default::default()
+        };
+        assert_eq!(consensus(&aln, &options), b"ACGWA");
+
+        // keep every column with a base in it
+        let options = ConsensusOptions {
+            min_occupancy: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(consensus(&aln, &options), b"ACGNTAC");
+        // unless gaps are counted
+        let options = ConsensusOptions {
+            min_occupancy: 0.0,
+            gaps: GapHandling::Count,
+            ..Default::default()
+        };
+        assert_eq!(consensus(&aln, &options), b"ACGNA");
+
+        // a stricter majority
+        let options = ConsensusOptions {
+            majority: 0.8,
+            ..Default::default()
+        };
+        assert_eq!(consensus(&aln, &options), b"ANNNA");
+    }
+
+    #[test]
+    fn test_trim_ends() {
+        let aln = alignment(&["--ACGT-A", "-TACGT--", "G-ACGA--"]);
+        let options = ConsensusOptions {
+            min_occupancy: 0.0,
+            trim_ends: Some(0.6),
+            ..Default::default()
+        };
+        // the ragged ends go
+        assert_eq!(consensus(&aln, &options), b"ACGT");
+
+        let options = ConsensusOptions {
+            min_occupancy: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(consensus(&aln, &options), b"GTACGTA");
+    }
+
+    #[test]
+    fn test_consensus_from_alignment_keeps_name() {
+        let family = fasta::Record::with_attrs("rnd-1_family-12#LTR/Gypsy", Some("old"), b"ACGT");
+        let record = consensus_from_alignment(
+            Path::new("test/intermediate/aligned/query1.fa"),
+            &family,
+            &ConsensusOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(record.id(), family.id());
+        assert_eq!(record.desc(), Some("old"));
+        assert_eq!(
+            record.seq(),
+            b"ATGCGTACGTAGCTAGCTGACTGATCGATCGTAGCTAGCTAGCTGATCGTACGTAGCTAG"
+        );
+    }
+}
@@ -0,0 +1,1112 @@
+// The start of the curation pipeline: find where each RepeatModeler
+// consensus sits in the genome.
+// 1. build a BLAST nucleotide database of the staged genome with
+//    makeblastdb, reusing it if it's already there;
+// 2. write each consensus to its own FASTA and blastn it against
+//    the genome, loading the hits into a BlastTable;
+// 3. take the best hits of each consensus and extract the genomic
+//    copies, with flanks, in the orientation of the consensus;
+// 4. align the copies of each family with MAFFT;
+// 5. re-call each consensus from the alignment.
+// Steps 2-5 are repeated ("blast, extract, extend") with the new
+// consensi, as RepeatModeler consensi are often truncated: each
+// round the flanks add sequence to the ends of a family, until the
+// ends are no longer conserved between copies.
+//
+// The curated library goes to results/<genome>.curated.fa, the
+// length and copy number of each family per round to
+// results/<genome>.curation.tsv, and the terminal repeats and TSDs
+// of the curated families to results/<genome>.structure.tsv. LINEs
+// without an ORF and SINEs without a tail are flagged for review in
+// the curation report, with the details in results/<genome>.qc.tsv.
+//
+// Everything goes into intermediate/, one file per family, so a
+// failed run can be inspected (and later stages re-run) by family.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use bio::{alphabets::dna::revcomp, io::fasta};
+
+use crate::{
+    cli::CurateArgs,
+    consensus::{consensus, read_alignment},
+    edges::{column_stats, find_edges, Edges},
+    mafft::align_families,
+    parse_blast::{BlastRecord, BlastTable, SortKey, SortOrder},
+    parse_rmout::Strand,
+    qc::{write_qc_tsv, QcReport},
+    repeatmasker::find_consensi,
+    staged_genome_path,
+    structure::{summarise_tsds, write_structure_tsv, StructureReport},
+    CommandRunner, Error, ErrorKind, RealCommandRunner, Result, INTERMEDIATE, RESULTS,
+};
+
+pub const MAKEBLASTDB: &str = "makeblastdb";
+pub const BLASTN: &str = "blastn";
+pub const BLASTX: &str = "blastx";
+
+// the columns we ask blastn for. qlen and slen are needed for
+// coverage and for extending hits to the end of the subject.
+const BLASTN_OUTFMT: &str = "7 std qlen slen";
+
+// For blastn and blastx alike.
+#[derive(Debug, Clone, Copy)]
+pub struct BlastOptions {
+    pub evalue: f64,
+    pub threads: u8,
+}
+
+// Which copies of a consensus to extract, and how much
+// sequence either side of them.
+#[derive(Debug, Clone, Copy)]
+pub struct ExtractOptions {
+    // the number of copies, best bitscore first
+    pub top_n: usize,
+    // the fraction of the consensus a hit must cover, if any
+    pub min_coverage: Option<f64>,
+    // flanks relative to the consensus, so on the minus strand the
+    // left flank is taken from the right of the hit in the genome
+    pub left_flank: u64,
+    pub right_flank: u64,
+}
+
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        Self {
+            top_n: 20,
+            min_coverage: None,
+            left_flank: 1000,
+            right_flank: 1000,
+        }
+    }
+}
+
+// A consensus and where it hits the genome.
+#[derive(Debug, Clone)]
+pub struct FamilyHits {
+    // the consensus, as in the RepeatModeler library
+    pub consensus: fasta::Record,
+    // the family name made safe for file names
+    pub stem: String,
+    pub hits: BlastTable,
+}
+
+// RepeatModeler IDs look like rnd-1_family-12#LTR/Gypsy. Drop the
+// classification, which has a slash in it, to name the files.
+pub fn family_stem(id: &str) -> String {
+    let name = id.split('#').next().unwrap_or(id);
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | ' ' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbType {
+    Nucleotide,
+    Protein,
+}
+
+impl DbType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DbType::Nucleotide => "nucl",
+            DbType::Protein => "prot",
+        }
+    }
+
+    // makeblastdb writes either a single volume (.nsq) or, for large
+    // genomes, an alias (.nal) over several volumes. Newer versions
+    // always write a .ndb as well. Protein databases are .p*.
+    fn extensions(&self) -> [&'static str; 3] {
+        match self {
+            DbType::Nucleotide => ["ndb", "nal", "nsq"],
+            DbType::Protein => ["pdb", "pal", "psq"],
+        }
+    }
+}
+
+pub fn blast_db_exists(fasta: &Path, dbtype: DbType) -> bool {
+    dbtype.extensions().iter().any(|ext| {
+        let mut p = fasta.as_os_str().to_owned();
+        p.push(".");
+        p.push(ext);
+        PathBuf::from(p).exists()
+    })
+}
+
+// Build a database named after the FASTA, next to it, unless there
+// is one already. Returns whether a database had to be built.
+pub fn make_blast_db(fasta: &Path, dbtype: DbType, runner: &dyn CommandRunner) -> Result<bool> {
+    if blast_db_exists(fasta, dbtype) {
+        eprintln!("Reusing BLAST database for {}", fasta.display());
+        return Ok(false);
+    }
+    build_blast_db(fasta, dbtype, runner)?;
+    Ok(true)
+}
+
+// Build a database named after the FASTA, replacing any there is.
+// For FASTAs we write ourselves, which an old database won't match.
+pub fn build_blast_db(fasta: &Path, dbtype: DbType, runner: &dyn CommandRunner) -> Result<()> {
+    let mut makeblastdb = Command::new(MAKEBLASTDB);
+    makeblastdb
+        .arg("-in")
+        .arg(fasta)
+        .arg("-dbtype")
+        .arg(dbtype.as_str())
+        .arg("-parse_seqids")
+        .arg("-out")
+        .arg(fasta);
+
+    let out = runner.run(&mut makeblastdb)?;
+    if !out.status.success() {
+        return Err(Error::new(ErrorKind::GenericCli(format!(
+            "makeblastdb failed: {}",
+            String::from_utf8_lossy(&out.stderr)
+        ))));
+    }
+    Ok(())
+}
+
+// Run `program` (blastn, blastx, ...) with `query` against the
+// database `db`, writing the tabular output to `out` and reading
+// it back in.
+pub fn blast(
+    program: &str,
+    db: &Path,
+    query: &Path,
+    out: &Path,
+    options: BlastOptions,
+    runner: &dyn CommandRunner,
+) -> Result<BlastTable> {
+    let mut blast = Command::new(program);
+    blast
+        .arg("-query")
+        .arg(query)
+        .arg("-db")
+        .arg(db)
+        .arg("-outfmt")
+        .arg(BLASTN_OUTFMT)
+        .arg("-evalue")
+        .arg(options.evalue.to_string())
+        .arg("-num_threads")
+        .arg(options.threads.to_string())
+        .arg("-out")
+        .arg(out);
+
+    let output = runner.run(&mut blast)?;
+    if !output.status.success() {
+        return Err(Error::new(ErrorKind::GenericCli(format!(
+            "{} failed for {}: {}",
+            program,
+            query.display(),
+            String::from_utf8_lossy(&output.stderr)
+        ))));
+    }
+
+    BlastTable::from_file(out)
+}
+
+pub fn blastn(
+    db: &Path,
+    query: &Path,
+    out: &Path,
+    options: BlastOptions,
+    runner: &dyn CommandRunner,
+) -> Result<BlastTable> {
+    blast(BLASTN, db, query, out, options, runner)
+}
+
+// blastn each consensus against `genome`. The consensi are
+// written to <intermediate>/consensi/<family>.fa and the hits
+// to <intermediate>/blastout/<family>.tsv.
+pub fn blast_records(
+    genome: &Path,
+    consensi: &[fasta::Record],
+    intermediate: &Path,
+    options: BlastOptions,
+    runner: &dyn CommandRunner,
+) -> Result<Vec<FamilyHits>> {
+    let consensi_dir = intermediate.join("consensi");
+    let blastout_dir = intermediate.join("blastout");
+    fs::create_dir_all(&consensi_dir)?;
+    fs::create_dir_all(&blastout_dir)?;
+
+    let mut families = Vec::new();
+    for record in consensi {
+        let stem = family_stem(record.id());
+
+        let query = consensi_dir.join(format!("{}.fa", stem));
+        let mut writer = fasta::Writer::new(BufWriter::new(File::create(&query)?));
+        writer.write_record(record)?;
+        writer.flush()?;
+
+        let out = blastout_dir.join(format!("{}.tsv", stem));
+        let mut hits = blastn(genome, &query, &out, options, runner)?;
+        hits.query_lengths
+            .insert(record.id().to_string(), record.seq().len() as u64);
+
+        families.push(FamilyHits {
+            consensus: record.clone(),
+            stem,
+            hits,
+        });
+    }
+
+    Ok(families)
+}
+
+// blastn each consensus in the FASTA `library` against `genome`.
+pub fn blast_consensi(
+    genome: &Path,
+    library: &Path,
+    intermediate: &Path,
+    options: BlastOptions,
+    runner: &dyn CommandRunner,
+) -> Result<Vec<FamilyHits>> {
+    let consensi = fasta::Reader::from_file(library)?
+        .records()
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    blast_records(genome, &consensi, intermediate, options, runner)
+}
+
+// The hits to extract: those covering enough of the consensus,
+// best bitscore first.
+pub fn select_copies(hits: &BlastTable, options: ExtractOptions) -> BlastTable {
+    let mut selected = match options.min_coverage {
+        Some(c) => hits.clone().filter_by_query_coverage(c),
+        None => hits.clone(),
+    };
+    selected.sort_by_keys(&[(SortKey::Bitscore, SortOrder::Descending)]);
+    selected.top_n(options.top_n)
+}
+
+// Read only the sequences in `ids` from a FASTA, so the whole
+// genome doesn't have to be held in memory.
+pub fn read_sequences(path: &Path, ids: &HashSet<&str>) -> Result<HashMap<String, Vec<u8>>> {
+    let mut sequences = HashMap::new();
+    for record in fasta::Reader::from_file(path)?.records() {
+        let record = record?;
+        if ids.contains(record.id()) {
+            sequences.insert(record.id().to_string(), record.seq().to_vec());
+        }
+    }
+    Ok(sequences)
+}
+
+// A copy of a consensus in the genome, flanks included.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenomicCopy {
+    pub seqid: String,
+    // 1-based, inclusive, on the forward strand of the genome
+    pub start: u64,
+    pub end: u64,
+    pub strand: Strand,
+    // in the orientation of the consensus
+    pub seq: Vec<u8>,
+}
+
+impl GenomicCopy {
+    // Cut the hit and its flanks out of `subject`, clipping the
+    // flanks at the ends of the sequence. A hit reaching outside
+    // `subject` is an error, as the database and genome are out
+    // of sync.
+    pub fn extract(record: &BlastRecord, subject: &[u8], left: u64, right: u64) -> Result<Self> {
+        let strand = record.strand();
+        let (hit_start, hit_end) = record.subject_interval();
+        let (before, after) = match strand {
+            Strand::Forward => (left, right),
+            Strand::Reverse => (right, left),
+        };
+        if hit_start < 1 || hit_end > subject.len() as u64 {
+            return Err(Error::new(ErrorKind::GenericCli(format!(
+                "BLAST hit at {}:{}-{} is outside the sequence ({} bp)",
+                record.sseqid,
+                hit_start,
+                hit_end,
+                subject.len()
+            ))));
+        }
+        let start = hit_start.saturating_sub(before).max(1);
+        let end = (hit_end + after).min(subject.len() as u64);
+
+        let region = &subject[(start - 1) as usize..end as usize];
+        let seq = match strand {
+            Strand::Forward => region.to_vec(),
+            Strand::Reverse => revcomp(region),
+        };
+
+        Ok(GenomicCopy {
+            seqid: record.sseqid.clone(),
+            start,
+            end,
+            strand,
+            seq,
+        })
+    }
+
+    // Unique within a family, e.g. chr1:101-2400(-)
+    pub fn id(&self) -> String {
+        format!(
+            "{}:{}-{}({})",
+            self.seqid,
+            self.start,
+            self.end,
+            self.strand.as_str()
+        )
+    }
+}
+
+// Extract the selected copies of a family and write them to
+// <dir>/<family>.fa. Hits on sequences missing from `genome`
+// are an error, as the database and genome are out of sync.
+pub fn write_copies(
+    family: &FamilyHits,
+    genome: &HashMap<String, Vec<u8>>,
+    options: ExtractOptions,
+    dir: &Path,
+) -> Result<Vec<GenomicCopy>> {
+    let mut copies = Vec::new();
+    for record in select_copies(&family.hits, options).records() {
+        let subject = genome.get(&record.sseqid).ok_or_else(|| {
+            Error::new(ErrorKind::GenericCli(format!(
+                "BLAST hit on {}, which is not in the genome",
+                record.sseqid
+            )))
+        })?;
+        copies.push(GenomicCopy::extract(
+            record,
+            subject,
+            options.left_flank,
+            options.right_flank,
+        )?);
+    }
+
+    let path = dir.join(format!("{}.fa", family.stem));
+    let mut writer = fasta::Writer::new(BufWriter::new(File::create(path)?));
+    for copy in &copies {
+        writer.write(&copy.id(), None, &copy.seq)?;
+    }
+    writer.flush()?;
+
+    Ok(copies)
+}
+
+// Extract the copies of every family from `genome` into
+// <intermediate>/blastn/<family>.fa.
+pub fn extract_copies(
+    families: &[FamilyHits],
+    genome: &Path,
+    intermediate: &Path,
+    options: ExtractOptions,
+) -> Result<Vec<Vec<GenomicCopy>>> {
+    let dir = intermediate.join("blastn");
+    fs::create_dir_all(&dir)?;
+
+    let ids = families
+        .iter()
+        .flat_map(|f| f.hits.records().iter().map(|r| r.sseqid.as_str()))
+        .collect::<HashSet<_>>();
+    let sequences = read_sequences(genome, &ids)?;
+
+    families
+        .iter()
+        .map(|f| write_copies(f, &sequences, options, &dir))
+        .collect()
+}
+
+// How long to keep extending the families.
+#[derive(Debug, Clone, Copy)]
+pub struct BeeOptions {
+    pub max_iterations: usize,
+    // stop once a round adds fewer bases than this
+    pub min_extension: u64,
+    // the ends of a new consensus are trimmed back to the first
+    // stretch of this many called (non-N) bases
+    pub conserved_window: usize,
+}
+
+impl Default for BeeOptions {
+    fn default() -> Self {
+        Self {
+            max_iterations: 5,
+            min_extension: 10,
+            conserved_window: 10,
+        }
+    }
+}
+
+// Why a family stopped being extended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    // conservation dropped at both edges of the alignment, so the
+    // copies were extended into unique flank
+    EdgesFound,
+    // a round added fewer than `min_extension` bases
+    Stalled,
+    MaxIterations,
+    // fewer than two copies to align
+    TooFewCopies,
+}
+
+impl StopReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StopReason::EdgesFound => "edges_found",
+            StopReason::Stalled => "stalled",
+            StopReason::MaxIterations => "max_iterations",
+            StopReason::TooFewCopies => "too_few_copies",
+        }
+    }
+}
+
+// One round of the loop for a family.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Iteration {
+    pub copies: usize,
+    // the length of the consensus after the round
+    pub length: u64,
+    // where the element was found in the alignment, if aligned
+    pub edges: Option<Edges>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CuratedFamily {
+    pub stem: String,
+    pub consensus: fasta::Record,
+    pub original_length: u64,
+    pub iterations: Vec<Iteration>,
+    pub stop: Option<StopReason>,
+    // set by the QC of the curated consensus
+    pub review: Option<&'static str>,
+}
+
+// Trim the ends of `seq` back to the first and last runs of
+// `window` called bases, dropping flank that wasn't conserved.
+pub fn trim_unconserved_ends(seq: &[u8], window: usize) -> &[u8] {
+    let window = window.max(1);
+    let conserved = |w: &[u8]| w.iter().all(|c| !c.eq_ignore_ascii_case(&b'N'));
+    let first = seq.windows(window).position(conserved);
+    let last = seq.windows(window).rposition(conserved);
+    match (first, last) {
+        (Some(first), Some(last)) => &seq[first..last + window],
+        _ => &[],
+    }
+}
+
+impl CuratedFamily {
+    // Still conserved to both ends of the alignment in the last
+    // round, so likely a segmental duplication rather than a TE.
+    pub fn is_segmental_duplication(&self) -> bool {
+        self.iterations
+            .last()
+            .and_then(|i| i.edges)
+            .is_some_and(|e| e.is_segmental_duplication())
+    }
+}
+
+// The blast-extract-extend loop over all families. Each round
+// blasts the families still being extended, extracts their copies
+// in one pass over the genome, aligns them and re-calls the
+// consensi over the columns between the edges of the alignment.
+pub fn extend_families(
+    genome: &Path,
+    consensi: Vec<fasta::Record>,
+    intermediate: &Path,
+    args: &CurateArgs,
+    runner: &dyn CommandRunner,
+) -> Result<Vec<CuratedFamily>> {
+    let blast_options = BlastOptions {
+        evalue: args.evalue,
+        threads: args.threads,
+    };
+    let mut families = consensi
+        .into_iter()
+        .map(|consensus| CuratedFamily {
+            stem: family_stem(consensus.id()),
+            original_length: consensus.seq().len() as u64,
+            consensus,
+            iterations: Vec::new(),
+            stop: None,
+            review: None,
+        })
+        .collect::<Vec<_>>();
+
+    for round in 1..=args.bee.max_iterations {
+        let active = families
+            .iter_mut()
+            .filter(|f| f.stop.is_none())
+            .collect::<Vec<_>>();
+        if active.is_empty() {
+            break;
+        }
+        eprintln!("Round {}: extending {} families", round, active.len());
+
+        let records = active
+            .iter()
+            .map(|f| f.consensus.clone())
+            .collect::<Vec<_>>();
+        let hits = blast_records(genome, &records, intermediate, blast_options, runner)?;
+        let copies = extract_copies(&hits, genome, intermediate, args.extract)?;
+
+        let stems = active.iter().map(|f| f.stem.clone()).collect::<Vec<_>>();
+        let aligned = align_families(&stems, intermediate, args.mafft, runner)?;
+
+        for (family, copies) in active.into_iter().zip(copies) {
+            let previous = family.consensus.seq().len() as u64;
+            let alignment = intermediate
+                .join("aligned")
+                .join(format!("{}.fa", family.stem));
+            if !aligned.contains(&alignment) {
+                family.iterations.push(Iteration {
+                    copies: copies.len(),
+                    length: previous,
+                    edges: None,
+                });
+                family.stop = Some(StopReason::TooFewCopies);
+                continue;
+            }
+
+            let alignment = read_alignment(&alignment)?;
+            let edges = find_edges(&column_stats(&alignment), &args.edges);
+            // keep the old consensus if nothing was conserved
+            if let Some(e) = edges {
+                let core = alignment
+                    .iter()
+                    .map(|s| s[e.start..e.end].to_vec())
+                    .collect::<Vec<_>>();
+                let seq = consensus(&core, &args.consensus);
+                let seq = trim_unconserved_ends(&seq, args.bee.conserved_window);
+                if !seq.is_empty() {
+                    family.consensus = fasta::Record::with_attrs(
+                        family.consensus.id(),
+                        family.consensus.desc(),
+                        seq,
+                    );
+                }
+            }
+
+            let length = family.consensus.seq().len() as u64;
+            family.iterations.push(Iteration {
+                copies: copies.len(),
+                length,
+                edges,
+            });
+            if edges.is_some_and(|e| e.left_drop && e.right_drop) {
+                family.stop = Some(StopReason::EdgesFound);
+            } else if length < previous + args.bee.min_extension {
+                family.stop = Some(StopReason::Stalled);
+            }
+        }
+    }
+
+    for family in families.iter_mut().filter(|f| f.stop.is_none()) {
+        family.stop = Some(StopReason::MaxIterations);
+    }
+    Ok(families)
+}
+
+// One row per family and round.
+pub fn write_curation_tsv<W: Write>(families: &[CuratedFamily], writer: W) -> Result<()> {
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_writer(writer);
+    wtr.write_record([
+        "family",
+        "iteration",
+        "copies",
+        "length",
+        "original_length",
+        "alignment_start",
+        "alignment_end",
+        "stop",
+        "segmental_duplication",
+        "review",
+    ])?;
+    for family in families {
+        let stop = family.stop.map(|s| s.as_str()).unwrap_or("");
+        for (i, iteration) in family.iterations.iter().enumerate() {
+            let last = i + 1 == family.iterations.len();
+            // the edges as 1-based alignment columns
+            let (start, end) = match iteration.edges {
+                Some(e) => ((e.start + 1).to_string(), e.end.to_string()),
+                None => (String::new(), String::new()),
+            };
+            wtr.write_record([
+                family.consensus.id(),
+                &(i + 1).to_string(),
+                &iteration.copies.to_string(),
+                &iteration.length.to_string(),
+                &family.original_length.to_string(),
+                &start,
+                &end,
+                if last { stop } else { "" },
+                if last {
+                    if family.is_segmental_duplication() {
+                        "true"
+                    } else {
+                        "false"
+                    }
+                } else {
+                    ""
+                },
+                if last {
+                    family.review.unwrap_or("")
+                } else {
+                    ""
+                },
+            ])?;
+        }
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+// Run the curation for a configured directory.
+pub fn curate(args: &CurateArgs, runner: &dyn CommandRunner) -> Result<Vec<CuratedFamily>> {
+    let genome = staged_genome_path(&args.configure, &args.fasta_file)?;
+    if !genome.exists() {
+        return Err(Error::new(ErrorKind::GenericCli(format!(
+            "Staged genome {} not found. Did you configure the file system?",
+            genome.display()
+        ))));
+    }
+
+    let library = match &args.library {
+        Some(l) => l.clone(),
+        None => find_consensi(&args.configure).ok_or_else(|| {
+            Error::new(ErrorKind::GenericCli(
+                "No consensi.fa.classified found in the data directory. Pass --library or run RepeatModeler first."
+                    .into(),
+            ))
+        })?,
+    };
+
+    make_blast_db(&genome, DbType::Nucleotide, runner)?;
+
+    let consensi = fasta::Reader::from_file(&library)?
+        .records()
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let intermediate = args.configure.join(INTERMEDIATE);
+    let mut families = extend_families(&genome, consensi, &intermediate, args, runner)?;
+
+    let name = genome
+        .file_name()
+        .map(|f| f.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let results = args.configure.join(RESULTS);
+
+    let curated = results.join(format!("{}.curated.fa", name));
+    let mut writer = fasta::Writer::new(BufWriter::new(File::create(&curated)?));
+    for family in &families {
+        writer.write_record(&family.consensus)?;
+    }
+    writer.flush()?;
+
+    let mut qc = Vec::new();
+    for family in families.iter_mut() {
+        let report = QcReport::new(family.consensus.id(), family.consensus.seq(), &args.qc);
+        family.review = report.review();
+        qc.push(report);
+    }
+    let qc_tsv = results.join(format!("{}.qc.tsv", name));
+    write_qc_tsv(&qc, BufWriter::new(File::create(&qc_tsv)?))?;
+
+    let tsv = results.join(format!("{}.curation.tsv", name));
+    write_curation_tsv(&families, BufWriter::new(File::create(&tsv)?))?;
+
+    // the TSDs come from the alignment of the last round
+    let mut reports = Vec::new();
+    for family in &families {
+        let tsd = match family.iterations.last().and_then(|i| i.edges) {
+            Some(edges) => {
+                let alignment = intermediate
+                    .join("aligned")
+                    .join(format!("{}.fa", family.stem));
+                Some(summarise_tsds(
+                    &read_alignment(&alignment)?,
+                    &edges,
+                    &args.structure,
+                ))
+            }
+            None => None,
+        };
+        reports.push(StructureReport::new(
+            family.consensus.id(),
+            family.consensus.seq(),
+            tsd,
+            &args.structure,
+        ));
+    }
+    let structure = results.join(format!("{}.structure.tsv", name));
+    write_structure_tsv(&reports, BufWriter::new(File::create(&structure)?))?;
+
+    eprintln!(
+        "Curated library written to {}, per round lengths to {}",
+        curated.display(),
+        tsv.display()
+    );
+    Ok(families)
+}
+
+// Entry point for `rep curate`
+pub fn run_curate(args: CurateArgs) -> Result<()> {
+    let families = curate(&args, &RealCommandRunner)?;
+    eprintln!(
+        "Extended {} of {} families; {} look like segmental duplications",
+        families
+            .iter()
+            .filter(|f| f.consensus.seq().len() as u64 > f.original_length)
+            .count(),
+        families.len(),
+        families
+            .iter()
+            .filter(|f| f.is_segmental_duplication())
+            .count()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{ExitStatus, Output};
+
+    // Records the programs run, and answers blastn with the rows
+    // of tempBlastOut.txt for the query.
+    #[derive(Default)]
+    struct MockBlast {
+        programs: RefCell<Vec<String>>,
+    }
+
+    impl CommandRunner for MockBlast {
+        fn run(&self, cmd: &mut Command) -> Result<Output> {
+            let args = cmd
+                .get_args()
+                .map(|s| s.to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            let program = cmd.get_program().to_string_lossy().to_string();
+            let value = |flag: &str| {
+                let i = args.iter().position(|a| a == flag).unwrap();
+                args[i + 1].clone()
+            };
+
+            if program == BLASTN {
+                let query = fasta::Reader::from_file(value("-query"))
+                    .unwrap()
+                    .records()
+                    .next()
+                    .unwrap()
+                    .unwrap();
+                let rows = std::fs::read_to_string("test/intermediate/tempBlastOut.txt")
+                    .unwrap()
+                    .lines()
+                    .filter(|l| l.starts_with(&format!("{}\t", query.id())))
+                    .map(|l| format!("{}\n", l))
+                    .collect::<String>();
+                std::fs::write(value("-out"), rows).unwrap();
+            }
+
+            self.programs.borrow_mut().push(program);
+            Ok(Output {
+                status: ExitStatus::from_raw(0),
+                stdout: vec![],
+                stderr: vec![],
+            })
+        }
+    }
+
+    #[test]
+    fn test_family_stem() {
+        assert_eq!(family_stem("rnd-1_family-12#LTR/Gypsy"), "rnd-1_family-12");
+        assert_eq!(family_stem("a/b"), "a_b");
+    }
+
+    #[test]
+    fn test_blast_consensi_reuses_database() {
+        let tmp = tempfile::tempdir().unwrap();
+        let runner = MockBlast::default();
+
+        // the fixture genome has a database alongside it
+        let genome = Path::new("test/data/genome.fa");
+        assert!(!make_blast_db(genome, DbType::Nucleotide, &runner).unwrap());
+
+        let options = BlastOptions {
+            evalue: 1e-10,
+            threads: 1,
+        };
+        let families = blast_consensi(
+            genome,
+            Path::new("test/data/rmdl.fa"),
+            tmp.path(),
+            options,
+            &runner,
+        )
+        .unwrap();
+        assert_eq!(*runner.programs.borrow(), vec![BLASTN, BLASTN]);
+
+        assert_eq!(families.len(), 2);
+        assert_eq!(families[1].stem, "query2");
+        let hits = &families[1].hits;
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits.records()[0].sseqid, "chr1");
+        assert_eq!(hits.query_coverage(&hits.records()[0]), Some(1.0));
+        assert!(tmp.path().join("consensi").join("query1.fa").exists());
+        assert!(tmp.path().join("blastout").join("query1.tsv").exists());
+
+        // but a fresh copy does not
+        let fresh = tmp.path().join("genome.fa");
+        std::fs::copy(genome, &fresh).unwrap();
+        assert!(make_blast_db(&fresh, DbType::Nucleotide, &runner).unwrap());
+        assert_eq!(runner.programs.borrow().last().unwrap(), MAKEBLASTDB);
+    }
+
+    #[test]
+    fn test_extract_copies_with_flanks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let hits = BlastTable::from_file("test/intermediate/tempBlastOut.txt").unwrap();
+
+        // the same copy of query1, on the minus strand
+        let mut records = hits.into_records();
+        let forward = records[0].clone();
+        let mut reverse = forward.clone();
+        (reverse.sstart, reverse.send) = (reverse.send, reverse.sstart);
+        reverse.bitscore = 60.0;
+        records.insert(1, reverse);
+        let hits = BlastTable::new(records);
+
+        let family = FamilyHits {
+            consensus: fasta::Record::with_attrs("query1", None, b"ACGT"),
+            stem: "query1".into(),
+            hits: hits.filter_by_query_name("query1"),
+        };
+        let options = ExtractOptions {
+            top_n: 2,
+            min_coverage: None,
+            left_flank: 5,
+            right_flank: 2,
+        };
+        let copies = extract_copies(
+            &[family],
+            Path::new("test/data/genome.fa"),
+            tmp.path(),
+            options,
+        )
+        .unwrap();
+        let copies = &copies[0];
+
+        // best bitscore first, and flanks swapped on the minus strand
+        let chr1 = b"ATGCGTACGTAGCTAGCTGACTGATCGATCGTAGCTAGCTAGCTGATCGTACGTAGCTAG";
+        assert_eq!(copies[0].id(), "chr1:17-52(-)");
+        assert_eq!(copies[0].seq, revcomp(&chr1[16..52]));
+        assert_eq!(copies[1].id(), "chr1:14-49(+)");
+        assert_eq!(copies[1].seq, chr1[13..49].to_vec());
+
+        let written = std::fs::read_to_string(tmp.path().join("blastn").join("query1.fa")).unwrap();
+        assert!(written.starts_with(">chr1:17-52(-)\n"));
+
+        // flanks are clipped at the ends of the sequence
+        let clipped = GenomicCopy::extract(&forward, chr1, 100, 100).unwrap();
+        assert_eq!((clipped.start, clipped.end), (1, 60));
+        // a hit that starts or ends past the end of the sequence
+        // is an error, not clipped
+        assert!(GenomicCopy::extract(&forward, &chr1[..10], 0, 0).is_err());
+        assert!(GenomicCopy::extract(&forward, &chr1[..40], 0, 0).is_err());
+        assert!(GenomicCopy::extract(&forward, &chr1[..47], 0, 0).is_ok());
+    }
+
+    // Finds exact forward copies of the query in the genome for
+    // blastn, and aligns by concatenating the (equal length) copies.
+    struct MockGenome;
+
+    impl CommandRunner for MockGenome {
+        fn run(&self, cmd: &mut Command) -> Result<Output> {
+            let args = cmd
+                .get_args()
+                .map(|s| s.to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            let program = cmd.get_program().to_string_lossy().to_string();
+            let value = |flag: &str| args[args.iter().position(|a| a == flag).unwrap() + 1].clone();
+            let read = |path: &str| {
+                fasta::Reader::from_file(path)
+                    .unwrap()
+                    .records()
+                    .map(|r| r.unwrap())
+                    .collect::<Vec<_>>()
+            };
+
+            let mut stdout = vec![];
+            if program == BLASTN {
+                let query = read(&value("-query")).remove(0);
+                let q = query.seq();
+                let mut rows = String::new();
+                for subject in read(&value("-db")) {
+                    let s = subject.seq();
+                    for start in (0..s.len().saturating_sub(q.len() - 1))
+                        .filter(|&i| &s[i..i + q.len()] == q)
+                    {
+                        rows.push_str(&format!(
+                            "{}\t{}\t100.000\t{}\t0\t0\t1\t{}\t{}\t{}\t1e-20\t{}\t{}\t{}\n",
+                            query.id(),
+                            subject.id(),
+                            q.len(),
+                            q.len(),
+                            start + 1,
+                            start + q.len(),
+                            2 * q.len(),
+                            q.len(),
+                            s.len()
+                        ));
+                    }
+                }
+                std::fs::write(value("-out"), rows).unwrap();
+            } else if program == crate::mafft::MAFFT {
+                stdout = std::fs::read(args.last().unwrap()).unwrap();
+            }
+            Ok(Output {
+                status: ExitStatus::from_raw(0),
+                stdout,
+                stderr: vec![],
+            })
+        }
+    }
+
+    #[test]
+    fn test_curate_extends_truncated_consensus() {
+        let tmp = tempfile::tempdir().unwrap();
+        let data = tmp.path().join("data");
+        std::fs::create_dir_all(&data).unwrap();
+
+        // two copies of a 40bp element with unrelated flanks; the
+        // library has the middle 20bp of it, and a single copy repeat
+        let te = "GACTGATCGATCGTAGCTAGCTAGCTGATCGATGCATCGA";
+        let single = "TTGACCGGTTAACCGGTACG";
+        std::fs::write(
+            data.join("genome.fa"),
+            format!(
+                ">chr1\n{}{}{}\n>chr2\n{}{}{}\n",
+                "A".repeat(30),
+                te,
+                single,
+                "C".repeat(30),
+                te,
+                "C".repeat(30)
+            ),
+        )
+        .unwrap();
+        let library = tmp.path().join("library.fa");
+        std::fs::write(
+            &library,
+            format!(
+                ">rnd-1_family-1#DNA\n{}\n>rnd-1_family-2#Unknown\n{}\n",
+                &te[10..30],
+                single
+            ),
+        )
+        .unwrap();
+
+        let args = CurateArgs {
+            fasta_file: PathBuf::from("/elsewhere/genome.fa"),
+            configure: tmp.path().to_path_buf(),
+            library: Some(library),
+            threads: 1,
+            evalue: 1e-10,
+            extract: ExtractOptions {
+                left_flank: 10,
+                right_flank: 10,
+                ..Default::default()
+            },
+            mafft: Default::default(),
+            consensus: Default::default(),
+            bee: Default::default(),
+            edges: Default::default(),
+            structure: Default::default(),
+            qc: Default::default(),
+        };
+        std::fs::create_dir_all(tmp.path().join(crate::RESULTS)).unwrap();
+        let families = curate(&args, &MockGenome).unwrap();
+
+        // extended to the whole element, then the flanks disagree
+        let family = &families[0];
+        assert_eq!(family.consensus.id(), "rnd-1_family-1#DNA");
+        assert_eq!(family.consensus.seq(), te.as_bytes());
+        assert_eq!(
+            family.iterations,
+            vec![
+                Iteration {
+                    copies: 2,
+                    length: 40,
+                    // the flanks were inside the element
+                    edges: Some(Edges {
+                        start: 0,
+                        end: 40,
+                        left_drop: false,
+                        right_drop: false
+                    })
+                },
+                Iteration {
+                    copies: 2,
+                    length: 40,
+                    edges: Some(Edges {
+                        start: 10,
+                        end: 50,
+                        left_drop: true,
+                        right_drop: true
+                    })
+                }
+            ]
+        );
+        assert_eq!(family.stop, Some(StopReason::EdgesFound));
+        assert!(!family.is_segmental_duplication());
+
+        assert_eq!(families[1].stop, Some(StopReason::TooFewCopies));
+        assert_eq!(families[1].consensus.seq(), single.as_bytes());
+
+        let tsv =
+            std::fs::read_to_string(tmp.path().join("results").join("genome.fa.curation.tsv"))
+                .unwrap();
+        assert_eq!(tsv.lines().count(), 4);
+        assert!(tsv
+            .lines()
+            .nth(2)
+            .unwrap()
+            .ends_with("\t40\t20\t11\t50\tedges_found\tfalse\t"));
+
+        // without flanks the copies never drop, and the consensus
+        // can't grow past the hits
+        let args = CurateArgs {
+            extract: ExtractOptions {
+                left_flank: 0,
+                right_flank: 0,
+                ..Default::default()
+            },
+            ..args
+        };
+        let families = curate(&args, &MockGenome).unwrap();
+        let family = &families[0];
+        assert_eq!(family.iterations.len(), 1);
+        assert_eq!(family.consensus.seq(), &te.as_bytes()[10..30]);
+        assert_eq!(family.stop, Some(StopReason::Stalled));
+        assert!(family.is_segmental_duplication());
+    }
+
+    #[test]
+    fn test_trim_unconserved_ends() {
+        assert_eq!(
+            trim_unconserved_ends(b"NANNACGTNACGTANNAN", 4),
+            b"ACGTNACGTA"
+        );
+        assert_eq!(trim_unconserved_ends(b"NANNA", 2), b"");
+    }
+}
@@ -0,0 +1,385 @@
+// Screening of TE libraries for host genes. Multi-copy host genes
+// get into de novo libraries and then mask exons, so the consensi
+// are searched against the host's proteins (blastx) or transcripts
+// (blastn). A family with a strong gene hit is removed, or only
+// flagged, unless it also hits a TE protein: many TE proteins look
+// like host genes, and domesticated genes look like TEs.
+
+use std::{collections::HashMap, io::Write, path::Path};
+
+use crate::{
+    cli::LibraryDecontamArgs,
+    curate::{blast, make_blast_db, BlastOptions, DbType, BLASTN, BLASTX},
+    library::{library_path, split_id, TeLibrary},
+    parse_blast::{BlastRecord, BlastTable},
+    CommandRunner, Error, ErrorKind, RealCommandRunner, Result,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct DecontamOptions {
+    // a gene hit must reach both of these
+    pub evalue: f64,
+    pub min_bitscore: f64,
+    // a TE protein hit only needs this
+    pub te_evalue: f64,
+    // keep families with gene hits in the library, only reporting them
+    pub flag_only: bool,
+    pub threads: u8,
+}
+
+impl Default for DecontamOptions {
+    fn default() -> Self {
+        Self {
+            evalue: 1e-10,
+            min_bitscore: 100.0,
+            te_evalue: 1e-5,
+            flag_only: false,
+            threads: 1,
+        }
+    }
+}
+
+// What to screen the library against. At least one of proteins
+// and transcripts is needed.
+#[derive(Debug, Clone, Default)]
+pub struct ScreenSets<'a> {
+    pub proteins: Option<&'a Path>,
+    pub transcripts: Option<&'a Path>,
+    pub te_proteins: Option<&'a Path>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    // no gene hit, or a TE protein hit as well
+    Kept,
+    Flagged,
+    Removed,
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Kept => "kept",
+            Action::Flagged => "flagged",
+            Action::Removed => "removed",
+        }
+    }
+}
+
+// The screen of a family with a gene hit.
+#[derive(Debug, Clone)]
+pub struct Screen {
+    pub family: String,
+    pub gene: BlastRecord,
+    pub te: Option<BlastRecord>,
+    pub action: Action,
+}
+
+// The best hit of each family, by family name.
+pub fn best_hits(
+    table: BlastTable,
+    evalue: f64,
+    min_bitscore: f64,
+) -> HashMap<String, BlastRecord> {
+    table
+        .filter_by_evalue(evalue)
+        .filter_by_bitscore(min_bitscore)
+        .rank_per_query(1)
+        .into_records()
+        .into_iter()
+        .map(|r| (split_id(&r.qseqid).0.to_string(), r))
+        .collect()
+}
+
+// Search the library against `set`, building its database first.
+fn search(
+    program: &str,
+    set: &Path,
+    library: &Path,
+    out: &Path,
+    evalue: f64,
+    threads: u8,
+    runner: &dyn CommandRunner,
+) -> Result<BlastTable> {
+    let dbtype = if program == BLASTN {
+        DbType::Nucleotide
+    } else {
+        DbType::Protein
+    };
+    make_blast_db(set, dbtype, runner)?;
+    blast(
+        program,
+        set,
+        library,
+        out,
+        BlastOptions { evalue, threads },
+        runner,
+    )
+}
+
+pub fn write_screen_tsv<W: Write>(screens: &[Screen], writer: W) -> Result<()> {
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_writer(writer);
+    wtr.write_record([
+        "family",
+        "gene_hit",
+        "gene_evalue",
+        "gene_bitscore",
+        "te_hit",
+        "te_evalue",
+        "action",
+    ])?;
+    let hit = |r: Option<&BlastRecord>| {
+        r.map(|r| {
+            (
+                r.sseqid.clone(),
+                r.evalue.to_string(),
+                r.bitscore.to_string(),
+            )
+        })
+        .unwrap_or_default()
+    };
+    for s in screens {
+        let (gene, gene_evalue, gene_bitscore) = hit(Some(&s.gene));
+        let (te, te_evalue, _) = hit(s.te.as_ref());
+        wtr.write_record([
+            s.family.as_str(),
+            &gene,
+            &gene_evalue,
+            &gene_bitscore,
+            &te,
+            &te_evalue,
+            s.action.as_str(),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+// Screen a library, writing the families kept to `output` and the
+// families with gene hits to <output>.screen.tsv. Returns those
+// screens.
+pub fn decontaminate(
+    library_path: &Path,
+    output: &Path,
+    sets: &ScreenSets,
+    options: &DecontamOptions,
+    runner: &dyn CommandRunner,
+) -> Result<Vec<Screen>> {
+    if sets.proteins.is_none() && sets.transcripts.is_none() {
+        return Err(Error::new(ErrorKind::GenericCli(
+            "Give host proteins or transcripts to screen the library against".into(),
+        )));
+    }
+    let library = TeLibrary::from_file(library_path)?;
+
+    let mut genes: HashMap<String, BlastRecord> = HashMap::new();
+    for (program, set, name) in [
+        (BLASTX, sets.proteins, "proteins"),
+        (BLASTN, sets.transcripts, "transcripts"),
+    ] {
+        let Some(set) = set else { continue };
+        let table = search(
+            program,
+            set,
+            library_path,
+            &output.with_extension(format!("{}.{}.tsv", name, program)),
+            options.evalue,
+            options.threads,
+            runner,
+        )?;
+        for (family, hit) in best_hits(table, options.evalue, options.min_bitscore) {
+            match genes.get(&family) {
+                Some(best) if best.bitscore >= hit.bitscore => (),
+                _ => {
+                    genes.insert(family, hit);
+                }
+            }
+        }
+    }
+
+    let mut tes = match sets.te_proteins {
+        Some(set) => best_hits(
+            search(
+                BLASTX,
+                set,
+                library_path,
+                &output.with_extension("te_proteins.blastx.tsv"),
+                options.te_evalue,
+                options.threads,
+                runner,
+            )?,
+            options.te_evalue,
+            0.0,
+        ),
+        None => HashMap::new(),
+    };
+
+    let mut screens = Vec::new();
+    let mut kept = Vec::new();
+    for family in &library.families {
+        let Some(gene) = genes.remove(&family.name) else {
+            kept.push(family.clone());
+            continue;
+        };
+        let te = tes.remove(&family.name);
+        let action = match (&te, options.flag_only) {
+            (Some(_), _) => Action::Kept,
+            (None, true) => Action::Flagged,
+            (None, false) => Action::Removed,
+        };
+        if action != Action::Removed {
+            kept.push(family.clone());
+        }
+        screens.push(Screen {
+            family: family.id(),
+            gene,
+            te,
+            action,
+        });
+    }
+
+    TeLibrary::new(kept).to_file(output)?;
+    write_screen_tsv(
+        &screens,
+        std::fs::File::create(output.with_extension("screen.tsv"))?,
+    )?;
+
+    let count = |a: Action| screens.iter().filter(|s| s.action == a).count();
+    eprintln!(
+        "{} of {} families hit host genes: {} removed, {} flagged, {} kept for their TE protein hits",
+        screens.len(),
+        library.len(),
+        count(Action::Removed),
+        count(Action::Flagged),
+        count(Action::Kept)
+    );
+    Ok(screens)
+}
+
+pub fn run_decontam(args: &LibraryDecontamArgs) -> Result<()> {
+    let library = library_path(args.library.as_ref(), args.configure.as_ref())?;
+    let output = args.output.clone().unwrap_or_else(|| {
+        let name = library.file_name().unwrap_or_default().to_string_lossy();
+        library.with_file_name(format!("{}.decontam.fa", name))
+    });
+    let sets = ScreenSets {
+        proteins: args.proteins.as_deref(),
+        transcripts: args.transcripts.as_deref(),
+        te_proteins: args.te_proteins.as_deref(),
+    };
+    decontaminate(&library, &output, &sets, &args.options, &RealCommandRunner)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{Command, ExitStatus, Output};
+
+    // blastx against the host proteins or TE proteins, and blastn
+    // against the transcripts, answer with fixed hits
+    struct MockScreen;
+
+    impl CommandRunner for MockScreen {
+        fn run(&self, cmd: &mut Command) -> Result<Output> {
+            let args = cmd
+                .get_args()
+                .map(|s| s.to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            let value = |flag: &str| {
+                let i = args.iter().position(|a| a == flag).unwrap();
+                args[i + 1].clone()
+            };
+            let program = cmd.get_program().to_string_lossy().to_string();
+            if program != BLASTN && program != BLASTX {
+                return Ok(Output {
+                    status: ExitStatus::from_raw(0),
+                    stdout: vec![],
+                    stderr: vec![],
+                });
+            }
+            let rows = match value("-db") {
+                db if db.ends_with("proteins.fa") && !db.ends_with("te_proteins.fa") => {
+                    "gene#Unknown\tHSP70\t90.0\t300\t30\t0\t1\t900\t1\t300\t1e-100\t500\n\
+                     dom#LTR/Gypsy\tRTase\t60.0\t200\t80\t0\t1\t600\t1\t200\t1e-50\t250\n\
+                     weak#DNA\tHSP70\t40.0\t50\t30\t0\t1\t150\t1\t50\t1e-3\t30\n"
+                }
+                db if db.ends_with("transcripts.fa") => {
+                    "other\tmRNA1\t99.0\t500\t5\t0\t1\t500\t1\t500\t1e-200\t900\n"
+                }
+                _ => "dom#LTR/Gypsy\tGypsy-1_DM#LTR/Gypsy\t70.0\t400\t120\t0\t1\t1200\t1\t400\t1e-80\t400\n",
+            };
+            std::fs::write(value("-out"), rows).unwrap();
+            Ok(Output {
+                status: ExitStatus::from_raw(0),
+                stdout: vec![],
+                stderr: vec![],
+            })
+        }
+    }
+
+    #[test]
+    fn test_decontaminate() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = dir.path().join("library.fa");
+        std::fs::write(
+            &library,
+            ">gene#Unknown\nACGT\n>dom#LTR/Gypsy\nACGT\n>weak#DNA\nACGT\n>other\nACGT\n>clean#LINE/L1\nACGT\n",
+        )
+        .unwrap();
+        let proteins = dir.path().join("proteins.fa");
+        let transcripts = dir.path().join("transcripts.fa");
+        let te_proteins = dir.path().join("te_proteins.fa");
+        let output = dir.path().join("decontam.fa");
+        let sets = ScreenSets {
+            proteins: Some(&proteins),
+            transcripts: Some(&transcripts),
+            te_proteins: Some(&te_proteins),
+        };
+
+        let screens = decontaminate(
+            &library,
+            &output,
+            &sets,
+            &DecontamOptions::default(),
+            &MockScreen,
+        )
+        .unwrap();
+        let actions = screens
+            .iter()
+            .map(|s| (s.family.as_str(), s.action))
+            .collect::<Vec<_>>();
+        // the weak hit doesn't count, and the family hitting a
+        // Gypsy protein as well is kept
+        assert_eq!(
+            actions,
+            vec![
+                ("gene#Unknown", Action::Removed),
+                ("dom#LTR/Gypsy", Action::Kept),
+                ("other#Unknown", Action::Removed),
+            ]
+        );
+        let kept = TeLibrary::from_file(&output).unwrap();
+        assert_eq!(
+            kept.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(),
+            vec!["dom", "weak", "clean"]
+        );
+        let report = std::fs::read_to_string(dir.path().join("decontam.screen.tsv")).unwrap();
+        assert!(report.contains("dom#LTR/Gypsy\tRTase\t"));
+        assert!(report.contains("\tGypsy-1_DM#LTR/Gypsy\t"));
+
+        // flagging keeps everything
+        let options = DecontamOptions {
+            flag_only: true,
+            ..Default::default()
+        };
+        decontaminate(&library, &output, &sets, &options, &MockScreen).unwrap();
+        assert_eq!(TeLibrary::from_file(&output).unwrap().len(), 5);
+
+        let none = ScreenSets::default();
+        assert!(decontaminate(&library, &output, &none, &options, &MockScreen).is_err());
+    }
+}
@@ -0,0 +1,498 @@
+// Redundancy removal from TE libraries. Libraries combined from
+// several rounds or genomes hold many copies of the same family, so
+// the consensi are clustered by the 80-80-80 rule (Wicker et al.
+// 2007): two consensi are the same family if they align at 80%
+// identity or more, over 80% of the shorter one, in alignments of
+// at least 80 bp.
+//
+// Pairs come from an all-vs-all blastn, or cd-hit-est does the
+// clustering. Either way the clustering is greedy, as in cd-hit:
+// the consensi are taken best representative first, and each joins
+// the first representative it matches or starts a new cluster.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{
+    cli::LibraryDedupArgs,
+    curate::{blastn, build_blast_db, BlastOptions, DbType},
+    intervals::IntervalSet,
+    library::{library_path, split_id, TeFamily, TeLibrary},
+    parse_blast::BlastTable,
+    CommandRunner, Error, ErrorKind, RealCommandRunner, Result,
+};
+
+const CD_HIT_EST: &str = "cd-hit-est";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClusterMethod {
+    Blastn,
+    CdHitEst,
+}
+
+// Which family of a cluster is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Representative {
+    Longest,
+    // classified over Unknown, then with a subclass, then longest
+    BestClassified,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DedupOptions {
+    pub method: ClusterMethod,
+    pub representative: Representative,
+    // percent, as in BLAST
+    pub min_identity: f64,
+    // the fraction of the shorter consensus aligned
+    pub min_coverage: f64,
+    // the shortest alignment counted
+    pub min_length: u64,
+    pub evalue: f64,
+    pub threads: u8,
+}
+
+impl Default for DedupOptions {
+    fn default() -> Self {
+        Self {
+            method: ClusterMethod::Blastn,
+            representative: Representative::Longest,
+            min_identity: 80.0,
+            min_coverage: 0.8,
+            min_length: 80,
+            evalue: 1e-10,
+            threads: 1,
+        }
+    }
+}
+
+// Sort key for representatives, best first.
+fn preference(family: &TeFamily, representative: Representative) -> impl Ord {
+    let classified = match representative {
+        Representative::Longest => (true, true),
+        Representative::BestClassified => (!family.is_unknown(), family.subclass.is_some()),
+    };
+    (
+        std::cmp::Reverse(classified),
+        std::cmp::Reverse(family.len()),
+    )
+}
+
+// The pairs of consensi, by family name, that pass the 80-80-80
+// rule. Each pair is given in both orders.
+pub fn similar_pairs(
+    table: &BlastTable,
+    lengths: &HashMap<&str, usize>,
+    options: &DedupOptions,
+) -> HashSet<(String, String)> {
+    // covered bases of each sequence of a pair, by pair
+    let mut covered: HashMap<(&str, &str), (IntervalSet, IntervalSet)> = HashMap::new();
+    for r in table.records() {
+        if split_id(&r.qseqid).0 == split_id(&r.sseqid).0
+            || r.pident < options.min_identity
+            || r.length < options.min_length
+        {
+            continue;
+        }
+        let (q, s) = covered
+            .entry((split_id(&r.qseqid).0, split_id(&r.sseqid).0))
+            .or_default();
+        let (qs, qe) = r.query_interval();
+        let (ss, se) = r.subject_interval();
+        q.insert(qs, qe);
+        s.insert(ss, se);
+    }
+
+    let mut pairs = HashSet::new();
+    for ((query, subject), (q, s)) in covered {
+        let (Some(&qlen), Some(&slen)) = (lengths.get(query), lengths.get(subject)) else {
+            continue;
+        };
+        // coverage of the shorter of the two
+        let coverage = if qlen <= slen {
+            q.covered() as f64 / qlen as f64
+        } else {
+            s.covered() as f64 / slen as f64
+        };
+        if coverage >= options.min_coverage {
+            pairs.insert((query.to_string(), subject.to_string()));
+            pairs.insert((subject.to_string(), query.to_string()));
+        }
+    }
+    pairs
+}
+
+// Greedy clustering of the families, given the pairs that are
+// similar. Returns the clusters as indices into `library`, the
+// representative first.
+pub fn cluster_families(
+    library: &TeLibrary,
+    pairs: &HashSet<(String, String)>,
+    representative: Representative,
+) -> Vec<Vec<usize>> {
+    // the pairs as indices, so probing them doesn't allocate
+    let index = library
+        .iter()
+        .enumerate()
+        .map(|(i, f)| (f.name.as_str(), i))
+        .collect::<HashMap<_, _>>();
+    let pairs = pairs
+        .iter()
+        .filter_map(|(a, b)| Some((*index.get(a.as_str())?, *index.get(b.as_str())?)))
+        .collect::<HashSet<_>>();
+    let mut order = (0..library.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| preference(&library.families[i], representative));
+
+    let mut clusters: Vec<Vec<usize>> = Vec::new();
+    for i in order {
+        let joined = clusters.iter_mut().find(|c| pairs.contains(&(c[0], i)));
+        match joined {
+            Some(cluster) => cluster.push(i),
+            None => clusters.push(vec![i]),
+        }
+    }
+    clusters
+}
+
+// The clusters of a cd-hit-est .clstr file, as sequence IDs with
+// the representative (marked *) first:
+// >Cluster 0
+// 0	5000nt, >rnd-1_family-1#LTR/Gypsy... *
+// 1	4800nt, >rnd-2_family-5#LTR/Gypsy... at +/95.00%
+pub fn read_clstr<R: BufRead>(reader: R) -> Result<Vec<Vec<String>>> {
+    let mut clusters: Vec<Vec<String>> = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.starts_with(">Cluster") {
+            clusters.push(Vec::new());
+            continue;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let parse_error = || {
+            Error::new(ErrorKind::GenericCli(format!(
+                "Error parsing cd-hit-est clusters at line {}",
+                i + 1
+            )))
+        };
+        let id = line
+            .split_once('>')
+            .and_then(|(_, rest)| rest.split_once("..."))
+            .map(|(id, _)| id.to_string())
+            .ok_or_else(parse_error)?;
+        let cluster = clusters.last_mut().ok_or_else(parse_error)?;
+        if line.trim_end().ends_with('*') {
+            cluster.insert(0, id);
+        } else {
+            cluster.push(id);
+        }
+    }
+    Ok(clusters)
+}
+
+// All-vs-all blastn of the library against itself.
+fn blastn_pairs(
+    library_path: &Path,
+    library: &TeLibrary,
+    out: &Path,
+    options: &DedupOptions,
+    runner: &dyn CommandRunner,
+) -> Result<HashSet<(String, String)>> {
+    build_blast_db(library_path, DbType::Nucleotide, runner)?;
+    let table = blastn(
+        library_path,
+        library_path,
+        out,
+        BlastOptions {
+            evalue: options.evalue,
+            threads: options.threads,
+        },
+        runner,
+    )?;
+    let lengths = library
+        .iter()
+        .map(|f| (f.name.as_str(), f.len()))
+        .collect::<HashMap<_, _>>();
+    Ok(similar_pairs(&table, &lengths, options))
+}
+
+// Let cd-hit-est cluster the library, and read its clusters back
+// as pairs of families in the same cluster. The representatives
+// are then picked as for blastn.
+fn cd_hit_pairs(
+    library_path: &Path,
+    out: &Path,
+    options: &DedupOptions,
+    runner: &dyn CommandRunner,
+) -> Result<HashSet<(String, String)>> {
+    let mut cd_hit = Command::new(CD_HIT_EST);
+    cd_hit
+        .arg("-i")
+        .arg(library_path)
+        .arg("-o")
+        .arg(out)
+        .arg("-c")
+        .arg((options.min_identity / 100.0).to_string())
+        .arg("-aS")
+        .arg(options.min_coverage.to_string())
+        // consensi shorter than the shortest alignment counted are
+        // left out, so they stay clusters of their own
+        .arg("-l")
+        .arg(options.min_length.saturating_sub(1).to_string())
+        // local identity, and word size 5 for identities of 0.8 up
+        .args([
+            "-G", "0", "-n", "5", "-g", "1", "-r", "1", "-d", "0", "-M", "0",
+        ])
+        .arg("-T")
+        .arg(options.threads.to_string());
+
+    let output = runner.run(&mut cd_hit)?;
+    if !output.status.success() {
+        return Err(Error::new(ErrorKind::GenericCli(format!(
+            "cd-hit-est failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ))));
+    }
+
+    let mut clstr = out.as_os_str().to_owned();
+    clstr.push(".clstr");
+    let clusters = read_clstr(BufReader::new(File::open(PathBuf::from(clstr))?))?;
+    let mut pairs = HashSet::new();
+    for cluster in clusters {
+        for (a, b) in cluster
+            .iter()
+            .flat_map(|a| cluster.iter().map(move |b| (a, b)))
+        {
+            if a != b {
+                pairs.insert((split_id(a).0.to_string(), split_id(b).0.to_string()));
+            }
+        }
+    }
+    Ok(pairs)
+}
+
+pub fn write_clusters_tsv<W: Write>(
+    library: &TeLibrary,
+    clusters: &[Vec<usize>],
+    writer: W,
+) -> Result<()> {
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_writer(writer);
+    wtr.write_record([
+        "cluster",
+        "family",
+        "classification",
+        "length",
+        "representative",
+        "members",
+    ])?;
+    for (n, cluster) in clusters.iter().enumerate() {
+        let representative = &library.families[cluster[0]];
+        for &i in cluster {
+            let family = &library.families[i];
+            wtr.write_record([
+                &n.to_string(),
+                &family.id(),
+                &family.classification(),
+                &family.len().to_string(),
+                &representative.id(),
+                &cluster.len().to_string(),
+            ])?;
+        }
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+// Cluster a library and keep a representative of each cluster.
+// Writes the representatives to `output` and the clusters to
+// <output>.clusters.tsv, and returns the clusters.
+pub fn dedup_library(
+    library_path: &Path,
+    output: &Path,
+    options: &DedupOptions,
+    runner: &dyn CommandRunner,
+) -> Result<Vec<Vec<String>>> {
+    let library = TeLibrary::from_file(library_path)?;
+
+    let pairs = match options.method {
+        ClusterMethod::Blastn => blastn_pairs(
+            library_path,
+            &library,
+            &output.with_extension("blastn.tsv"),
+            options,
+            runner,
+        )?,
+        ClusterMethod::CdHitEst => cd_hit_pairs(
+            library_path,
+            &output.with_extension("cdhit.fa"),
+            options,
+            runner,
+        )?,
+    };
+
+    let clusters = cluster_families(&library, &pairs, options.representative);
+    write_clusters_tsv(
+        &library,
+        &clusters,
+        File::create(output.with_extension("clusters.tsv"))?,
+    )?;
+    TeLibrary::new(
+        clusters
+            .iter()
+            .map(|c| library.families[c[0]].clone())
+            .collect(),
+    )
+    .to_file(output)?;
+
+    eprintln!(
+        "Kept {} representatives of {} families in {}",
+        clusters.len(),
+        library.len(),
+        output.display()
+    );
+    Ok(clusters
+        .iter()
+        .map(|c| c.iter().map(|&i| library.families[i].id()).collect())
+        .collect())
+}
+
+pub fn run_dedup(args: &LibraryDedupArgs) -> Result<()> {
+    let library = library_path(args.library.as_ref(), args.configure.as_ref())?;
+    let output = args.output.clone().unwrap_or_else(|| {
+        let name = library.file_name().unwrap_or_default().to_string_lossy();
+        library.with_file_name(format!("{}.dedup.fa", name))
+    });
+    dedup_library(&library, &output, &args.options, &RealCommandRunner)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curate::BLASTN;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{ExitStatus, Output};
+
+    // blastn answers with a fixed all-vs-all table, cd-hit-est with
+    // a fixed .clstr
+    struct MockCluster;
+
+    impl CommandRunner for MockCluster {
+        fn run(&self, cmd: &mut Command) -> Result<Output> {
+            let args = cmd
+                .get_args()
+                .map(|s| s.to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            let value = |flag: &str| {
+                let i = args.iter().position(|a| a == flag).unwrap();
+                args[i + 1].clone()
+            };
+            let program = cmd.get_program().to_string_lossy().to_string();
+            if program == BLASTN {
+                std::fs::write(
+                    value("-out"),
+                    "b#Unknown\ta#LTR/Gypsy\t95.0\t90\t4\t0\t1\t90\t11\t100\t1e-40\t150\n\
+                     a#LTR/Gypsy\tb#Unknown\t95.0\t90\t4\t0\t11\t100\t1\t90\t1e-40\t150\n\
+                     c#DNA\ta#LTR/Gypsy\t99.0\t85\t0\t0\t1\t85\t1\t85\t1e-40\t150\n\
+                     d#DNA/hAT\tc#DNA\t70.0\t200\t60\t0\t1\t200\t1\t200\t1e-20\t100\n\
+                     e\td#DNA/hAT\t90.0\t150\t15\t0\t1\t150\t21\t170\t1e-40\t200\n",
+                )
+                .unwrap();
+            } else if program == CD_HIT_EST {
+                assert_eq!(value("-l"), "79");
+                std::fs::write(
+                    format!("{}.clstr", value("-o")),
+                    ">Cluster 0\n0\t200nt, >c#DNA... *\n>Cluster 1\n0\t120nt, >a#LTR/Gypsy... *\n1\t100nt, >b#Unknown... at +/95.00%\n>Cluster 2\n0\t200nt, >d#DNA/hAT... *\n1\t150nt, >e... at +/90.00%\n",
+                )
+                .unwrap();
+            }
+            Ok(Output {
+                status: ExitStatus::from_raw(0),
+                stdout: vec![],
+                stderr: vec![],
+            })
+        }
+    }
+
+    fn write_library(dir: &Path) -> PathBuf {
+        let seq = |n: usize| "A".repeat(n);
+        let path = dir.join("library.fa");
+        std::fs::write(
+            &path,
+            format!(
+                ">a#LTR/Gypsy\n{}\n>b#Unknown\n{}\n>c#DNA\n{}\n>d#DNA/hAT\n{}\n>e\n{}\n",
+                seq(120),
+                seq(100),
+                seq(200),
+                seq(200),
+                seq(150)
+            ),
+        )
+        .unwrap();
+        path
+    }
+
+    #[test]
+    fn test_dedup_with_blastn() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = write_library(dir.path());
+        let output = dir.path().join("dedup.fa");
+
+        // a and b are the same family; c only covers 85 of a's 120
+        // bases, and c and d are only 70% identical. e has no
+        // classification in its header, so BLAST reports it bare
+        let clusters =
+            dedup_library(&library, &output, &DedupOptions::default(), &MockCluster).unwrap();
+        assert_eq!(
+            clusters,
+            vec![
+                vec!["c#DNA".to_string()],
+                vec!["d#DNA/hAT".to_string(), "e#Unknown".to_string()],
+                vec!["a#LTR/Gypsy".to_string(), "b#Unknown".to_string()],
+            ]
+        );
+        let kept = TeLibrary::from_file(&output).unwrap();
+        assert_eq!(kept.len(), 3);
+
+        let tsv = std::fs::read_to_string(dir.path().join("dedup.clusters.tsv")).unwrap();
+        assert!(tsv.contains("2\tb#Unknown\tUnknown\t100\ta#LTR/Gypsy\t2\n"));
+
+        // the best classified representative of a/b is still a,
+        // and d now comes first
+        let options = DedupOptions {
+            representative: Representative::BestClassified,
+            ..Default::default()
+        };
+        let clusters = dedup_library(&library, &output, &options, &MockCluster).unwrap();
+        assert_eq!(
+            clusters[0],
+            vec!["d#DNA/hAT".to_string(), "e#Unknown".to_string()]
+        );
+        assert_eq!(clusters[1][0], "a#LTR/Gypsy");
+    }
+
+    #[test]
+    fn test_dedup_with_cd_hit_est() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = write_library(dir.path());
+        let output = dir.path().join("dedup.fa");
+        let options = DedupOptions {
+            method: ClusterMethod::CdHitEst,
+            ..Default::default()
+        };
+        let clusters = dedup_library(&library, &output, &options, &MockCluster).unwrap();
+        assert_eq!(clusters.len(), 3);
+        assert_eq!(
+            clusters[2],
+            vec!["a#LTR/Gypsy".to_string(), "b#Unknown".to_string()]
+        );
+    }
+}
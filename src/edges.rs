@@ -0,0 +1,273 @@
+// Where does a TE end in the alignment of its flanked copies?
+//
+// Inside the element the copies agree; past its ends they are
+// unrelated flanking sequence. So we score each column, average the
+// scores over a sliding window, and take the element to run from
+// the first to the last conserved window. The most common base
+// always makes up at least 1/depth of a column, which for a handful
+// of copies is most of the way to conserved, so columns are scored
+// by how far they are above that, capped by how little the bases
+// disagree (entropy), and columns that are mostly gaps score 0. If
+// the alignment is
+// still conserved at an end, that edge never dropped: either the
+// flanks were too short, or the copies are segmental duplications
+// rather than insertions.
+
+use std::path::Path;
+
+use crate::{
+    consensus::{column_counts, read_alignment, ColumnCounts},
+    Result,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColumnStats {
+    // the fraction of sequences with the most common base, so
+    // gaps count against a column
+    pub conservation: f64,
+    // Shannon entropy of the bases, in bits (0-2)
+    pub entropy: f64,
+    pub gap_fraction: f64,
+    pub depth: usize,
+}
+
+impl ColumnStats {
+    pub fn from_counts(counts: &ColumnCounts) -> Self {
+        let bases = counts.bases.iter().sum::<usize>();
+        let top = *counts.bases.iter().max().expect("four bases");
+        let entropy = counts
+            .bases
+            .iter()
+            .filter(|&&n| n > 0)
+            .map(|&n| {
+                let p = n as f64 / bases as f64;
+                -p * p.log2()
+            })
+            .sum::<f64>();
+
+        let fraction = |n: usize| {
+            if counts.depth == 0 {
+                0.0
+            } else {
+                n as f64 / counts.depth as f64
+            }
+        };
+        ColumnStats {
+            conservation: fraction(top),
+            // -0.0 for a single base
+            entropy: entropy.abs(),
+            gap_fraction: fraction(counts.gaps),
+            depth: counts.depth,
+        }
+    }
+
+    // Conservation rescaled so chance (1/depth) is 0 and full
+    // agreement 1. With fewer than two sequences nothing is
+    // conserved.
+    pub fn above_chance(&self) -> f64 {
+        if self.depth < 2 {
+            return 0.0;
+        }
+        let chance = 1.0 / self.depth as f64;
+        ((self.conservation - chance) / (1.0 - chance)).max(0.0)
+    }
+
+    // How conserved the column is for finding edges: above chance,
+    // but no more than its entropy allows, and 0 if it is mostly
+    // gaps.
+    pub fn score(&self, max_gap_fraction: f64) -> f64 {
+        if self.gap_fraction > max_gap_fraction {
+            return 0.0;
+        }
+        self.above_chance().min(1.0 - self.entropy / 2.0)
+    }
+}
+
+pub fn column_stats(alignment: &[Vec<u8>]) -> Vec<ColumnStats> {
+    column_counts(alignment)
+        .iter()
+        .map(ColumnStats::from_counts)
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EdgeOptions {
+    // columns averaged over
+    pub window: usize,
+    // the mean conservation above chance of a window inside
+    // the element
+    pub min_conservation: f64,
+    // columns with more gaps than this are not conserved
+    pub max_gap_fraction: f64,
+}
+
+impl Default for EdgeOptions {
+    fn default() -> Self {
+        Self {
+            window: 10,
+            min_conservation: 0.75,
+            max_gap_fraction: 0.5,
+        }
+    }
+}
+
+// The element's columns in the alignment, [start, end), 0-based.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edges {
+    pub start: usize,
+    pub end: usize,
+    // whether conservation dropped before the end of the alignment
+    pub left_drop: bool,
+    pub right_drop: bool,
+}
+
+impl Edges {
+    // Conserved right to both ends of the alignment.
+    pub fn is_segmental_duplication(&self) -> bool {
+        !self.left_drop && !self.right_drop
+    }
+}
+
+// The mean of each window of `values`. A window longer than the
+// values is shortened to all of them.
+pub fn window_means(values: &[f64], window: usize) -> Vec<f64> {
+    let window = window.clamp(1, values.len().max(1));
+    values
+        .windows(window)
+        .map(|w| w.iter().sum::<f64>() / window as f64)
+        .collect()
+}
+
+// Find the edges of the element, or None if no window is conserved.
+// The ends of the first and last conserved windows are moved in to
+// the first conserved column, so a few agreeing bases just past the
+// end of the element aren't included.
+pub fn find_edges(stats: &[ColumnStats], options: &EdgeOptions) -> Option<Edges> {
+    let conservation = stats
+        .iter()
+        .map(|s| s.score(options.max_gap_fraction))
+        .collect::<Vec<_>>();
+    let window = options.window.clamp(1, conservation.len().max(1));
+    let means = window_means(&conservation, window);
+
+    let conserved = |c: &f64| *c >= options.min_conservation;
+    let first = means.iter().position(conserved)?;
+    let last = means.iter().rposition(conserved)?;
+
+    let mut start = first;
+    while start < last + window && !conserved(&conservation[start]) {
+        start += 1;
+    }
+    let mut end = last + window;
+    while end > start && !conserved(&conservation[end - 1]) {
+        end -= 1;
+    }
+
+    Some(Edges {
+        start,
+        end,
+        left_drop: first > 0,
+        right_drop: last + window < conservation.len(),
+    })
+}
+
+// Find the edges of the element in an aligned FASTA.
+pub fn edges_from_alignment(path: &Path, options: &EdgeOptions) -> Result<Option<Edges>> {
+    Ok(find_edges(&column_stats(&read_alignment(path)?), options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alignment(seqs: &[&str]) -> Vec<Vec<u8>> {
+        seqs.iter().map(|s| s.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_column_stats() {
+        let stats = column_stats(&alignment(&["AA-", "AC-", "AGT", "AA-"]));
+        assert_eq!(stats[0].conservation, 1.0);
+        assert_eq!(stats[0].above_chance(), 1.0);
+        assert_eq!(stats[0].entropy, 0.0);
+        assert_eq!(stats[0].score(0.5), 1.0);
+        assert_eq!(stats[1].conservation, 0.5);
+        assert_eq!(stats[1].above_chance(), 1.0 / 3.0);
+        assert_eq!(stats[1].entropy, 1.5);
+        assert_eq!(stats[2].conservation, 0.25);
+        assert_eq!(stats[2].above_chance(), 0.0);
+        assert_eq!(stats[2].gap_fraction, 0.75);
+        assert_eq!(stats[2].score(0.5), 0.0);
+        // a single sequence agrees with itself by chance
+        assert_eq!(column_stats(&alignment(&["A"]))[0].above_chance(), 0.0);
+    }
+
+    #[test]
+    fn test_find_edges() {
+        // an element of 8 columns between unrelated flanks, with one
+        // agreeing flank base on the right
+        let aln = alignment(&["AAAAACGTACGTAACA", "CCCCACGTACGTCGCT", "GGGGACGAACGTTTCG"]);
+        let options = EdgeOptions {
+            window: 4,
+            min_conservation: 0.75,
+            ..Default::default()
+        };
+        let edges = find_edges(&column_stats(&aln), &options).unwrap();
+        assert_eq!((edges.start, edges.end), (4, 12));
+        assert!(edges.left_drop && edges.right_drop);
+        assert!(!edges.is_segmental_duplication());
+
+        // two copies agree on half of their unrelated flank columns
+        // by chance; two chance matches don't carry the element on
+        let aln = alignment(&[
+            "ACGTACGTACGTACGTACGTTTGACCGGTT",
+            "ACGTACGTACGTACGTACGTCCCCCCCCCC",
+        ]);
+        let two = EdgeOptions {
+            window: 10,
+            ..Default::default()
+        };
+        let edges = find_edges(&column_stats(&aln), &two).unwrap();
+        assert_eq!((edges.start, edges.end), (0, 20));
+        assert!(edges.right_drop);
+
+        // conserved throughout
+        let aln = alignment(&["ACGTACGTAC", "ACGTACGTAC"]);
+        let edges = find_edges(&column_stats(&aln), &options).unwrap();
+        assert_eq!((edges.start, edges.end), (0, 10));
+        assert!(edges.is_segmental_duplication());
+
+        // nothing conserved
+        let aln = alignment(&["ACGT", "CATG"]);
+        assert_eq!(find_edges(&column_stats(&aln), &options), None);
+    }
+
+    #[test]
+    fn test_edges_from_alignment() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("aligned.fa");
+        std::fs::write(
+            &path,
+            ">a\nAAAAACGTACGTAACA\n>b\nCCCCACGTACGTCGCT\n>c\nGGGGACGAACGTTTCG\n",
+        )
+        .unwrap();
+        let options = EdgeOptions {
+            window: 4,
+            ..Default::default()
+        };
+        let edges = edges_from_alignment(&path, &options).unwrap().unwrap();
+        assert_eq!((edges.start, edges.end), (4, 12));
+        assert!(edges.left_drop && edges.right_drop);
+
+        // a mostly gapped stretch isn't conserved, however well the
+        // few bases in it agree
+        std::fs::write(
+            &path,
+            ">a\nACGTACGTACGT\n>b\nACGTAC------\n>c\nACGTAC------\n",
+        )
+        .unwrap();
+        let edges = edges_from_alignment(&path, &options).unwrap().unwrap();
+        assert_eq!((edges.start, edges.end), (0, 6));
+        assert!(!edges.left_drop && edges.right_drop);
+    }
+}
@@ -7,7 +7,9 @@ use std::{
 };
 
 use anyhow::Error as AnyhowError;
+use arrow_schema::ArrowError;
 use csv::Error as CsvError;
+use serde_json::Error as JsonError;
 
 // A type alias for `Result<T, rep::Error>`.
 pub type Result<T> = anyhow::Result<T, Error>;
@@ -42,6 +44,12 @@ pub enum ErrorKind {
     Parsef64(ParseFloatError),
     ParseInt(ParseIntError),
     BlastParse(CsvError),
+    // line number and message
+    BlastRow(usize, String),
+    // line number and message
+    RmOutParse(usize, String),
+    Json(JsonError),
+    Arrow(ArrowError),
 }
 
 impl StdError for Error {}
@@ -56,6 +64,18 @@ impl fmt::Display for Error {
             ErrorKind::Parsef64(err) => write!(f, "Error parsing float: {}", err),
             ErrorKind::ParseInt(err) => write!(f, "Error parsing int: {}", err),
             ErrorKind::BlastParse(err) => write!(f, "Error parsing BLAST output: {}", err),
+            ErrorKind::BlastRow(line, msg) => {
+                write!(f, "Error parsing BLAST output at line {}: {}", line, msg)
+            }
+            ErrorKind::RmOutParse(line, msg) => {
+                write!(
+                    f,
+                    "Error parsing RepeatMasker .out at line {}: {}",
+                    line, msg
+                )
+            }
+            ErrorKind::Json(err) => write!(f, "JSON error: {}", err),
+            ErrorKind::Arrow(err) => write!(f, "Arrow error: {}", err),
         }
     }
 }
@@ -90,3 +110,15 @@ impl From<CsvError> for Error {
         Error::new(ErrorKind::BlastParse(err))
     }
 }
+
+impl From<JsonError> for Error {
+    fn from(err: JsonError) -> Error {
+        Error::new(ErrorKind::Json(err))
+    }
+}
+
+impl From<ArrowError> for Error {
+    fn from(err: ArrowError) -> Error {
+        Error::new(ErrorKind::Arrow(err))
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+
+// A set of disjoint, closed (inclusive) intervals on a
+// single sequence. Coordinates are 1-based, as in the
+// RepeatMasker .out file and BLAST tabular output.
+//
+// Adjacent and overlapping intervals are merged on insertion,
+// so the set always holds the minimal number of intervals.
+#[derive(Debug, Clone, Default)]
+pub struct IntervalSet(BTreeMap<u64, u64>);
+
+impl IntervalSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Insert the interval [start, end] into the set and return
+    // the number of bases that were not already covered.
+    pub fn insert(&mut self, start: u64, end: u64) -> u64 {
+        let (start, end) = if start <= end {
+            (start, end)
+        } else {
+            (end, start)
+        };
+
+        let mut new_start = start;
+        let mut new_end = end;
+        let mut already_covered = 0;
+        let mut to_remove = Vec::new();
+
+        // walk backwards from the last interval that could touch this one
+        for (&s, &e) in self.0.range(..=end.saturating_add(1)).rev() {
+            if e.saturating_add(1) < start {
+                break;
+            }
+            // only count true overlaps, not adjacency
+            let overlap_start = s.max(start);
+            let overlap_end = e.min(end);
+            if overlap_start <= overlap_end {
+                already_covered += overlap_end - overlap_start + 1;
+            }
+            new_start = new_start.min(s);
+            new_end = new_end.max(e);
+            to_remove.push(s);
+        }
+
+        for s in to_remove {
+            self.0.remove(&s);
+        }
+        self.0.insert(new_start, new_end);
+
+        (end - start + 1) - already_covered
+    }
+
+    // The parts of [start, end] not covered by the set.
+    pub fn uncovered(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        let (start, end) = if start <= end {
+            (start, end)
+        } else {
+            (end, start)
+        };
+
+        let mut gaps = Vec::new();
+        let mut cursor = start;
+        // the first interval that may overlap starts at or before `start`
+        let first = self
+            .0
+            .range(..=start)
+            .next_back()
+            .map(|(&s, _)| s)
+            .unwrap_or(start);
+        for (&s, &e) in self.0.range(first..=end) {
+            if e < cursor {
+                continue;
+            }
+            if s > cursor {
+                gaps.push((cursor, s - 1));
+            }
+            cursor = e + 1;
+            if cursor > end {
+                return gaps;
+            }
+        }
+        gaps.push((cursor, end));
+        gaps
+    }
+
+    // Total number of bases covered by the set.
+    pub fn covered(&self) -> u64 {
+        self.0.iter().map(|(s, e)| e - s + 1).sum()
+    }
+
+    // Iterate over the merged intervals in order.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.0.iter().map(|(&s, &e)| (s, e))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_counts_new_bases_only() {
+        let mut set = IntervalSet::new();
+        assert_eq!(set.insert(10, 19), 10);
+        // fully contained
+        assert_eq!(set.insert(12, 15), 0);
+        // partial overlap on the right
+        assert_eq!(set.insert(15, 24), 5);
+        // adjacent, merged but nothing double counted
+        assert_eq!(set.insert(25, 30), 6);
+        // reversed coordinates are normalised
+        assert_eq!(set.insert(5, 1), 5);
+
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![(1, 5), (10, 30)]);
+        assert_eq!(set.covered(), 26);
+
+        // bridging two intervals
+        assert_eq!(set.insert(4, 11), 4);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![(1, 30)]);
+    }
+
+    #[test]
+    fn test_uncovered() {
+        let mut set = IntervalSet::new();
+        set.insert(10, 20);
+        set.insert(30, 40);
+        assert_eq!(set.uncovered(1, 50), vec![(1, 9), (21, 29), (41, 50)]);
+        assert_eq!(set.uncovered(15, 35), vec![(21, 29)]);
+        assert_eq!(set.uncovered(12, 18), vec![]);
+        assert_eq!(set.uncovered(41, 45), vec![(41, 45)]);
+    }
+}
@@ -1,21 +1,46 @@
 // Public modules used across the CLI tool
+pub mod annotation; // .out/GFF3/BED repeat annotations
 pub mod cli; // Command-line argument parsing
 pub mod command_runner;
+pub mod consensus; // Majority-rule consensus from an alignment
+pub mod curate; // BLAST of the consensi for curation
+pub mod decontam; // Host gene screening of TE libraries
+pub mod dedup; // 80-80-80 clustering of TE libraries
+pub mod edges; // Element boundaries in an alignment
 pub mod error; // Error types and handling
+pub mod intervals; // Merging of overlapping intervals
+pub mod library; // RepeatModeler/RepeatMasker TE libraries
+pub mod mafft; // MAFFT wrapper for the curation
+pub mod mask; // Soft/hard masking from annotations
+pub mod merge; // Merging of de novo and curated TE libraries
+pub mod output; // JSON Lines and Arrow IPC output
 pub mod parse_blast; // BLAST outfmt 7 parser
+pub mod parse_rmout; // RepeatMasker .out parser
+pub mod qc; // Poly-A tail and ORF checks of consensi
 pub mod repeatmasker; // RepeatMasker wrapper
 pub mod repeatmodeler; // RepeatModeler wrapper
+pub mod resolve; // Overlap and fragment resolution of the .out
+pub mod structure; // TIR, LTR and TSD detection
+pub mod summary; // Repeat summary table from the .out
 
 // Re-export key types and functions
-pub use cli::{parse_args, CliArgs};
+pub use cli::{
+    parse_args, CliArgs, CurateArgs, LibraryCommand, LibraryConvertArgs, LibraryDecontamArgs,
+    LibraryDedupArgs, LibraryRenameArgs, MaskFastaArgs, RepCommand,
+};
 pub use command_runner::{CommandRunner, RealCommandRunner};
+pub use curate::run_curate;
 pub use error::{Error, ErrorKind, Result};
+pub use library::run_library;
+pub use mask::run_mask_fasta;
 pub use repeatmasker::run_repeatmasker;
 pub use repeatmodeler::run_repeatmodeler;
+pub use resolve::resolve_repeatmasker;
+pub use summary::summarise_repeatmasker;
 
 use std::{
     fs::{self, File},
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
 };
 
@@ -26,17 +51,62 @@ const PIPELINE_SCRIPTS: &str = "pipeline_scripts";
 const DATA: &str = "data";
 
 // Utility to create a named subdirectory within a base path
-fn make_subdir(base: &PathBuf, name: &str) -> Result<()> {
-    let mut p = base.clone();
+fn make_subdir(base: &Path, name: &str) -> Result<()> {
+    let mut p = base.to_path_buf();
     p.push(name);
     fs::create_dir_all(&p)?;
     Ok(())
 }
 
+// The path to the genome as copied (and decompressed) into
+// the data directory by `set_up_filesystem`
+pub(crate) fn staged_genome_path(configure: &Path, fasta_file: &Path) -> Result<PathBuf> {
+    let base_fasta_name = fasta_file
+        .file_name()
+        .ok_or_else(|| Error::new(ErrorKind::GenericCli("FASTA path has no file name".into())))?;
+    let fasta_name = base_fasta_name.to_string_lossy();
+
+    let mut data_path = configure.join(DATA);
+    data_path.push(fasta_name.strip_suffix(".gz").unwrap_or(&fasta_name));
+
+    Ok(data_path)
+}
+
+pub(crate) fn staged_genome(matches: &CliArgs) -> Result<PathBuf> {
+    let configure = matches.configure.as_ref().ok_or_else(|| {
+        Error::new(ErrorKind::GenericCli(
+            "A configure directory is required to find the staged genome".into(),
+        ))
+    })?;
+    staged_genome_path(configure, &matches.fasta_file)
+}
+
 // Main entry point for running the full pipeline
 pub fn pipeline() -> Result<()> {
     // now parse the args
-    let matches = parse_args()?;
+    let matches = match parse_args()? {
+        RepCommand::Pipeline(matches) => matches,
+        RepCommand::MaskFasta(args) => return run_mask_fasta(args),
+        RepCommand::Curate(args) => return run_curate(args),
+        RepCommand::Library(command) => return run_library(command),
+    };
+
+    // the curation pipeline doesn't need RepeatMasker/RepeatModeler,
+    // only BLAST. Stage the genome it is given and curate.
+    if let Some(genome) = matches.curation_only.clone() {
+        let configure = matches.configure.clone().expect("errored by clap");
+        set_up_filesystem(CliArgs {
+            fasta_file: genome.clone(),
+            ..matches.clone()
+        })?;
+        eprintln!("Running the curation pipeline only...");
+        return run_curate(cli::curation_args(
+            &genome,
+            &configure,
+            matches.curation_rmdl_library.as_deref(),
+            matches.rmo_threads,
+        )?);
+    }
 
     // check whether the executables are there first
     check_executables()?;
@@ -50,6 +120,8 @@ pub fn pipeline() -> Result<()> {
         eprintln!("Running RepeatMasker only...");
         let runner = RealCommandRunner;
         run_repeatmasker(matches.clone(), &runner)?;
+        summarise_repeatmasker(&matches)?;
+        resolve_repeatmasker(&matches)?;
         return Ok(());
     }
 
@@ -62,6 +134,11 @@ pub fn pipeline() -> Result<()> {
     eprintln!("Running RepeatMasker...");
     run_repeatmasker(matches.clone(), &runner)?;
 
+    // and summarise the output
+    eprintln!("Summarising RepeatMasker output...");
+    summarise_repeatmasker(&matches)?;
+    resolve_repeatmasker(&matches)?;
+
     Ok(())
 }
 
@@ -210,6 +287,8 @@ mod tests {
             rmo_threads: 1,
             rma_threads: 1,
             rma_only: false,
+            curation_only: None,
+            curation_rmdl_library: None,
             verbose: false,
         };
 
@@ -222,4 +301,18 @@ mod tests {
         assert!(config_path.join("data").join("repeatmasker").exists());
         assert!(config_path.join("data").join("repeatmodeler").exists());
     }
+
+    #[test]
+    fn test_curation_args_match_rep_curate() {
+        let dir = tempdir().unwrap();
+        let genome = dir.path().join("genome.fa");
+        let args = cli::curation_args(&genome, dir.path(), None, 4).unwrap();
+        assert_eq!(args.evalue, 1e-10);
+        assert_eq!((args.threads, args.mafft.threads), (4, 4));
+        assert_eq!(args.extract.top_n, 20);
+        assert!(args.library.is_none());
+
+        let missing = dir.path().join("consensi.fa.classified");
+        assert!(cli::curation_args(&genome, dir.path(), Some(&missing), 4).is_err());
+    }
 }
@@ -0,0 +1,1364 @@
+// A library of TE consensi, as written by RepeatModeler:
+//
+// >rnd-1_family-12#LTR/Gypsy ( Recon Family Size = 45, Final Multiple Alignment Size = 40 )
+//
+// The ID is the family name and its classification, class/subclass,
+// separated by #. The description holds what RECON/RepeatScout (or
+// the LTR pipeline, in square brackets) knew about the family.
+// Written back out, the ID is left as RepeatMasker expects it.
+//
+// Curated libraries come as FASTA in the same format, as exported
+// from Dfam (>MIR#SINE/MIR @Mammalia [S:40,60,65]), as RepBase
+// FASTA (>MIR<tab>SINE<tab>Mammalia), or as EMBL, with the
+// classification in RepeatMasker's CC annotations. RepeatModeler
+// and Dfam also write the seed alignments of the families as
+// Stockholm, which is read with the consensus and kept alongside it.
+//
+// `rep library` works on these libraries. `rename` gives the
+// families species-prefixed names, so libraries from several
+// projects can be merged, and renames them in the outputs already
+// written with the old names. `convert` moves a library between
+// FASTA, EMBL and Stockholm. `dedup`, `decontaminate` and `merge`
+// are in dedup.rs, decontam.rs and merge.rs.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use bio::io::fasta;
+use walkdir::WalkDir;
+
+use crate::{
+    cli::{LibraryCommand, LibraryConvertArgs, LibraryRenameArgs},
+    consensus::{consensus, ConsensusOptions},
+    decontam::run_decontam,
+    dedup::run_dedup,
+    merge::run_merge,
+    repeatmasker::find_consensi,
+    Error, ErrorKind, Result, DATA, INTERMEDIATE,
+};
+
+// RepeatMasker's class for families RepeatClassifier couldn't classify.
+pub const UNKNOWN: &str = "Unknown";
+
+// Split an ID into the family name and its classification, if any.
+pub fn split_id(id: &str) -> (&str, Option<&str>) {
+    match id.split_once('#') {
+        Some((name, classification)) => (name, Some(classification)),
+        None => (id, None),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TeFamily {
+    pub name: String,
+    // e.g. LTR, or Unknown if the consensus was not classified
+    pub class: String,
+    // e.g. Gypsy
+    pub subclass: Option<String>,
+    // the description, written back as it was read
+    description: Option<String>,
+    // key = value pairs from a RepeatModeler description, in order.
+    // Items without a value, e.g. "Localized to 37 out of 38
+    // contigs", have an empty value.
+    pub metadata: Vec<(String, String)>,
+    // the clades of a curated family, from @Mammalia tags
+    pub species: Vec<String>,
+    pub sequence: Vec<u8>,
+    // the alignment the consensus was built from, if the library
+    // came with one
+    pub seed: Option<SeedAlignment>,
+}
+
+impl TeFamily {
+    pub fn new(name: &str, classification: Option<&str>, sequence: &[u8]) -> Self {
+        let (class, subclass) = match classification {
+            Some(c) => match c.split_once('/') {
+                Some((class, subclass)) => (class.to_string(), Some(subclass.to_string())),
+                None => (c.to_string(), None),
+            },
+            None => (UNKNOWN.to_string(), None),
+        };
+        TeFamily {
+            name: name.to_string(),
+            class,
+            subclass,
+            description: None,
+            metadata: Vec::new(),
+            species: Vec::new(),
+            sequence: sequence.to_vec(),
+            seed: None,
+        }
+    }
+
+    pub fn from_record(record: &fasta::Record) -> Self {
+        let (name, classification) = split_id(record.id());
+        // RepBase: name<tab>class<tab>species
+        let repbase = match (classification, record.desc()) {
+            (None, Some(desc)) if desc.contains('\t') => desc.split_once('\t'),
+            _ => None,
+        };
+        if let Some((class, species)) = repbase {
+            let class = class.trim().replace(' ', "_");
+            let mut family = TeFamily::new(name, Some(&class), record.seq());
+            family.set_species(species.split('\t').map(str::trim).filter(|s| !s.is_empty()));
+            return family;
+        }
+
+        let mut family = TeFamily::new(name, classification, record.seq());
+        if let Some(desc) = record.desc() {
+            family.description = Some(desc.to_string());
+            family.metadata = parse_metadata(desc);
+            family.species = desc
+                .split_whitespace()
+                .filter_map(|w| w.strip_prefix('@'))
+                .map(str::to_string)
+                .collect();
+        }
+        family
+    }
+
+    // Set the clades. Without a description, they are written as
+    // @tags in their place.
+    pub fn set_species<I: IntoIterator<Item = S>, S: AsRef<str>>(&mut self, species: I) {
+        self.species = species
+            .into_iter()
+            .map(|s| s.as_ref().replace(' ', "_"))
+            .collect();
+        let tags = self
+            .species
+            .iter()
+            .map(|s| format!("@{}", s))
+            .collect::<Vec<_>>();
+        if self.description.is_none() && !tags.is_empty() {
+            self.description = Some(tags.join(" "));
+        }
+    }
+
+    // class/subclass, as in the ID
+    pub fn classification(&self) -> String {
+        match &self.subclass {
+            Some(subclass) => format!("{}/{}", self.class, subclass),
+            None => self.class.clone(),
+        }
+    }
+
+    // The RepeatMasker library ID, name#class/subclass
+    pub fn id(&self) -> String {
+        format!("{}#{}", self.name, self.classification())
+    }
+
+    pub fn len(&self) -> usize {
+        self.sequence.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sequence.is_empty()
+    }
+
+    pub fn is_unknown(&self) -> bool {
+        self.class.eq_ignore_ascii_case(UNKNOWN)
+    }
+
+    // Whether the classification is, or is under, `class`, so
+    // "LTR" matches LTR/Gypsy but not LTRx.
+    pub fn is_class(&self, class: &str) -> bool {
+        let classification = self.classification();
+        classification == class
+            || classification
+                .strip_prefix(class)
+                .is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    pub fn metadata(&self, key: &str) -> Option<&str> {
+        self.metadata
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    // The program that built the family, from the name of the
+    // family size: Recon or RepeatScout.
+    pub fn builder(&self) -> Option<&str> {
+        self.metadata
+            .iter()
+            .find_map(|(k, _)| k.strip_suffix(" Family Size"))
+    }
+
+    // The number of copies the family was built from.
+    pub fn family_size(&self) -> Option<u64> {
+        self.metadata
+            .iter()
+            .find(|(k, _)| k.ends_with("Family Size"))
+            .and_then(|(_, v)| v.parse().ok())
+    }
+
+    pub fn alignment_size(&self) -> Option<u64> {
+        self.metadata("Final Multiple Alignment Size")
+            .and_then(|v| v.parse().ok())
+    }
+
+    // The description as it was read, or as RepeatModeler writes
+    // it from the metadata.
+    pub fn description(&self) -> Option<String> {
+        if self.description.is_some() || self.metadata.is_empty() {
+            return self.description.clone();
+        }
+        let items = self
+            .metadata
+            .iter()
+            .map(|(k, v)| {
+                if v.is_empty() {
+                    k.clone()
+                } else {
+                    format!("{} = {}", k, v)
+                }
+            })
+            .collect::<Vec<_>>();
+        Some(format!("( {} )", items.join(", ")))
+    }
+
+    pub fn to_record(&self) -> fasta::Record {
+        fasta::Record::with_attrs(&self.id(), self.description().as_deref(), &self.sequence)
+    }
+}
+
+// "( Recon Family Size = 45, Final Multiple Alignment Size = 40 )"
+// or "[ Type=LTR, Final Multiple Alignment Size = 4 ]". Other
+// descriptions have no metadata.
+fn parse_metadata(desc: &str) -> Vec<(String, String)> {
+    let desc = desc.trim();
+    if !desc.starts_with(['(', '[']) {
+        return Vec::new();
+    }
+    let inner = desc
+        .trim_start_matches(['(', '['])
+        .trim_end_matches([')', ']']);
+    inner
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| match item.split_once('=') {
+            Some((k, v)) => (k.trim().to_string(), v.trim().to_string()),
+            None => (item.to_string(), String::new()),
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LibraryFormat {
+    Fasta,
+    Embl,
+    Stockholm,
+}
+
+impl LibraryFormat {
+    // EMBL libraries are .embl, or .dat as Dfam and RepBase ship
+    // them; Stockholm is .stk or .sto. Anything else is taken to
+    // be FASTA.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("embl" | "dat" | "ebl") => LibraryFormat::Embl,
+            Some("stk" | "sto" | "stockholm") => LibraryFormat::Stockholm,
+            _ => LibraryFormat::Fasta,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "fasta" => Some(LibraryFormat::Fasta),
+            "embl" => Some(LibraryFormat::Embl),
+            "stockholm" => Some(LibraryFormat::Stockholm),
+            _ => None,
+        }
+    }
+}
+
+// The seed alignment of a family, as in a RepeatModeler or Dfam
+// Stockholm file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SeedAlignment {
+    // sequence names and aligned sequences, in order
+    pub sequences: Vec<(String, Vec<u8>)>,
+    // the #=GC RF line: the consensus base, or x, of each column
+    // that is in the consensus, and a gap otherwise
+    pub reference: Option<Vec<u8>>,
+}
+
+impl SeedAlignment {
+    // The consensus: the bases of the RF line if it has them,
+    // otherwise called from the columns of the alignment it marks.
+    pub fn consensus(&self) -> Vec<u8> {
+        let is_gap = |c: &u8| matches!(c, b'.' | b'-');
+        if let Some(rf) = &self.reference {
+            if rf
+                .iter()
+                .any(|c| !is_gap(c) && !c.eq_ignore_ascii_case(&b'x'))
+            {
+                return rf
+                    .iter()
+                    .filter(|c| !is_gap(c))
+                    .map(u8::to_ascii_uppercase)
+                    .collect();
+            }
+        }
+        let columns = match &self.reference {
+            Some(rf) => rf
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| !is_gap(c))
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>(),
+            None => (0..self.sequences.first().map_or(0, |(_, s)| s.len())).collect(),
+        };
+        let alignment = self
+            .sequences
+            .iter()
+            .map(|(_, s)| columns.iter().filter_map(|&i| s.get(i).copied()).collect())
+            .collect::<Vec<Vec<u8>>>();
+        consensus(&alignment, &ConsensusOptions::default())
+    }
+}
+
+// The fields of an EMBL or Stockholm entry we need, as read.
+#[derive(Default)]
+struct Entry {
+    id: Option<String>,
+    name: Option<String>,
+    description: Vec<String>,
+    rm_type: Option<String>,
+    rm_subtype: Option<String>,
+    keywords: String,
+    cc_species: Option<String>,
+    os: Vec<String>,
+    sequence: Vec<u8>,
+    seed: Option<SeedAlignment>,
+}
+
+impl Entry {
+    // RepeatMasker's annotations in CC lines, which Dfam writes to
+    // both EMBL and Stockholm.
+    fn comment(&mut self, value: &str) {
+        match value.split_once(':') {
+            Some(("Type", v)) => self.rm_type = Some(v.trim().to_string()),
+            Some(("SubType", v)) => self.rm_subtype = Some(v.trim().to_string()),
+            Some(("Species", v)) => self.cc_species = Some(v.trim().to_string()),
+            _ => (),
+        }
+    }
+
+    fn into_family(self, format: &str, line_no: usize) -> Result<TeFamily> {
+        let id = self.name.or(self.id).ok_or_else(|| {
+            Error::new(ErrorKind::GenericCli(format!(
+                "{} entry ending at line {} has no ID",
+                format, line_no
+            )))
+        })?;
+        // a RepeatMasker ID, RepeatMasker's annotations, or a
+        // keyword like SINE/MIR
+        let (name, in_id) = split_id(&id);
+        let classification = match (in_id, self.rm_type, self.rm_subtype) {
+            (Some(c), _, _) => Some(c.to_string()),
+            (None, Some(t), Some(s)) if !s.is_empty() => Some(format!("{}/{}", t, s)),
+            (None, Some(t), _) if !t.is_empty() => Some(t),
+            _ => self
+                .keywords
+                .split(';')
+                .map(|k| k.trim().trim_end_matches('.'))
+                .find(|k| k.contains('/'))
+                .map(str::to_string),
+        };
+
+        let seed = self.seed.filter(|s| !s.sequences.is_empty());
+        let sequence = match &seed {
+            Some(seed) if self.sequence.is_empty() => seed.consensus(),
+            _ => self.sequence,
+        };
+        // a family written as its consensus alone has no seed
+        let seed = seed.filter(|s| match s.sequences.as_slice() {
+            [(_, aligned)] => !aligned
+                .iter()
+                .filter(|c| !matches!(c, b'.' | b'-'))
+                .map(u8::to_ascii_uppercase)
+                .eq(sequence.iter().map(u8::to_ascii_uppercase)),
+            _ => true,
+        });
+        let mut family = TeFamily::new(name, classification.as_deref(), &sequence);
+        if !self.description.is_empty() {
+            let description = self.description.join(" ");
+            family.metadata = parse_metadata(&description);
+            family.description = Some(description);
+        }
+        match self.cc_species {
+            Some(species) => {
+                family.set_species(species.split(',').map(str::trim).filter(|s| !s.is_empty()))
+            }
+            None => family.set_species(&self.os),
+        }
+        family.seed = seed;
+        Ok(family)
+    }
+}
+
+// RepeatMasker's annotations of a family, as Dfam writes them in
+// the CC lines of both EMBL and Stockholm.
+fn rm_annotations(family: &TeFamily) -> [String; 4] {
+    [
+        "RepeatMasker Annotations:".to_string(),
+        format!("     Type: {}", family.class),
+        format!("     SubType: {}", family.subclass.as_deref().unwrap_or("")),
+        format!("     Species: {}", family.species.join(", ")),
+    ]
+}
+
+// The bases of an EMBL SQ line, counted as A, C, G, T and other.
+fn base_counts(seq: &[u8]) -> [usize; 5] {
+    let mut counts = [0; 5];
+    for c in seq {
+        let i = match c.to_ascii_uppercase() {
+            b'A' => 0,
+            b'C' => 1,
+            b'G' => 2,
+            b'T' => 3,
+            _ => 4,
+        };
+        counts[i] += 1;
+    }
+    counts
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TeLibrary {
+    pub families: Vec<TeFamily>,
+}
+
+impl TeLibrary {
+    pub fn new(families: Vec<TeFamily>) -> Self {
+        Self { families }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_reader(File::open(path)?)
+    }
+
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self> {
+        let mut families = Vec::new();
+        for record in fasta::Reader::new(reader).records() {
+            families.push(TeFamily::from_record(&record?));
+        }
+        Ok(Self { families })
+    }
+
+    // A FASTA, EMBL or Stockholm library, by its extension.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::read_as(path.as_ref(), LibraryFormat::from_path(path.as_ref()))
+    }
+
+    pub fn read_as(path: &Path, format: LibraryFormat) -> Result<Self> {
+        match format {
+            LibraryFormat::Fasta => Self::from_file(path),
+            LibraryFormat::Embl => Self::from_embl_reader(BufReader::new(File::open(path)?)),
+            LibraryFormat::Stockholm => {
+                Self::from_stockholm_reader(BufReader::new(File::open(path)?))
+            }
+        }
+    }
+
+    // Dfam and RepBase EMBL. Only the name, description,
+    // classification, species and sequence are kept.
+    pub fn from_embl_reader<R: BufRead>(reader: R) -> Result<Self> {
+        let mut families = Vec::new();
+        let mut entry = Entry::default();
+        let mut in_sequence = false;
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.starts_with("//") {
+                families.push(std::mem::take(&mut entry).into_family("EMBL", i + 1)?);
+                in_sequence = false;
+                continue;
+            }
+            if in_sequence {
+                entry.sequence.extend(
+                    line.bytes()
+                        .filter(u8::is_ascii_alphabetic)
+                        .map(|c| c.to_ascii_uppercase()),
+                );
+                continue;
+            }
+            let (code, value) = (
+                line.get(..2).unwrap_or(""),
+                line.get(5..).unwrap_or("").trim(),
+            );
+            match code {
+                "ID" => {
+                    entry.id = value
+                        .split([' ', ';'])
+                        .next()
+                        .filter(|id| !id.is_empty())
+                        .map(str::to_string)
+                }
+                "NM" => entry.name = Some(value.to_string()),
+                "DE" => entry.description.push(value.to_string()),
+                "KW" => {
+                    entry.keywords.push_str(value);
+                    entry.keywords.push(' ');
+                }
+                "OS" => entry.os.push(value.to_string()),
+                "CC" => entry.comment(value),
+                "SQ" => in_sequence = true,
+                _ => (),
+            }
+        }
+        Ok(Self { families })
+    }
+
+    // RepeatModeler and Dfam Stockholm seed alignments. The
+    // consensus is taken from the RF line, or called from the seed.
+    pub fn from_stockholm_reader<R: BufRead>(reader: R) -> Result<Self> {
+        let mut families = Vec::new();
+        let mut entry = Entry::default();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim_end();
+            if line.starts_with("//") {
+                families.push(std::mem::take(&mut entry).into_family("Stockholm", i + 1)?);
+                continue;
+            }
+            if line.is_empty() || line.starts_with("# STOCKHOLM") {
+                continue;
+            }
+
+            if let Some(gf) = line.strip_prefix("#=GF ") {
+                let (tag, value) = gf.split_once(char::is_whitespace).unwrap_or((gf, ""));
+                let value = value.trim();
+                match tag {
+                    "ID" => entry.id = Some(value.to_string()),
+                    "DE" => entry.description.push(value.to_string()),
+                    "OC" => entry.os.push(value.to_string()),
+                    "CC" => entry.comment(value),
+                    _ => (),
+                }
+            } else if let Some(rf) = line.strip_prefix("#=GC RF") {
+                let seed = entry.seed.get_or_insert_with(Default::default);
+                seed.reference
+                    .get_or_insert_with(Vec::new)
+                    .extend(rf.trim().bytes());
+            } else if !line.starts_with('#') {
+                // sequences may be split over several blocks
+                let (name, aligned) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+                let aligned = aligned.trim().bytes();
+                let seed = entry.seed.get_or_insert_with(Default::default);
+                match seed.sequences.iter_mut().find(|(n, _)| n == name) {
+                    Some((_, seq)) => seq.extend(aligned),
+                    None => seed.sequences.push((name.to_string(), aligned.collect())),
+                }
+            }
+        }
+        Ok(Self { families })
+    }
+
+    pub fn len(&self) -> usize {
+        self.families.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.families.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &TeFamily> {
+        self.families.iter()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TeFamily> {
+        self.families.iter().find(|f| f.name == name)
+    }
+
+    // Keep the families of `class`, e.g. "LTR" or "LINE/L1".
+    pub fn filter_by_class(mut self, class: &str) -> Self {
+        self.families.retain(|f| f.is_class(class));
+        self
+    }
+
+    // Keep the families RepeatClassifier couldn't classify.
+    pub fn unknown_only(mut self) -> Self {
+        self.families.retain(|f| f.is_unknown());
+        self
+    }
+
+    // Keep the families with a consensus of `min` to `max` bases.
+    pub fn filter_by_length(mut self, min: usize, max: Option<usize>) -> Self {
+        self.families
+            .retain(|f| f.len() >= min && max.is_none_or(|max| f.len() <= max));
+        self
+    }
+
+    pub fn write_fasta<W: Write>(&self, writer: W) -> Result<()> {
+        let mut writer = fasta::Writer::new(writer);
+        for family in &self.families {
+            writer.write_record(&family.to_record())?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.write_fasta(BufWriter::new(File::create(path)?))
+    }
+
+    // Write the library in `format`.
+    pub fn write_as(&self, path: &Path, format: LibraryFormat) -> Result<()> {
+        let writer = BufWriter::new(File::create(path)?);
+        match format {
+            LibraryFormat::Fasta => self.write_fasta(writer),
+            LibraryFormat::Embl => self.write_embl(writer),
+            LibraryFormat::Stockholm => self.write_stockholm(writer),
+        }
+    }
+
+    // EMBL as Dfam exports it, with RepeatMasker's annotations.
+    pub fn write_embl<W: Write>(&self, mut writer: W) -> Result<()> {
+        for family in &self.families {
+            let len = family.len();
+            writeln!(
+                writer,
+                "ID   {}; SV 1; linear; DNA; STD; UNC; {} BP.",
+                family.name, len
+            )?;
+            writeln!(writer, "NM   {}\nXX", family.name)?;
+            if let Some(description) = family.description() {
+                writeln!(writer, "DE   {}\nXX", description)?;
+            }
+            for species in &family.species {
+                writeln!(writer, "OS   {}", species)?;
+            }
+            if !family.species.is_empty() {
+                writeln!(writer, "XX")?;
+            }
+            for line in rm_annotations(family) {
+                writeln!(writer, "CC   {}", line)?;
+            }
+            writeln!(writer, "XX")?;
+
+            let [a, c, g, t, other] = base_counts(&family.sequence);
+            writeln!(
+                writer,
+                "SQ   Sequence {} BP; {} A; {} C; {} G; {} T; {} other;",
+                len, a, c, g, t, other
+            )?;
+            for (i, line) in family.sequence.chunks(60).enumerate() {
+                let groups = line
+                    .chunks(10)
+                    .map(|g| String::from_utf8_lossy(g).to_lowercase())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                writeln!(writer, "     {:<65}{:>10}", groups, i * 60 + line.len())?;
+            }
+            writeln!(writer, "//")?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Stockholm, one alignment per family. A family without a seed
+    // is written as its consensus alone; either way the RF line
+    // carries the consensus when it can.
+    pub fn write_stockholm<W: Write>(&self, mut writer: W) -> Result<()> {
+        for family in &self.families {
+            let seed = match &family.seed {
+                Some(seed) => seed.clone(),
+                None => SeedAlignment {
+                    sequences: vec![(family.name.clone(), family.sequence.clone())],
+                    reference: Some(family.sequence.clone()),
+                },
+            };
+            writeln!(writer, "# STOCKHOLM 1.0")?;
+            writeln!(writer, "#=GF ID    {}", family.name)?;
+            if let Some(description) = family.description() {
+                writeln!(writer, "#=GF DE    {}", description)?;
+            }
+            for species in &family.species {
+                writeln!(writer, "#=GF OC    {}", species)?;
+            }
+            for line in rm_annotations(family) {
+                writeln!(writer, "#=GF CC    {}", line)?;
+            }
+            writeln!(writer, "#=GF SQ    {}", seed.sequences.len())?;
+
+            let width = seed
+                .sequences
+                .iter()
+                .map(|(n, _)| n.len())
+                .chain([7])
+                .max()
+                .unwrap_or(7);
+            for (name, aligned) in &seed.sequences {
+                writeln!(
+                    writer,
+                    "{:<width$} {}",
+                    name,
+                    String::from_utf8_lossy(aligned),
+                    width = width
+                )?;
+            }
+            if let Some(rf) = &seed.reference {
+                writeln!(
+                    writer,
+                    "{:<width$} {}",
+                    "#=GC RF",
+                    String::from_utf8_lossy(rf),
+                    width = width
+                )?;
+            }
+            writeln!(writer, "//")?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Prefix the family names with `prefix`, keeping their
+    // classification. Returns the old and new names of the families
+    // that were renamed; names already carrying the prefix are left
+    // alone, so renaming twice changes nothing.
+    pub fn rename(&mut self, prefix: &str) -> Vec<(String, String)> {
+        let mut renamed = Vec::new();
+        for family in &mut self.families {
+            let name = prefixed_name(&family.name, prefix);
+            if name != family.name {
+                renamed.push((std::mem::replace(&mut family.name, name.clone()), name));
+            }
+        }
+        renamed
+    }
+}
+
+// e.g. dmel_rnd-1_family-12
+pub fn prefixed_name(name: &str, prefix: &str) -> String {
+    match name.strip_prefix(prefix) {
+        Some(rest) if rest.starts_with('_') => name.to_string(),
+        _ => format!("{}_{}", prefix, name),
+    }
+}
+
+// A prefix has to survive as part of an ID in every format a
+// family name ends up in.
+fn check_prefix(prefix: &str) -> Result<()> {
+    if prefix.is_empty()
+        || !prefix
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(Error::new(ErrorKind::GenericCli(format!(
+            "Invalid prefix '{}': use letters, digits, _ and - only",
+            prefix
+        ))));
+    }
+    Ok(())
+}
+
+pub fn write_mapping_tsv<W: Write>(
+    mapping: &[(String, String)],
+    library: &TeLibrary,
+    writer: W,
+) -> Result<()> {
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_writer(writer);
+    wtr.write_record(["old_name", "new_name", "classification"])?;
+    for (old, new) in mapping {
+        let classification = library
+            .get(new)
+            .map(|f| f.classification())
+            .unwrap_or_default();
+        wtr.write_record([old.as_str(), new.as_str(), &classification])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+// The characters a family name is made of. A name in a column is
+// a whole run of these, so rnd-1_family-1 isn't found inside
+// rnd-1_family-12, whether it is the whole .out column, after
+// Motif: in a GFF Target, or before the #class of a BLAST query.
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.'
+}
+
+// Replace the family names in a column.
+pub fn rename_line(line: &str, names: &HashMap<&str, &str>) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        let len = if is_name_char(c) {
+            rest.find(|c: char| !is_name_char(c)).unwrap_or(rest.len())
+        } else {
+            c.len_utf8()
+        };
+        let (token, tail) = rest.split_at(len);
+        out.push_str(names.get(token).copied().unwrap_or(token));
+        rest = tail;
+    }
+    out
+}
+
+// The byte range of field `n` of `line`, split on tabs, or on runs
+// of whitespace if `tab` isn't set.
+fn field_span(line: &str, n: usize, tab: bool) -> Option<(usize, usize)> {
+    if tab {
+        let mut start = 0;
+        for (i, field) in line.split('\t').enumerate() {
+            if i == n {
+                return Some((start, start + field.len()));
+            }
+            start += field.len() + 1;
+        }
+        return None;
+    }
+    let mut fields = 0;
+    let mut start = None;
+    for (i, c) in line.char_indices() {
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some(i),
+            (true, Some(s)) => {
+                if fields == n {
+                    return Some((s, i));
+                }
+                fields += 1;
+                start = None;
+            }
+            _ => (),
+        }
+    }
+    start.filter(|_| fields == n).map(|s| (s, line.len()))
+}
+
+// The outputs the families are renamed in, each by the column
+// holding their names; sequence IDs and other columns are left as
+// they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenameTarget {
+    // the repeat column of the RepeatMasker .out
+    RmOut,
+    // the attributes of RepeatMasker's GFF
+    Gff,
+    // the query column of a BLAST table of the consensi, and the
+    // subject column where it is a family ID (name#classification)
+    // too, as in an all-vs-all table of a library
+    Blast,
+}
+
+impl RenameTarget {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("out") => Some(RenameTarget::RmOut),
+            Some("gff" | "gff3") => Some(RenameTarget::Gff),
+            Some("tsv" | "txt" | "blast") => Some(RenameTarget::Blast),
+            _ => None,
+        }
+    }
+
+    // Rename the families in the name column of `line`.
+    pub fn rename(&self, line: &str, names: &HashMap<&str, &str>) -> String {
+        let spans = match self {
+            _ if line.starts_with('#') => vec![],
+            RenameTarget::RmOut => field_span(line, 9, false).into_iter().collect(),
+            RenameTarget::Gff => field_span(line, 8, true).into_iter().collect(),
+            RenameTarget::Blast => field_span(line, 0, true)
+                .into_iter()
+                .chain(field_span(line, 1, true).filter(|&(s, e)| line[s..e].contains('#')))
+                .collect::<Vec<_>>(),
+        };
+        let mut renamed = String::with_capacity(line.len());
+        let mut last = 0;
+        for (start, end) in spans {
+            renamed.push_str(&line[last..start]);
+            renamed.push_str(&rename_line(&line[start..end], names));
+            last = end;
+        }
+        renamed.push_str(&line[last..]);
+        renamed
+    }
+}
+
+// Rename the families in an output. The renamed copy is streamed
+// to a file beside it and moved into place, so a large .out is
+// never half written. Returns the number of lines changed.
+pub fn rename_file(path: &Path, names: &HashMap<&str, &str>) -> Result<usize> {
+    let target = RenameTarget::from_path(path).ok_or_else(|| {
+        Error::new(ErrorKind::GenericCli(format!(
+            "Can't rename families in {}: expected a .out, GFF or BLAST .tsv",
+            path.display()
+        )))
+    })?;
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".renaming");
+    let tmp = path.with_file_name(tmp_name);
+
+    let mut changed = 0;
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let new = target.rename(&line, names);
+        if new != line {
+            changed += 1;
+        }
+        writeln!(writer, "{}", new)?;
+    }
+    writer.flush()?;
+    drop(writer);
+    if changed > 0 {
+        fs::rename(&tmp, path)?;
+    } else {
+        fs::remove_file(&tmp)?;
+    }
+    Ok(changed)
+}
+
+// The RepeatMasker annotations and the BLAST tables of the
+// consensi in the configured directory. Reports made from them
+// (summaries, QC and the like) and RepeatModeler's own working
+// files are left alone.
+pub fn renameable_outputs(configure: &Path) -> Vec<PathBuf> {
+    let mut outputs = Vec::new();
+    for (dir, extensions) in [
+        (
+            configure.join(DATA).join("repeatmasker"),
+            ["out", "gff", "gff3"].as_slice(),
+        ),
+        (configure.join(INTERMEDIATE).join("blastout"), &["tsv"]),
+    ] {
+        for entry in WalkDir::new(dir)
+            .follow_links(true)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+        {
+            let renameable = entry
+                .path()
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| extensions.contains(&ext));
+            if renameable {
+                outputs.push(entry.into_path());
+            }
+        }
+    }
+    outputs
+}
+
+// The library given, or the classified consensi RepeatModeler wrote
+// in the configured directory.
+pub(crate) fn library_path(
+    library: Option<&PathBuf>,
+    configure: Option<&PathBuf>,
+) -> Result<PathBuf> {
+    match (library, configure) {
+        (Some(library), _) if library.exists() => Ok(library.clone()),
+        (Some(_), _) => Err(Error::new(ErrorKind::GenericCli(
+            "Library not found".into(),
+        ))),
+        (None, Some(configure)) => find_consensi(configure).ok_or_else(|| {
+            Error::new(ErrorKind::GenericCli(
+                "Could not find consensi.fa.classified in the configured directory".into(),
+            ))
+        }),
+        (None, None) => Err(Error::new(ErrorKind::GenericCli(
+            "Give a library, or a configured directory to find one in".into(),
+        ))),
+    }
+}
+
+pub fn rename_library(args: &LibraryRenameArgs) -> Result<Vec<(String, String)>> {
+    check_prefix(&args.prefix)?;
+    if let Some(other) = args
+        .outputs
+        .iter()
+        .find(|o| RenameTarget::from_path(o).is_none())
+    {
+        return Err(Error::new(ErrorKind::GenericCli(format!(
+            "Can't rename families in {}: expected a .out, GFF or BLAST .tsv",
+            other.display()
+        ))));
+    }
+    let library_path = library_path(args.library.as_ref(), args.configure.as_ref())?;
+    let mut library = TeLibrary::from_file(&library_path)?;
+    let mapping = library.rename(&args.prefix);
+
+    // RepeatModeler 2 writes <database>-families.fa
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| library_path.with_file_name(format!("{}-families.fa", args.prefix)));
+    library.to_file(&output)?;
+    let mapping_path = output.with_extension("rename.tsv");
+    write_mapping_tsv(&mapping, &library, File::create(&mapping_path)?)?;
+    eprintln!(
+        "Renamed {} of {} families to {}, mapping in {}",
+        mapping.len(),
+        library.len(),
+        output.display(),
+        mapping_path.display()
+    );
+
+    let names = mapping
+        .iter()
+        .map(|(old, new)| (old.as_str(), new.as_str()))
+        .collect::<HashMap<_, _>>();
+    let mut outputs = args.outputs.clone();
+    if let Some(configure) = &args.configure {
+        outputs.extend(renameable_outputs(configure));
+    }
+    for path in outputs.iter().filter(|p| **p != mapping_path) {
+        let changed = rename_file(path, &names)?;
+        if changed > 0 {
+            eprintln!(
+                "Renamed families on {} lines of {}",
+                changed,
+                path.display()
+            );
+        }
+    }
+
+    Ok(mapping)
+}
+
+// Convert a library between formats. Seed alignments that the
+// output format can't hold are written to <output>.seeds.stk, so
+// they stay alongside the consensi.
+pub fn convert_library(args: &LibraryConvertArgs) -> Result<TeLibrary> {
+    let from = args
+        .from
+        .unwrap_or_else(|| LibraryFormat::from_path(&args.input));
+    let to = args
+        .to
+        .unwrap_or_else(|| LibraryFormat::from_path(&args.output));
+    let library = TeLibrary::read_as(&args.input, from)?;
+    library.write_as(&args.output, to)?;
+
+    let seeded = library
+        .iter()
+        .filter(|f| f.seed.is_some())
+        .cloned()
+        .collect::<Vec<_>>();
+    if to != LibraryFormat::Stockholm && !seeded.is_empty() {
+        let seeds = args.output.with_extension("seeds.stk");
+        TeLibrary::new(seeded).write_as(&seeds, LibraryFormat::Stockholm)?;
+        eprintln!("Seed alignments written to {}", seeds.display());
+    }
+    eprintln!(
+        "Converted {} families to {}",
+        library.len(),
+        args.output.display()
+    );
+    Ok(library)
+}
+
+pub fn run_library(command: LibraryCommand) -> Result<()> {
+    match command {
+        LibraryCommand::Rename(args) => rename_library(&args).map(|_| ()),
+        LibraryCommand::Dedup(args) => run_dedup(&args),
+        LibraryCommand::Decontaminate(args) => run_decontam(&args),
+        LibraryCommand::Merge(args) => run_merge(&args),
+        LibraryCommand::Convert(args) => convert_library(&args).map(|_| ()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIBRARY: &str = "\
+>rnd-1_family-12#LTR/Gypsy ( Recon Family Size = 45, Final Multiple Alignment Size = 40 )
+ACGTACGTAC
+>rnd-5_family-1391#LINE/L1 ( RepeatScout Family Size = 26, Final Multiple Alignment Size = 20, Localized to 37 out of 38 contigs )
+ACGTACGTACGTACGTACGT
+>ltr-1_family-1#LTRx [ Type=LTR, Final Multiple Alignment Size = 4 ]
+ACGTA
+>rnd-1_family-3#Unknown
+ACGTACGTACGTACG
+>rnd-2_family-7
+ACG
+";
+
+    #[test]
+    fn test_parse_headers() {
+        let library = TeLibrary::from_reader(LIBRARY.as_bytes()).unwrap();
+        assert_eq!(library.len(), 5);
+
+        let gypsy = library.get("rnd-1_family-12").unwrap();
+        assert_eq!(gypsy.class, "LTR");
+        assert_eq!(gypsy.subclass.as_deref(), Some("Gypsy"));
+        assert_eq!(gypsy.builder(), Some("Recon"));
+        assert_eq!(gypsy.family_size(), Some(45));
+        assert_eq!(gypsy.alignment_size(), Some(40));
+        assert_eq!(
+            gypsy.description().as_deref(),
+            Some("( Recon Family Size = 45, Final Multiple Alignment Size = 40 )")
+        );
+        // built from metadata alone
+        let mut built = TeFamily::new("x", None, b"ACGT");
+        built.metadata = gypsy.metadata.clone();
+        assert_eq!(built.description(), gypsy.description());
+
+        let l1 = library.get("rnd-5_family-1391").unwrap();
+        assert_eq!(l1.builder(), Some("RepeatScout"));
+        assert_eq!(
+            l1.metadata.last().unwrap(),
+            &(
+                "Localized to 37 out of 38 contigs".to_string(),
+                String::new()
+            )
+        );
+
+        let ltr = library.get("ltr-1_family-1").unwrap();
+        assert_eq!(ltr.metadata("Type"), Some("LTR"));
+        assert_eq!(ltr.builder(), None);
+
+        // unclassified consensi are Unknown
+        let unclassified = library.get("rnd-2_family-7").unwrap();
+        assert!(unclassified.is_unknown());
+        assert_eq!(unclassified.id(), "rnd-2_family-7#Unknown");
+    }
+
+    #[test]
+    fn test_filters_and_round_trip() {
+        let library = TeLibrary::from_reader(LIBRARY.as_bytes()).unwrap();
+
+        let ltr = library.clone().filter_by_class("LTR");
+        assert_eq!(ltr.len(), 1);
+        assert_eq!(library.clone().filter_by_class("LINE/L1").len(), 1);
+        assert_eq!(library.clone().unknown_only().len(), 2);
+        let mid = library.clone().filter_by_length(5, Some(15));
+        assert_eq!(
+            mid.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(),
+            vec!["rnd-1_family-12", "ltr-1_family-1", "rnd-1_family-3"]
+        );
+
+        let mut out = Vec::new();
+        library.write_fasta(&mut out).unwrap();
+        let written = String::from_utf8(out).unwrap();
+        // classified headers are written back unchanged
+        assert!(written.starts_with(LIBRARY.lines().next().unwrap()));
+        assert!(written.contains(">rnd-2_family-7#Unknown\n"));
+        assert_eq!(
+            TeLibrary::from_reader(written.as_bytes()).unwrap(),
+            library.clone()
+        );
+    }
+
+    #[test]
+    fn test_embl_round_trip() {
+        let mut library = TeLibrary::from_reader(LIBRARY.as_bytes()).unwrap();
+        library.families[0].set_species(["Drosophila melanogaster"]);
+        let mut out = Vec::new();
+        library.write_embl(&mut out).unwrap();
+        let written = String::from_utf8(out).unwrap();
+        assert!(written.contains("CC        Type: LTR\nCC        SubType: Gypsy\n"));
+        assert!(written.contains("OS   Drosophila_melanogaster\n"));
+        assert_eq!(
+            TeLibrary::from_embl_reader(written.as_bytes()).unwrap(),
+            library
+        );
+    }
+
+    const STOCKHOLM: &str = "# STOCKHOLM 1.0
+#=GF ID    rnd-1_family-5#DNA/hAT
+#=GF DE    ( RepeatScout Family Size = 3, Final Multiple Alignment Size = 3 )
+#=GF SQ    3
+chr1:100-110   ACG.TA
+chr2:5-15      ACGATA
+
+chr1:100-110   CGT
+chr2:5-15      C-T
+chr3:40-50     ------
+chr3:40-50     GGT
+#=GC RF        xxx.xx
+#=GC RF        xxx
+//
+# STOCKHOLM 1.0
+#=GF ID    MIR
+#=GF CC    RepeatMasker Annotations:
+#=GF CC         Type: SINE
+#=GF CC         SubType: MIR
+#=GF CC         Species: Mammalia
+x   ACAGT
+#=GC RF   ACa.T
+//
+";
+
+    #[test]
+    fn test_stockholm_and_convert() {
+        let library = TeLibrary::from_stockholm_reader(STOCKHOLM.as_bytes()).unwrap();
+        assert_eq!(library.len(), 2);
+        // the consensus is called from the RF columns of the seed
+        let hat = library.get("rnd-1_family-5").unwrap();
+        assert_eq!(hat.classification(), "DNA/hAT");
+        assert_eq!(hat.sequence, b"ACGTACGT");
+        assert_eq!(hat.family_size(), Some(3));
+        let seed = hat.seed.as_ref().unwrap();
+        assert_eq!(seed.sequences.len(), 3);
+        assert_eq!(
+            seed.sequences[0],
+            ("chr1:100-110".into(), b"ACG.TACGT".to_vec())
+        );
+        // or taken from an RF line with bases
+        let mir = library.get("MIR").unwrap();
+        assert_eq!(mir.id(), "MIR#SINE/MIR");
+        assert_eq!(mir.species, vec!["Mammalia"]);
+        assert_eq!(mir.sequence, b"ACAT");
+
+        let mut out = Vec::new();
+        library.write_stockholm(&mut out).unwrap();
+        assert_eq!(
+            TeLibrary::from_stockholm_reader(out.as_slice()).unwrap(),
+            library
+        );
+        // the classification goes in the CC lines, as Dfam has it
+        let written = String::from_utf8(out).unwrap();
+        assert!(written.contains("#=GF ID    MIR\n"));
+        assert!(written.contains("#=GF CC         Type: SINE\n#=GF CC         SubType: MIR\n"));
+
+        // to FASTA, with the seeds kept beside it
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("families.stk");
+        fs::write(&input, STOCKHOLM).unwrap();
+        let output = dir.path().join("families.fa");
+        convert_library(&LibraryConvertArgs {
+            input: input.clone(),
+            output: output.clone(),
+            from: None,
+            to: None,
+        })
+        .unwrap();
+        let fasta = TeLibrary::from_file(&output).unwrap();
+        assert_eq!(fasta.get("rnd-1_family-5").unwrap().sequence, b"ACGTACGT");
+        let seeds = TeLibrary::read(dir.path().join("families.seeds.stk")).unwrap();
+        assert_eq!(seeds, library);
+
+        // and a consensus-only family makes it back from Stockholm
+        let stk = dir.path().join("families.sto");
+        convert_library(&LibraryConvertArgs {
+            input: output,
+            output: stk.clone(),
+            from: None,
+            to: Some(LibraryFormat::Stockholm),
+        })
+        .unwrap();
+        let consensi = TeLibrary::read(&stk).unwrap();
+        let mir = consensi.get("MIR").unwrap();
+        assert_eq!(mir.sequence, b"ACAT");
+        assert_eq!(mir.id(), "MIR#SINE/MIR");
+        // which is not a seed, so back to FASTA writes no seeds
+        assert!(mir.seed.is_none());
+        let fasta = dir.path().join("again.fa");
+        convert_library(&LibraryConvertArgs {
+            input: stk,
+            output: fasta,
+            from: None,
+            to: None,
+        })
+        .unwrap();
+        assert!(!dir.path().join("again.seeds.stk").exists());
+    }
+
+    #[test]
+    fn test_rename_library_and_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = dir.path().join("consensi.fa.classified");
+        fs::write(
+            &library,
+            ">rnd-1_family-1#LTR/Gypsy\nACGT\n>rnd-1_family-12#DNA\nACGT\n>dmel_rnd-2_family-3#LINE/L1\nACGT\n",
+        )
+        .unwrap();
+        let out = dir.path().join("genome.fa.out");
+        fs::write(
+            &out,
+            "  239   29.4  1.9  1.0  chr1  1  104  (1000) C  rnd-1_family-12  DNA  (0) 104  1  1\n",
+        )
+        .unwrap();
+        let blast = dir.path().join("blast.tsv");
+        fs::write(
+            &blast,
+            "# BLASTN 2.14.0+\nrnd-1_family-1#LTR/Gypsy\tchr1\t100.0\nrnd-1_family-12#DNA\trnd-1_family-1\t99.0\nrnd-1_family-12#DNA\trnd-1_family-1#LTR/Gypsy\t85.0\n",
+        )
+        .unwrap();
+        // only the RepeatMasker and BLAST outputs of a configured
+        // directory are renamed
+        let configure = dir.path().join("configured");
+        let blastout = configure.join(INTERMEDIATE).join("blastout");
+        fs::create_dir_all(&blastout).unwrap();
+        fs::write(
+            blastout.join("rnd-1_family-1.tsv"),
+            "rnd-1_family-1\tchr2\n",
+        )
+        .unwrap();
+        let results = configure.join(crate::RESULTS);
+        fs::create_dir_all(&results).unwrap();
+        fs::write(
+            results.join("genome.fa.summary.tsv"),
+            "rnd-1_family-1\t10\n",
+        )
+        .unwrap();
+
+        let args = LibraryRenameArgs {
+            library: Some(library.clone()),
+            configure: Some(configure.clone()),
+            prefix: "dmel".into(),
+            output: None,
+            outputs: vec![out.clone(), blast.clone()],
+        };
+        let mapping = rename_library(&args).unwrap();
+        // the already prefixed family is left alone
+        assert_eq!(mapping.len(), 2);
+
+        let renamed = TeLibrary::from_file(dir.path().join("dmel-families.fa")).unwrap();
+        assert_eq!(
+            renamed.iter().map(|f| f.id()).collect::<Vec<_>>(),
+            vec![
+                "dmel_rnd-1_family-1#LTR/Gypsy",
+                "dmel_rnd-1_family-12#DNA",
+                "dmel_rnd-2_family-3#LINE/L1"
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("dmel-families.rename.tsv")).unwrap(),
+            "old_name\tnew_name\tclassification\n\
+             rnd-1_family-1\tdmel_rnd-1_family-1\tLTR/Gypsy\n\
+             rnd-1_family-12\tdmel_rnd-1_family-12\tDNA\n"
+        );
+        assert!(fs::read_to_string(&out)
+            .unwrap()
+            .contains(" dmel_rnd-1_family-12  DNA "));
+        // subjects are renamed only if they are family IDs, as in
+        // an all-vs-all table of the library; a bare name is a
+        // sequence ID, whatever it looks like
+        assert_eq!(
+            fs::read_to_string(&blast).unwrap(),
+            "# BLASTN 2.14.0+\ndmel_rnd-1_family-1#LTR/Gypsy\tchr1\t100.0\ndmel_rnd-1_family-12#DNA\trnd-1_family-1\t99.0\ndmel_rnd-1_family-12#DNA\tdmel_rnd-1_family-1#LTR/Gypsy\t85.0\n"
+        );
+        assert_eq!(
+            fs::read_to_string(blastout.join("rnd-1_family-1.tsv")).unwrap(),
+            "dmel_rnd-1_family-1\tchr2\n"
+        );
+        assert_eq!(
+            fs::read_to_string(results.join("genome.fa.summary.tsv")).unwrap(),
+            "rnd-1_family-1\t10\n"
+        );
+        assert!(!dir.path().join("blast.tsv.renaming").exists());
+
+        // a second run changes nothing
+        let args = LibraryRenameArgs {
+            library: Some(dir.path().join("dmel-families.fa")),
+            ..args
+        };
+        assert!(rename_library(&args).unwrap().is_empty());
+        assert!(check_prefix("dm el").is_err());
+        let args = LibraryRenameArgs {
+            outputs: vec![dir.path().join("notes.md")],
+            ..args
+        };
+        assert!(rename_library(&args).is_err());
+    }
+}
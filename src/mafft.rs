@@ -0,0 +1,210 @@
+// Align the extracted copies of each family with MAFFT.
+//
+// MAFFT writes the alignment to stdout and its progress to stderr,
+// so we write the first to intermediate/aligned/<family>.fa and keep
+// the second as intermediate/mafft/<family>.log.
+
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use bio::io::fasta;
+
+use crate::{CommandRunner, Error, ErrorKind, Result};
+
+pub const MAFFT: &str = "mafft";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MafftStrategy {
+    // let MAFFT pick, based on the size of the input
+    Auto,
+    // E-INS-i, suited to sequences with long unalignable
+    // regions, such as copies with flanks
+    Einsi { max_iterate: u32 },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MafftOptions {
+    pub strategy: MafftStrategy,
+    // reverse complement copies in the wrong orientation. MAFFT
+    // prefixes their names with _R_
+    pub adjust_direction: bool,
+    pub threads: u8,
+}
+
+impl Default for MafftOptions {
+    fn default() -> Self {
+        Self {
+            strategy: MafftStrategy::Auto,
+            adjust_direction: false,
+            threads: 1,
+        }
+    }
+}
+
+// The number of sequences in a FASTA file.
+pub fn count_sequences(path: &Path) -> Result<usize> {
+    let mut n = 0;
+    for record in fasta::Reader::from_file(path)?.records() {
+        record?;
+        n += 1;
+    }
+    Ok(n)
+}
+
+pub fn mafft_command(input: &Path, options: MafftOptions) -> Command {
+    let mut mafft = Command::new(MAFFT);
+    match options.strategy {
+        MafftStrategy::Auto => {
+            mafft.arg("--auto");
+        }
+        MafftStrategy::Einsi { max_iterate } => {
+            mafft
+                .arg("--genafpair")
+                .arg("--maxiterate")
+                .arg(max_iterate.to_string());
+        }
+    }
+    if options.adjust_direction {
+        mafft.arg("--adjustdirection");
+    }
+    mafft
+        .arg("--thread")
+        .arg(options.threads.to_string())
+        .arg(input);
+    mafft
+}
+
+// Align `input` into `output`, writing MAFFT's log to `log`.
+// Returns false, without running MAFFT, if there is nothing to
+// align: a single sequence is not worth an alignment.
+pub fn align(
+    input: &Path,
+    output: &Path,
+    log: &Path,
+    options: MafftOptions,
+    runner: &dyn CommandRunner,
+) -> Result<bool> {
+    if count_sequences(input)? < 2 {
+        return Ok(false);
+    }
+
+    let out = runner.run(&mut mafft_command(input, options))?;
+    File::create(log)?.write_all(&out.stderr)?;
+    if !out.status.success() {
+        return Err(Error::new(ErrorKind::GenericCli(format!(
+            "MAFFT failed for {}. See {}",
+            input.display(),
+            log.display()
+        ))));
+    }
+    File::create(output)?.write_all(&out.stdout)?;
+
+    Ok(true)
+}
+
+// Align the copies in <intermediate>/blastn/<family>.fa of each
+// family, returning the paths of the alignments made.
+pub fn align_families(
+    stems: &[String],
+    intermediate: &Path,
+    options: MafftOptions,
+    runner: &dyn CommandRunner,
+) -> Result<Vec<PathBuf>> {
+    let aligned_dir = intermediate.join("aligned");
+    let log_dir = intermediate.join("mafft");
+    fs::create_dir_all(&aligned_dir)?;
+    fs::create_dir_all(&log_dir)?;
+
+    let mut aligned = Vec::new();
+    for stem in stems {
+        let input = intermediate.join("blastn").join(format!("{}.fa", stem));
+        let output = aligned_dir.join(format!("{}.fa", stem));
+        let log = log_dir.join(format!("{}.log", stem));
+        if align(&input, &output, &log, options, runner)? {
+            aligned.push(output);
+        } else {
+            eprintln!("Skipping alignment of {}: fewer than two copies", stem);
+        }
+    }
+    Ok(aligned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{ExitStatus, Output};
+
+    // Answers with the aligned fixture and the MAFFT log fixture.
+    #[derive(Default)]
+    struct MockMafft {
+        calls: RefCell<Vec<Vec<String>>>,
+    }
+
+    impl CommandRunner for MockMafft {
+        fn run(&self, cmd: &mut Command) -> Result<Output> {
+            let args = cmd
+                .get_args()
+                .map(|s| s.to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            self.calls.borrow_mut().push(args);
+            Ok(Output {
+                status: ExitStatus::from_raw(0),
+                stdout: fs::read("test/intermediate/aligned/query1.fa").unwrap(),
+                stderr: fs::read("test/intermediate/tempMafft.txt").unwrap(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_align_families_skips_single_copies() {
+        let tmp = tempfile::tempdir().unwrap();
+        let blastn = tmp.path().join("blastn");
+        fs::create_dir_all(&blastn).unwrap();
+        fs::write(blastn.join("one.fa"), ">chr1:1-4(+)\nACGT\n").unwrap();
+        fs::write(
+            blastn.join("two.fa"),
+            ">chr1:1-4(+)\nACGT\n>chr2:5-8(-)\nACGA\n",
+        )
+        .unwrap();
+
+        let options = MafftOptions {
+            strategy: MafftStrategy::Einsi { max_iterate: 1000 },
+            adjust_direction: true,
+            threads: 3,
+        };
+        let runner = MockMafft::default();
+        let aligned = align_families(
+            &["one".to_string(), "two".to_string()],
+            tmp.path(),
+            options,
+            &runner,
+        )
+        .unwrap();
+
+        assert_eq!(aligned, vec![tmp.path().join("aligned").join("two.fa")]);
+        let calls = runner.calls.borrow();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(
+            calls[0][..6],
+            [
+                "--genafpair",
+                "--maxiterate",
+                "1000",
+                "--adjustdirection",
+                "--thread",
+                "3"
+            ]
+        );
+
+        assert_eq!(count_sequences(&aligned[0]).unwrap(), 1);
+        let log = fs::read_to_string(tmp.path().join("mafft").join("two.log")).unwrap();
+        assert!(log.contains("makedirectionlist"));
+        assert!(!tmp.path().join("aligned").join("one.fa").exists());
+    }
+}
@@ -0,0 +1,215 @@
+// Mask the staged genome from a set of repeat annotations, so
+// the genome can be re-masked with a filtered annotation
+// without running RepeatMasker again.
+
+use std::{collections::HashMap, fs::File, io::BufWriter, path::Path};
+
+use bio::io::fasta;
+
+use crate::{
+    annotation::{read_annotations, Annotation, AnnotationFormat},
+    cli::MaskFastaArgs,
+    intervals::IntervalSet,
+    staged_genome_path, Error, ErrorKind, Result, RESULTS,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaskMode {
+    // lowercase the repeats
+    Soft,
+    // replace the repeats with N
+    Hard,
+    // replace the repeats with X
+    X,
+}
+
+impl MaskMode {
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            MaskMode::Soft => "softmasked",
+            MaskMode::Hard => "hardmasked",
+            MaskMode::X => "xmasked",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MaskOptions {
+    pub mode: MaskMode,
+    // skip Simple_repeat and Low_complexity annotations
+    pub exclude_simple: bool,
+    // skip annotations shorter than this
+    pub min_length: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MaskStats {
+    pub total_bases: u64,
+    pub masked_bases: u64,
+    pub annotations_used: usize,
+    pub annotations_skipped: usize,
+}
+
+// Apply the mask to a single sequence. In soft mode any existing
+// lowercase is removed first, so only the given intervals are masked.
+pub fn mask_sequence(seq: &mut [u8], intervals: &IntervalSet, mode: MaskMode) -> u64 {
+    if mode == MaskMode::Soft {
+        seq.make_ascii_uppercase();
+    }
+
+    let mut masked = 0;
+    for (start, end) in intervals.iter() {
+        let start = (start as usize).saturating_sub(1);
+        let end = (end as usize).min(seq.len());
+        if start >= end {
+            continue;
+        }
+        let region = &mut seq[start..end];
+        match mode {
+            MaskMode::Soft => region.make_ascii_lowercase(),
+            MaskMode::Hard => region.fill(b'N'),
+            MaskMode::X => region.fill(b'X'),
+        }
+        masked += region.len() as u64;
+    }
+    masked
+}
+
+// Mask every sequence in `genome` and write to `output`.
+pub fn mask_fasta(
+    genome: &Path,
+    annotations: &[Annotation],
+    options: MaskOptions,
+    output: &Path,
+) -> Result<MaskStats> {
+    let mut stats = MaskStats::default();
+
+    // merge the annotations we keep per sequence
+    let mut by_seq: HashMap<&str, IntervalSet> = HashMap::new();
+    for annotation in annotations {
+        if annotation.length() < options.min_length
+            || (options.exclude_simple && annotation.is_simple_or_low_complexity())
+        {
+            stats.annotations_skipped += 1;
+            continue;
+        }
+        stats.annotations_used += 1;
+        by_seq
+            .entry(&annotation.seqid)
+            .or_default()
+            .insert(annotation.start, annotation.end);
+    }
+
+    let reader = fasta::Reader::from_file(genome)?;
+    let mut writer = fasta::Writer::new(BufWriter::new(File::create(output)?));
+    for record in reader.records() {
+        let record = record?;
+        let mut seq = record.seq().to_vec();
+        stats.total_bases += seq.len() as u64;
+        let masked = match by_seq.get(record.id()) {
+            Some(intervals) => mask_sequence(&mut seq, intervals, options.mode),
+            None => mask_sequence(&mut seq, &IntervalSet::new(), options.mode),
+        };
+        stats.masked_bases += masked;
+        writer.write(record.id(), record.desc(), &seq)?;
+    }
+    writer.flush()?;
+
+    Ok(stats)
+}
+
+// Entry point for `rep mask-fasta`
+pub fn run_mask_fasta(args: MaskFastaArgs) -> Result<()> {
+    let genome = staged_genome_path(&args.configure, &args.fasta_file)?;
+    if !genome.exists() {
+        return Err(Error::new(ErrorKind::GenericCli(format!(
+            "Staged genome {} not found. Did you configure the file system?",
+            genome.display()
+        ))));
+    }
+
+    let format = match args.format {
+        Some(f) => f,
+        None => AnnotationFormat::from_path(&args.annotation).ok_or_else(|| {
+            Error::new(ErrorKind::GenericCli(
+                "Could not guess the annotation format; pass --format".into(),
+            ))
+        })?,
+    };
+    let annotations = read_annotations(&args.annotation, format)?;
+
+    let output = match args.output {
+        Some(o) => o,
+        None => {
+            let name = genome
+                .file_name()
+                .map(|f| f.to_string_lossy().to_string())
+                .unwrap_or_default();
+            args.configure
+                .join(RESULTS)
+                .join(format!("{}.{}.fa", name, args.options.mode.suffix()))
+        }
+    };
+
+    let stats = mask_fasta(&genome, &annotations, args.options, &output)?;
+    eprintln!(
+        "Masked {} of {} bp using {} annotations ({} skipped). Written to {}",
+        stats.masked_bases,
+        stats.total_bases,
+        stats.annotations_used,
+        stats.annotations_skipped,
+        output.display()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn annotation(start: u64, end: u64, repeat: &str, class: &str) -> Annotation {
+        Annotation {
+            seqid: "chr1".into(),
+            start,
+            end,
+            repeat: repeat.into(),
+            class_family: Some(class.into()),
+        }
+    }
+
+    #[test]
+    fn test_mask_fasta_modes_and_filters() {
+        let dir = tempfile::tempdir().unwrap();
+        let genome = dir.path().join("genome.fa");
+        std::fs::write(&genome, ">chr1 desc\nacgtACGTACGTACGT\n>chr2\nACGT\n").unwrap();
+
+        let annotations = vec![
+            annotation(3, 6, "L1MA1", "LINE/L1"),
+            annotation(5, 8, "L1MA1", "LINE/L1"),
+            annotation(10, 12, "(CA)n", "Simple_repeat"),
+            annotation(15, 15, "AluY", "SINE/Alu"),
+        ];
+        let output = dir.path().join("masked.fa");
+
+        let options = MaskOptions {
+            mode: MaskMode::Soft,
+            exclude_simple: true,
+            min_length: 2,
+        };
+        let stats = mask_fasta(&genome, &annotations, options, &output).unwrap();
+        assert_eq!(stats.masked_bases, 6);
+        assert_eq!(stats.annotations_skipped, 2);
+        let masked = std::fs::read_to_string(&output).unwrap();
+        assert_eq!(masked, ">chr1 desc\nACgtacgtACGTACGT\n>chr2\nACGT\n");
+
+        let options = MaskOptions {
+            mode: MaskMode::Hard,
+            exclude_simple: false,
+            min_length: 0,
+        };
+        mask_fasta(&genome, &annotations, options, &output).unwrap();
+        let masked = std::fs::read_to_string(&output).unwrap();
+        assert_eq!(masked, ">chr1 desc\nacNNNNNNANNNACNT\n>chr2\nACGT\n");
+    }
+}
@@ -0,0 +1,404 @@
+// Merging of a de novo library with curated ones (Dfam or RepBase,
+// as FASTA or EMBL) into the single library RepeatMasker is run
+// with.
+//
+// The curated libraries come first, in the order given, then the de
+// novo families. Every family is written with a RepeatMasker header.
+// A name already taken is a duplicate if the sequence is the same,
+// and is dropped; otherwise the later family is renamed name_2,
+// name_3, ... Optionally, de novo families that pass the 80-80-80
+// rule against a curated family are dropped as redundant.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    cli::LibraryMergeArgs,
+    curate::{blastn, build_blast_db, BlastOptions, DbType},
+    dedup::{similar_pairs, DedupOptions},
+    library::{library_path, TeFamily, TeLibrary},
+    CommandRunner, RealCommandRunner, Result, DATA,
+};
+
+// Where `rep library merge` puts the library, and where
+// `run_repeatmasker` looks for it first.
+pub const MERGED_LIBRARY: &str = "merged_library.fa";
+
+pub fn merged_library_path(configure: &Path) -> PathBuf {
+    configure
+        .join(DATA)
+        .join("repeatmasker")
+        .join(MERGED_LIBRARY)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeAction {
+    Added,
+    Renamed,
+    Duplicate,
+    Redundant,
+}
+
+impl MergeAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MergeAction::Added => "added",
+            MergeAction::Renamed => "renamed",
+            MergeAction::Duplicate => "duplicate",
+            MergeAction::Redundant => "redundant",
+        }
+    }
+}
+
+// What happened to one family of one of the libraries.
+#[derive(Debug, Clone)]
+pub struct MergeEntry {
+    pub source: String,
+    pub family: String,
+    // the family's name in the merged library, or, for duplicates
+    // and redundant families, the family they were dropped for
+    pub name: String,
+    pub action: MergeAction,
+    pub curated: bool,
+}
+
+// Merge the libraries, in order, resolving name collisions.
+pub fn merge_libraries(libraries: &[(String, TeLibrary, bool)]) -> (TeLibrary, Vec<MergeEntry>) {
+    let mut merged: Vec<TeFamily> = Vec::new();
+    let mut names: HashMap<String, usize> = HashMap::new();
+    let mut entries = Vec::new();
+
+    for (source, library, curated) in libraries {
+        for family in library.iter() {
+            let mut entry = MergeEntry {
+                source: source.clone(),
+                family: family.id(),
+                name: family.name.clone(),
+                action: MergeAction::Added,
+                curated: *curated,
+            };
+            if let Some(&i) = names.get(&family.name) {
+                if merged[i].sequence.eq_ignore_ascii_case(&family.sequence) {
+                    entry.action = MergeAction::Duplicate;
+                    entries.push(entry);
+                    continue;
+                }
+                let n = (2..)
+                    .find(|n| !names.contains_key(&format!("{}_{}", family.name, n)))
+                    .expect("a free name");
+                entry.name = format!("{}_{}", family.name, n);
+                entry.action = MergeAction::Renamed;
+            }
+            let mut family = family.clone();
+            family.name = entry.name.clone();
+            names.insert(family.name.clone(), merged.len());
+            merged.push(family);
+            entries.push(entry);
+        }
+    }
+    (TeLibrary::new(merged), entries)
+}
+
+// Drop the de novo families redundant with a curated one, by blastn
+// of the de novo families against the curated ones. The searches
+// are written next to `output`.
+pub fn remove_redundant(
+    merged: TeLibrary,
+    entries: &mut [MergeEntry],
+    output: &Path,
+    options: &DedupOptions,
+    runner: &dyn CommandRunner,
+) -> Result<TeLibrary> {
+    let curated_names = entries
+        .iter()
+        .filter(|e| e.curated && e.action != MergeAction::Duplicate)
+        .map(|e| e.name.as_str())
+        .collect::<HashSet<_>>();
+    let (curated, de_novo): (Vec<_>, Vec<_>) = merged
+        .families
+        .into_iter()
+        .partition(|f| curated_names.contains(f.name.as_str()));
+    let (curated, de_novo) = (TeLibrary::new(curated), TeLibrary::new(de_novo));
+    if curated.is_empty() || de_novo.is_empty() {
+        return Ok(TeLibrary::new(
+            curated
+                .families
+                .into_iter()
+                .chain(de_novo.families)
+                .collect(),
+        ));
+    }
+
+    let curated_fa = output.with_extension("curated.fa");
+    let de_novo_fa = output.with_extension("de_novo.fa");
+    curated.to_file(&curated_fa)?;
+    de_novo.to_file(&de_novo_fa)?;
+    build_blast_db(&curated_fa, DbType::Nucleotide, runner)?;
+    let table = blastn(
+        &curated_fa,
+        &de_novo_fa,
+        &output.with_extension("blastn.tsv"),
+        BlastOptions {
+            evalue: options.evalue,
+            threads: options.threads,
+        },
+        runner,
+    )?;
+
+    let lengths = curated
+        .iter()
+        .chain(de_novo.iter())
+        .map(|f| (f.name.as_str(), f.len()))
+        .collect::<HashMap<_, _>>();
+    let pairs = similar_pairs(&table, &lengths, options);
+    // the curated family each redundant de novo family matches
+    let mut redundant: HashMap<&str, &str> = HashMap::new();
+    for (a, b) in &pairs {
+        if !curated_names.contains(a.as_str()) && curated_names.contains(b.as_str()) {
+            redundant.entry(a.as_str()).or_insert(b.as_str());
+        }
+    }
+
+    for entry in entries.iter_mut().filter(|e| !e.curated) {
+        if let Some(curated) = redundant.get(entry.name.as_str()) {
+            entry.action = MergeAction::Redundant;
+            entry.name = curated.to_string();
+        }
+    }
+    let kept = de_novo
+        .families
+        .into_iter()
+        .filter(|f| !redundant.contains_key(f.name.as_str()));
+    Ok(TeLibrary::new(
+        curated.families.into_iter().chain(kept).collect(),
+    ))
+}
+
+pub fn write_merge_tsv<W: Write>(entries: &[MergeEntry], writer: W) -> Result<()> {
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_writer(writer);
+    wtr.write_record(["source", "family", "curated", "action", "name"])?;
+    for e in entries {
+        wtr.write_record([
+            e.source.as_str(),
+            &e.family,
+            if e.curated { "true" } else { "false" },
+            e.action.as_str(),
+            &e.name,
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+// Merge the curated libraries and the de novo library into `output`,
+// with what happened to each family in <output>.merge.tsv.
+pub fn merge(
+    de_novo: Option<&Path>,
+    curated: &[PathBuf],
+    output: &Path,
+    redundancy: Option<&DedupOptions>,
+    runner: &dyn CommandRunner,
+) -> Result<TeLibrary> {
+    let source = |p: &Path| {
+        p.file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string()
+    };
+    let mut libraries = Vec::new();
+    for path in curated {
+        libraries.push((source(path), TeLibrary::read(path)?, true));
+    }
+    if let Some(path) = de_novo {
+        libraries.push((source(path), TeLibrary::read(path)?, false));
+    }
+
+    let (mut merged, mut entries) = merge_libraries(&libraries);
+    if let Some(options) = redundancy {
+        merged = remove_redundant(merged, &mut entries, output, options, runner)?;
+    }
+
+    if let Some(dir) = output.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    merged.to_file(output)?;
+    write_merge_tsv(&entries, File::create(output.with_extension("merge.tsv"))?)?;
+
+    let count = |a: MergeAction| entries.iter().filter(|e| e.action == a).count();
+    eprintln!(
+        "Merged {} families into {}: {} renamed, {} duplicates and {} redundant de novo families dropped",
+        merged.len(),
+        output.display(),
+        count(MergeAction::Renamed),
+        count(MergeAction::Duplicate),
+        count(MergeAction::Redundant)
+    );
+    Ok(merged)
+}
+
+pub fn run_merge(args: &LibraryMergeArgs) -> Result<()> {
+    // the de novo library is optional when there is nothing to find
+    // it in
+    let de_novo = match (&args.library, &args.configure) {
+        (None, None) => None,
+        (library, configure) => Some(library_path(library.as_ref(), configure.as_ref())?),
+    };
+    let output = match (&args.output, &args.configure) {
+        (Some(output), _) => output.clone(),
+        (None, Some(configure)) => merged_library_path(configure),
+        (None, None) => PathBuf::from(MERGED_LIBRARY),
+    };
+    let redundancy = args.remove_redundant.then_some(&args.dedup);
+    merge(
+        de_novo.as_deref(),
+        &args.curated,
+        &output,
+        redundancy,
+        &RealCommandRunner,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::curate::{BLASTN, MAKEBLASTDB};
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{Command, ExitStatus, Output};
+
+    // the de novo rnd-1_family-2 matches the curated MIR
+    struct MockRedundant;
+
+    impl CommandRunner for MockRedundant {
+        fn run(&self, cmd: &mut Command) -> Result<Output> {
+            let args = cmd
+                .get_args()
+                .map(|s| s.to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            if cmd.get_program() == BLASTN {
+                let i = args.iter().position(|a| a == "-out").unwrap();
+                fs::write(
+                    &args[i + 1],
+                    "rnd-1_family-2#SINE\tMIR#SINE/MIR\t90.0\t100\t10\t0\t1\t100\t1\t100\t1e-30\t150\n",
+                )
+                .unwrap();
+            } else if cmd.get_program() == MAKEBLASTDB {
+                let i = args.iter().position(|a| a == "-out").unwrap();
+                fs::write(format!("{}.nsq", args[i + 1]), "fresh").unwrap();
+            }
+            Ok(Output {
+                status: ExitStatus::from_raw(0),
+                stdout: vec![],
+                stderr: vec![],
+            })
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = "A".repeat(100);
+        let c = "C".repeat(100);
+        let dfam = dir.path().join("dfam.fa");
+        fs::write(
+            &dfam,
+            format!(">MIR#SINE/MIR @Mammalia [S:40,60,65]\n{a}\n>L2#LINE/L2 @Mammalia\n{c}\n"),
+        )
+        .unwrap();
+        let repbase = dir.path().join("repbase.embl");
+        fs::write(
+            &repbase,
+            format!(
+                "ID   L2         repeat; DNA; ???; 100 BP.\n\
+                 XX\n\
+                 CC   RepeatMasker Annotations:\n\
+                 CC        Type: LINE\n\
+                 CC        SubType: L2\n\
+                 CC        Species: Mammalia, Eutheria\n\
+                 XX\n\
+                 SQ   Sequence 100 BP;\n\
+                 {}\n\
+                 //\n\
+                 ID   Charlie1; SV 1; linear; DNA; STD; UNC; 100 BP.\n\
+                 NM   Charlie1\n\
+                 KW   DNA/hAT-Charlie.\n\
+                 OS   Homo sapiens\n\
+                 SQ   Sequence 100 BP;\n\
+                 {}        60\n\
+                 {}        100\n\
+                 //\n",
+                c.to_lowercase(),
+                "g".repeat(60),
+                "g".repeat(40)
+            ),
+        )
+        .unwrap();
+        let de_novo = dir.path().join("consensi.fa.classified");
+        fs::write(
+            &de_novo,
+            format!(
+                ">rnd-1_family-1#LTR/Gypsy ( Recon Family Size = 45, Final Multiple Alignment Size = 40 )\n{}\n>rnd-1_family-2#SINE\n{a}\n>L2\n{}\n",
+                "T".repeat(100),
+                "ACGT".repeat(25)
+            ),
+        )
+        .unwrap();
+
+        let output = dir.path().join("merged.fa");
+        let curated = [dfam, repbase];
+        let merged = merge(Some(&de_novo), &curated, &output, None, &MockRedundant).unwrap();
+        assert_eq!(
+            merged
+                .iter()
+                .map(|f| f.to_record().to_string())
+                .collect::<Vec<_>>()[..2],
+            [
+                format!(">MIR#SINE/MIR @Mammalia [S:40,60,65]\n{a}\n"),
+                format!(">L2#LINE/L2 @Mammalia\n{c}\n"),
+            ]
+        );
+        let ids = merged.iter().map(|f| f.id()).collect::<Vec<_>>();
+        // the RepBase L2 is the Dfam one, the de novo L2 is not
+        assert_eq!(
+            ids,
+            vec![
+                "MIR#SINE/MIR",
+                "L2#LINE/L2",
+                "Charlie1#DNA/hAT-Charlie",
+                "rnd-1_family-1#LTR/Gypsy",
+                "rnd-1_family-2#SINE",
+                "L2_2#Unknown",
+            ]
+        );
+        assert_eq!(merged.families[2].species, vec!["Homo_sapiens"]);
+        assert_eq!(merged.families[2].len(), 100);
+
+        let report = fs::read_to_string(dir.path().join("merged.merge.tsv")).unwrap();
+        assert!(report.contains("repbase.embl\tL2#LINE/L2\ttrue\tduplicate\tL2\n"));
+        assert!(report.contains("consensi.fa.classified\tL2#Unknown\tfalse\trenamed\tL2_2\n"));
+
+        // a database left from an earlier merge is rebuilt
+        let nsq = dir.path().join("merged.curated.fa.nsq");
+        fs::write(&nsq, "stale").unwrap();
+        let options = DedupOptions::default();
+        let merged = merge(
+            Some(&de_novo),
+            &curated,
+            &output,
+            Some(&options),
+            &MockRedundant,
+        )
+        .unwrap();
+        assert_eq!(merged.len(), 5);
+        assert!(merged.get("rnd-1_family-2").is_none());
+        let report = fs::read_to_string(dir.path().join("merged.merge.tsv")).unwrap();
+        assert!(report.contains("\trnd-1_family-2#SINE\tfalse\tredundant\tMIR\n"));
+        assert_eq!(fs::read_to_string(&nsq).unwrap(), "fresh");
+    }
+}
@@ -0,0 +1,134 @@
+// Writing parsed records back out, for loading into notebooks
+// and dashboards: JSON Lines, and Arrow IPC (the Feather v2
+// file format), which pandas/polars/R arrow read directly.
+// The tab-delimited writers live with each table type.
+
+use std::{io::Write, sync::Arc};
+
+use arrow_array::{ArrayRef, BooleanArray, Float64Array, RecordBatch, StringArray, UInt64Array};
+use arrow_ipc::writer::FileWriter;
+use serde::Serialize;
+
+use crate::{parse_blast::BlastTable, parse_rmout::RmOutTable, Result};
+
+// Write one JSON object per line.
+pub fn write_jsonl<T: Serialize, W: Write>(records: &[T], mut writer: W) -> Result<()> {
+    for record in records {
+        serde_json::to_writer(&mut writer, record)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// Write a single record batch as an Arrow IPC file.
+pub fn write_arrow_ipc<W: Write>(batch: &RecordBatch, writer: W) -> Result<()> {
+    let mut wtr = FileWriter::try_new(writer, &batch.schema())?;
+    wtr.write(batch)?;
+    wtr.finish()?;
+    Ok(())
+}
+
+fn strings<'a, I: Iterator<Item = &'a str>>(values: I) -> ArrayRef {
+    Arc::new(StringArray::from_iter_values(values))
+}
+
+fn u64s<I: Iterator<Item = u64>>(values: I) -> ArrayRef {
+    Arc::new(UInt64Array::from_iter_values(values))
+}
+
+fn f64s<I: Iterator<Item = f64>>(values: I) -> ArrayRef {
+    Arc::new(Float64Array::from_iter_values(values))
+}
+
+// One column per BlastRecord field. qlen and slen are
+// nullable, as not every layout includes them.
+pub fn blast_record_batch(table: &BlastTable) -> Result<RecordBatch> {
+    let r = table.records();
+    let batch = RecordBatch::try_from_iter([
+        ("qseqid", strings(r.iter().map(|x| x.qseqid.as_str()))),
+        ("sseqid", strings(r.iter().map(|x| x.sseqid.as_str()))),
+        ("pident", f64s(r.iter().map(|x| x.pident))),
+        ("length", u64s(r.iter().map(|x| x.length))),
+        ("mismatch", u64s(r.iter().map(|x| x.mismatch))),
+        ("gapopen", u64s(r.iter().map(|x| x.gapopen))),
+        ("qstart", u64s(r.iter().map(|x| x.qstart))),
+        ("qend", u64s(r.iter().map(|x| x.qend))),
+        ("sstart", u64s(r.iter().map(|x| x.sstart))),
+        ("send", u64s(r.iter().map(|x| x.send))),
+        ("evalue", f64s(r.iter().map(|x| x.evalue))),
+        ("bitscore", f64s(r.iter().map(|x| x.bitscore))),
+        (
+            "qlen",
+            Arc::new(r.iter().map(|x| x.qlen).collect::<UInt64Array>()) as ArrayRef,
+        ),
+        (
+            "slen",
+            Arc::new(r.iter().map(|x| x.slen).collect::<UInt64Array>()) as ArrayRef,
+        ),
+    ])?;
+    Ok(batch)
+}
+
+// One column per RmOutRecord field.
+pub fn rmout_record_batch(table: &RmOutTable) -> Result<RecordBatch> {
+    let r = &table.0;
+    let batch = RecordBatch::try_from_iter([
+        ("score", u64s(r.iter().map(|x| x.score))),
+        ("perc_div", f64s(r.iter().map(|x| x.perc_div))),
+        ("perc_del", f64s(r.iter().map(|x| x.perc_del))),
+        ("perc_ins", f64s(r.iter().map(|x| x.perc_ins))),
+        ("query", strings(r.iter().map(|x| x.query.as_str()))),
+        ("query_start", u64s(r.iter().map(|x| x.query_start))),
+        ("query_end", u64s(r.iter().map(|x| x.query_end))),
+        ("query_left", u64s(r.iter().map(|x| x.query_left))),
+        ("strand", strings(r.iter().map(|x| x.strand.as_str()))),
+        ("repeat", strings(r.iter().map(|x| x.repeat.as_str()))),
+        (
+            "class_family",
+            strings(r.iter().map(|x| x.class_family.as_str())),
+        ),
+        ("repeat_start", u64s(r.iter().map(|x| x.repeat_start))),
+        ("repeat_end", u64s(r.iter().map(|x| x.repeat_end))),
+        ("repeat_left", u64s(r.iter().map(|x| x.repeat_left))),
+        ("id", u64s(r.iter().map(|x| x.id))),
+        (
+            "overlapping",
+            Arc::new(
+                r.iter()
+                    .map(|x| Some(x.overlapping))
+                    .collect::<BooleanArray>(),
+            ) as ArrayRef,
+        ),
+    ])?;
+    Ok(batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_ipc::reader::FileReader;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_arrow_ipc_round_trip() {
+        let table = BlastTable::from_file("test/intermediate/tempBlastOut.txt").unwrap();
+        let batch = blast_record_batch(&table).unwrap();
+
+        let mut buffer = Vec::new();
+        write_arrow_ipc(&batch, &mut buffer).unwrap();
+
+        let mut reader = FileReader::try_new(Cursor::new(buffer), None).unwrap();
+        let read = reader.next().unwrap().unwrap();
+        assert_eq!(read, batch);
+        assert_eq!(read.num_rows(), 2);
+        assert_eq!(read.column_by_name("qlen").unwrap().null_count(), 2);
+        let sstart = read
+            .column_by_name("sstart")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap();
+        assert_eq!(sstart.values(), &[19, 1]);
+    }
+}
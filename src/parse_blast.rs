@@ -1,15 +1,215 @@
-use std::path::PathBuf;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
 
-use crate::Result;
-use csv::ReaderBuilder;
+use bio::io::fasta;
+use serde::Serialize;
 
-// use the CSV reader builder to parse
-// blast outfmt 7, which includes headers
-// we want a struct to wrap the data
-// and implementations to parse
-// column headers are: qseqid sseqid pident length mismatch gapopen qstart qend sstart send evalue bitscore
+use crate::{
+    intervals::IntervalSet,
+    output::{blast_record_batch, write_arrow_ipc, write_jsonl},
+    parse_rmout::Strand,
+    Error, ErrorKind, Result,
+};
 
-#[derive(Clone)]
+// parse BLAST tabular output, either outfmt 6 (no comments)
+// or outfmt 7, which includes comment lines per query:
+//
+// # BLASTN 2.14.0+
+// # Query: query1
+// # Database: genome.fa
+// # Fields: query acc.ver, subject acc.ver, % identity, ...
+// # 1 hits found
+//
+// columns are mapped from the `# Fields:` line, so custom
+// layouts such as -outfmt "7 qseqid sseqid ... qlen slen"
+// work. Without a `# Fields:` line the default layout is assumed:
+// qseqid sseqid pident length mismatch gapopen qstart qend sstart send evalue bitscore
+
+// The columns we know how to read. Anything else is skipped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlastField {
+    Qseqid,
+    Sseqid,
+    Pident,
+    Length,
+    Mismatch,
+    Gapopen,
+    Qstart,
+    Qend,
+    Sstart,
+    Send,
+    Evalue,
+    Bitscore,
+    Qlen,
+    Slen,
+    Other(String),
+}
+
+impl BlastField {
+    // The fields every layout must include.
+    const REQUIRED: [BlastField; 12] = [
+        BlastField::Qseqid,
+        BlastField::Sseqid,
+        BlastField::Pident,
+        BlastField::Length,
+        BlastField::Mismatch,
+        BlastField::Gapopen,
+        BlastField::Qstart,
+        BlastField::Qend,
+        BlastField::Sstart,
+        BlastField::Send,
+        BlastField::Evalue,
+        BlastField::Bitscore,
+    ];
+
+    // Map either the description BLAST writes in the `# Fields:`
+    // line (e.g. `q. start`) or the -outfmt specifier (`qstart`).
+    pub fn from_name(name: &str) -> Self {
+        match name.trim() {
+            "query id" | "query acc." | "query acc.ver" | "qseqid" | "qacc" | "qaccver" => {
+                BlastField::Qseqid
+            }
+            "subject id" | "subject acc." | "subject acc.ver" | "sseqid" | "sacc" | "saccver" => {
+                BlastField::Sseqid
+            }
+            "% identity" | "pident" => BlastField::Pident,
+            "alignment length" | "length" => BlastField::Length,
+            "mismatches" | "mismatch" => BlastField::Mismatch,
+            "gap opens" | "gapopen" => BlastField::Gapopen,
+            "q. start" | "qstart" => BlastField::Qstart,
+            "q. end" | "qend" => BlastField::Qend,
+            "s. start" | "sstart" => BlastField::Sstart,
+            "s. end" | "send" => BlastField::Send,
+            "evalue" => BlastField::Evalue,
+            "bit score" | "bitscore" => BlastField::Bitscore,
+            "query length" | "qlen" => BlastField::Qlen,
+            "subject length" | "slen" => BlastField::Slen,
+            other => BlastField::Other(other.to_string()),
+        }
+    }
+
+    // The -outfmt specifier, used for error messages and writing.
+    pub fn specifier(&self) -> &str {
+        match self {
+            BlastField::Qseqid => "qseqid",
+            BlastField::Sseqid => "sseqid",
+            BlastField::Pident => "pident",
+            BlastField::Length => "length",
+            BlastField::Mismatch => "mismatch",
+            BlastField::Gapopen => "gapopen",
+            BlastField::Qstart => "qstart",
+            BlastField::Qend => "qend",
+            BlastField::Sstart => "sstart",
+            BlastField::Send => "send",
+            BlastField::Evalue => "evalue",
+            BlastField::Bitscore => "bitscore",
+            BlastField::Qlen => "qlen",
+            BlastField::Slen => "slen",
+            BlastField::Other(name) => name,
+        }
+    }
+}
+
+// The column index of each field we read.
+#[derive(Debug, Clone)]
+struct ColumnMap {
+    fields: Vec<BlastField>,
+    index: [usize; 12],
+    qlen: Option<usize>,
+    slen: Option<usize>,
+}
+
+impl ColumnMap {
+    fn new(fields: Vec<BlastField>, line_no: usize) -> Result<Self> {
+        let position = |f: &BlastField| fields.iter().position(|x| x == f);
+        let mut index = [0; 12];
+        for (i, field) in BlastField::REQUIRED.iter().enumerate() {
+            index[i] = position(field).ok_or_else(|| {
+                Error::new(ErrorKind::BlastRow(
+                    line_no,
+                    format!("# Fields: is missing the {} column", field.specifier()),
+                ))
+            })?;
+        }
+        Ok(ColumnMap {
+            qlen: position(&BlastField::Qlen),
+            slen: position(&BlastField::Slen),
+            fields,
+            index,
+        })
+    }
+}
+
+impl Default for ColumnMap {
+    fn default() -> Self {
+        ColumnMap::new(BlastField::REQUIRED.to_vec(), 0).expect("default layout is complete")
+    }
+}
+
+// The information in one outfmt 7 comment block.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BlastComment {
+    pub program: Option<String>,
+    pub query: Option<String>,
+    pub database: Option<String>,
+    pub fields: Vec<BlastField>,
+    pub hits: Option<usize>,
+}
+
+// C's %e always has a sign and at least two exponent digits,
+// where Rust writes e.g. 1e-5 and 1e5.
+fn c_exponent(formatted: String) -> String {
+    match formatted.split_once('e') {
+        Some((mantissa, exp)) => {
+            let (sign, digits) = match exp.strip_prefix('-') {
+                Some(d) => ('-', d),
+                None => ('+', exp),
+            };
+            format!("{}e{}{:0>2}", mantissa, sign, digits)
+        }
+        None => formatted,
+    }
+}
+
+// Format an e-value the way BLAST does in tabular output
+// (see CAlignFormatUtil::GetScoreString), so written tables
+// match the text BLAST produced.
+pub fn format_evalue(evalue: f64) -> String {
+    if evalue < 1.0e-180 {
+        "0.0".to_string()
+    } else if evalue < 1.0e-99 {
+        c_exponent(format!("{:.0e}", evalue))
+    } else if evalue < 0.0009 {
+        c_exponent(format!("{:.2e}", evalue))
+    } else if evalue < 0.1 {
+        format!("{:.3}", evalue)
+    } else if evalue < 1.0 {
+        format!("{:.2}", evalue)
+    } else if evalue < 10.0 {
+        format!("{:.1}", evalue)
+    } else {
+        format!("{:.0}", evalue)
+    }
+}
+
+// Format a bitscore the way BLAST does in tabular output.
+pub fn format_bitscore(bitscore: f64) -> String {
+    if bitscore > 99999.0 {
+        c_exponent(format!("{:.3e}", bitscore))
+    } else if bitscore > 99.9 {
+        format!("{:.0}", bitscore)
+    } else {
+        format!("{:.1}", bitscore)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct BlastRecord {
     pub qseqid: String,
     pub sseqid: String,
@@ -23,101 +223,1274 @@ pub struct BlastRecord {
     pub send: u64,
     pub evalue: f64,
     pub bitscore: f64,
+    // only present if the layout included them
+    pub qlen: Option<u64>,
+    pub slen: Option<u64>,
 }
 
 impl BlastRecord {
-    fn from_row(row: &csv::StringRecord) -> Result<Self> {
+    fn from_row(row: &[&str], columns: &ColumnMap, line_no: usize) -> Result<Self> {
+        if row.len() < columns.fields.len() {
+            return Err(Error::new(ErrorKind::BlastRow(
+                line_no,
+                format!(
+                    "expected {} columns, found {}",
+                    columns.fields.len(),
+                    row.len()
+                ),
+            )));
+        }
+
+        // parse the column at position `i` of the required fields
+        fn parse<T: std::str::FromStr>(
+            row: &[&str],
+            columns: &ColumnMap,
+            i: usize,
+            line_no: usize,
+        ) -> Result<T> {
+            let value = row[columns.index[i]].trim();
+            value.parse().map_err(|_| {
+                Error::new(ErrorKind::BlastRow(
+                    line_no,
+                    format!(
+                        "could not parse {} from '{}'",
+                        BlastField::REQUIRED[i].specifier(),
+                        value
+                    ),
+                ))
+            })
+        }
+        let optional = |i: Option<usize>, name: &str| -> Result<Option<u64>> {
+            i.map(|i| {
+                row[i].trim().parse().map_err(|_| {
+                    Error::new(ErrorKind::BlastRow(
+                        line_no,
+                        format!("could not parse {} from '{}'", name, row[i]),
+                    ))
+                })
+            })
+            .transpose()
+        };
+
         let r = BlastRecord {
-            // TODO: these unwraps should be fine... ideally they should
-            // be handled
-            qseqid: row.get(0).unwrap().to_string(),
-            sseqid: row.get(1).unwrap().to_string(),
-            pident: row.get(2).unwrap().parse()?,
-            length: row.get(3).unwrap().parse()?,
-            mismatch: row.get(4).unwrap().parse()?,
-            gapopen: row.get(5).unwrap().parse()?,
-            qstart: row.get(6).unwrap().parse()?,
-            qend: row.get(7).unwrap().parse()?,
-            sstart: row.get(8).unwrap().parse()?,
-            send: row.get(9).unwrap().parse()?,
-            evalue: row.get(10).unwrap().parse()?,
-            bitscore: row.get(11).unwrap().parse()?,
+            qseqid: row[columns.index[0]].to_string(),
+            sseqid: row[columns.index[1]].to_string(),
+            pident: parse(row, columns, 2, line_no)?,
+            length: parse(row, columns, 3, line_no)?,
+            mismatch: parse(row, columns, 4, line_no)?,
+            gapopen: parse(row, columns, 5, line_no)?,
+            qstart: parse(row, columns, 6, line_no)?,
+            qend: parse(row, columns, 7, line_no)?,
+            sstart: parse(row, columns, 8, line_no)?,
+            send: parse(row, columns, 9, line_no)?,
+            evalue: parse(row, columns, 10, line_no)?,
+            bitscore: parse(row, columns, 11, line_no)?,
+            qlen: optional(columns.qlen, "qlen")?,
+            slen: optional(columns.slen, "slen")?,
         };
 
         Ok(r)
     }
 
     pub fn from_file(path: PathBuf) -> Result<BlastTable> {
-        let mut rdr = ReaderBuilder::new()
-            .delimiter(b'\t')
-            .has_headers(false)
-            .from_path(path)?;
+        BlastTable::from_file(path)
+    }
+
+    // The row as BLAST would write it, with `qlen`/`slen`
+    // appended if `with_lengths` is set.
+    fn to_row(&self, with_lengths: bool) -> Vec<String> {
+        let mut row = vec![
+            self.qseqid.clone(),
+            self.sseqid.clone(),
+            format!("{:.3}", self.pident),
+            self.length.to_string(),
+            self.mismatch.to_string(),
+            self.gapopen.to_string(),
+            self.qstart.to_string(),
+            self.qend.to_string(),
+            self.sstart.to_string(),
+            self.send.to_string(),
+            format_evalue(self.evalue),
+            format_bitscore(self.bitscore),
+        ];
+        if with_lengths {
+            let or_empty = |v: Option<u64>| v.map(|v| v.to_string()).unwrap_or_default();
+            row.push(or_empty(self.qlen));
+            row.push(or_empty(self.slen));
+        }
+        row
+    }
+
+    // BLAST reports minus strand hits with sstart > send.
+    pub fn strand(&self) -> Strand {
+        if (self.sstart > self.send) == (self.qstart > self.qend) {
+            Strand::Forward
+        } else {
+            Strand::Reverse
+        }
+    }
+
+    // The subject coordinates as (min, max), whatever the strand.
+    pub fn subject_interval(&self) -> (u64, u64) {
+        (self.sstart.min(self.send), self.sstart.max(self.send))
+    }
+
+    // The query coordinates as (min, max), whatever the strand.
+    pub fn query_interval(&self) -> (u64, u64) {
+        (self.qstart.min(self.qend), self.qstart.max(self.qend))
+    }
+}
+
+// A set of HSPs of one query on one subject, collapsed
+// into a single locus by `merge_hits` or `chain_hits`.
+#[derive(Debug, Clone)]
+pub struct BlastLocus {
+    pub qseqid: String,
+    pub sseqid: String,
+    // the strand of the best scoring hit
+    pub strand: Strand,
+    // subject span, min to max
+    pub sstart: u64,
+    pub send: u64,
+    // query span, min to max
+    pub qstart: u64,
+    pub qend: u64,
+    pub hits: usize,
+    // bases of the query covered by the hits
+    pub query_covered: u64,
+    pub qlen: Option<u64>,
+    pub best_evalue: f64,
+    // summed over the hits
+    pub bitscore: f64,
+}
+
+impl BlastLocus {
+    fn from_hits(hits: &[&BlastRecord]) -> Self {
+        let best = hits
+            .iter()
+            .min_by(|a, b| cmp_f64(a.bitscore, b.bitscore, SortOrder::Descending))
+            .expect("a locus has at least one hit");
+
+        let mut query = IntervalSet::new();
+        for hit in hits {
+            let (s, e) = hit.query_interval();
+            query.insert(s, e);
+        }
+
+        BlastLocus {
+            qseqid: best.qseqid.clone(),
+            sseqid: best.sseqid.clone(),
+            strand: best.strand(),
+            sstart: hits
+                .iter()
+                .map(|h| h.subject_interval().0)
+                .min()
+                .unwrap_or(0),
+            send: hits
+                .iter()
+                .map(|h| h.subject_interval().1)
+                .max()
+                .unwrap_or(0),
+            qstart: hits.iter().map(|h| h.query_interval().0).min().unwrap_or(0),
+            qend: hits.iter().map(|h| h.query_interval().1).max().unwrap_or(0),
+            hits: hits.len(),
+            query_covered: query.covered(),
+            qlen: hits.iter().find_map(|h| h.qlen),
+            best_evalue: hits.iter().map(|h| h.evalue).fold(f64::INFINITY, f64::min),
+            bitscore: hits.iter().map(|h| h.bitscore).sum(),
+        }
+    }
+
+    // Fraction of the query covered, if the query length is known.
+    pub fn query_coverage(&self) -> Option<f64> {
+        self.qlen
+            .filter(|&l| l > 0)
+            .map(|l| self.query_covered as f64 / l as f64)
+    }
+
+    pub fn subject_length(&self) -> u64 {
+        self.send - self.sstart + 1
+    }
+}
+
+// A column to sort a BlastTable by, see `BlastTable::sort_by_keys`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Query,
+    Subject,
+    Evalue,
+    Bitscore,
+    Pident,
+    Length,
+    // the lower subject coordinate, whatever the strand
+    SubjectStart,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Ascending,
+    Descending,
+}
 
-        let mut records = Vec::new();
-        for result in rdr.records() {
-            let record = Self::from_row(&result?)?;
-            records.push(record);
+// Compare floats with a total order. NaN, e.g. from a malformed
+// row, always sorts last whichever the direction.
+fn cmp_f64(a: f64, b: f64, order: SortOrder) -> Ordering {
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => match order {
+            SortOrder::Ascending => a.total_cmp(&b),
+            SortOrder::Descending => b.total_cmp(&a),
+        },
+    }
+}
+
+impl SortKey {
+    fn compare(&self, a: &BlastRecord, b: &BlastRecord, order: SortOrder) -> Ordering {
+        let ordered = |o: Ordering| match order {
+            SortOrder::Ascending => o,
+            SortOrder::Descending => o.reverse(),
+        };
+        match self {
+            SortKey::Query => ordered(a.qseqid.cmp(&b.qseqid)),
+            SortKey::Subject => ordered(a.sseqid.cmp(&b.sseqid)),
+            SortKey::Evalue => cmp_f64(a.evalue, b.evalue, order),
+            SortKey::Bitscore => cmp_f64(a.bitscore, b.bitscore, order),
+            SortKey::Pident => cmp_f64(a.pident, b.pident, order),
+            SortKey::Length => ordered(a.length.cmp(&b.length)),
+            SortKey::SubjectStart => ordered(a.subject_interval().0.cmp(&b.subject_interval().0)),
+        }
+    }
+}
+
+// Best hits first: lowest e-value, then highest bitscore.
+const RANKING: [(SortKey, SortOrder); 2] = [
+    (SortKey::Evalue, SortOrder::Ascending),
+    (SortKey::Bitscore, SortOrder::Descending),
+];
+
+fn compare_by_keys(a: &BlastRecord, b: &BlastRecord, keys: &[(SortKey, SortOrder)]) -> Ordering {
+    keys.iter()
+        .map(|(key, order)| key.compare(a, b, *order))
+        .find(|o| o.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+// Positions of the records of each query, and of each query and
+// subject pair, so lookups don't rescan the whole table. Each ID is
+// stored once and the records refer to it by number.
+#[derive(Clone, Debug, Default)]
+struct BlastIndex {
+    // queries in order of first appearance
+    queries: Vec<String>,
+    query_ids: HashMap<String, usize>,
+    subject_ids: HashMap<String, usize>,
+    // by query number
+    by_query: Vec<Vec<usize>>,
+    by_pair: HashMap<(usize, usize), Vec<usize>>,
+}
+
+impl BlastIndex {
+    fn new(records: &[BlastRecord]) -> Self {
+        // the number of `id`, numbering it if it is new
+        fn intern(ids: &mut HashMap<String, usize>, id: &str) -> (usize, bool) {
+            match ids.get(id) {
+                Some(&n) => (n, false),
+                None => {
+                    let n = ids.len();
+                    ids.insert(id.to_string(), n);
+                    (n, true)
+                }
+            }
         }
 
-        Ok(BlastTable(records))
+        let mut index = BlastIndex::default();
+        for (i, record) in records.iter().enumerate() {
+            let (query, new) = intern(&mut index.query_ids, &record.qseqid);
+            if new {
+                index.queries.push(record.qseqid.clone());
+                index.by_query.push(Vec::new());
+            }
+            index.by_query[query].push(i);
+            let (subject, _) = intern(&mut index.subject_ids, &record.sseqid);
+            index.by_pair.entry((query, subject)).or_default().push(i);
+        }
+        index
     }
 }
 
-#[derive(Clone)]
-pub struct BlastTable(pub Vec<BlastRecord>);
+#[derive(Clone, Debug, Default)]
+pub struct BlastTable {
+    // private, so the index is always in step with the records
+    records: Vec<BlastRecord>,
+    // built on first lookup, and dropped when the records change
+    index: OnceLock<BlastIndex>,
+    // the outfmt 7 comment blocks, one per query
+    pub comments: Vec<BlastComment>,
+    // query lengths, from qlen columns or the query FASTA
+    pub query_lengths: HashMap<String, u64>,
+}
 
 impl BlastTable {
+    pub fn new(records: Vec<BlastRecord>) -> Self {
+        let mut table = BlastTable::default();
+        table.set_records(records);
+        table.collect_query_lengths();
+        table
+    }
+
+    pub fn records(&self) -> &[BlastRecord] {
+        &self.records
+    }
+
+    pub fn into_records(self) -> Vec<BlastRecord> {
+        self.records
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    // The queries in the table, in order of first appearance.
+    pub fn queries(&self) -> &[String] {
+        &self.index().queries
+    }
+
+    fn index(&self) -> &BlastIndex {
+        self.index.get_or_init(|| BlastIndex::new(&self.records))
+    }
+
+    fn set_records(&mut self, records: Vec<BlastRecord>) {
+        self.records = records;
+        self.reindex();
+    }
+
+    fn reindex(&mut self) {
+        self.index = OnceLock::new();
+    }
+
+    // a table with the same comments and lengths but different records
+    fn with_records(&self, records: Vec<BlastRecord>) -> Self {
+        BlastTable {
+            index: OnceLock::new(),
+            records,
+            comments: self.comments.clone(),
+            query_lengths: self.query_lengths.clone(),
+        }
+    }
+
+    // a table of the records at `positions`
+    fn select(&self, positions: &[usize]) -> Self {
+        self.with_records(positions.iter().map(|&i| self.records[i].clone()).collect())
+    }
+
+    // keep only the records matching `f`
+    fn retain<F: FnMut(&BlastRecord) -> bool>(mut self, f: F) -> Self {
+        self.records.retain(f);
+        self.reindex();
+        self
+    }
+
+    // pick up the lengths of any records with a qlen column
+    fn collect_query_lengths(&mut self) {
+        for record in &self.records {
+            if let Some(qlen) = record.qlen {
+                self.query_lengths
+                    .entry(record.qseqid.clone())
+                    .or_insert(qlen);
+            }
+        }
+    }
+
+    // Read the query lengths from the FASTA the queries came from,
+    // e.g. the RepeatModeler library. The ID is the first word of
+    // the header, as BLAST uses it.
+    pub fn add_query_lengths_from_fasta<P: AsRef<Path> + std::fmt::Debug>(
+        &mut self,
+        path: P,
+    ) -> Result<()> {
+        let reader = fasta::Reader::from_file(path)?;
+        for record in reader.records() {
+            let record = record?;
+            self.query_lengths
+                .insert(record.id().to_string(), record.seq().len() as u64);
+        }
+        Ok(())
+    }
+
+    // The length of the query of `record`, if known.
+    pub fn query_length(&self, record: &BlastRecord) -> Option<u64> {
+        record
+            .qlen
+            .or_else(|| self.query_lengths.get(&record.qseqid).copied())
+    }
+
+    // The fraction of its query that `record` covers, if the length is known.
+    pub fn query_coverage(&self, record: &BlastRecord) -> Option<f64> {
+        let (start, end) = record.query_interval();
+        self.query_length(record)
+            .filter(|&l| l > 0)
+            .map(|l| (end - start + 1) as f64 / l as f64)
+    }
+
+    // Keep hits covering at least `min_coverage` (0-1) of their query.
+    // Hits whose query length is unknown are dropped.
+    pub fn filter_by_query_coverage(mut self, min_coverage: f64) -> Self {
+        let records = std::mem::take(&mut self.records);
+        let records = records
+            .into_iter()
+            .filter(|r| self.query_coverage(r).is_some_and(|c| c >= min_coverage))
+            .collect();
+        self.set_records(records);
+        self
+    }
+
+    // Keep hits with at least `min_pident` percent identity.
+    pub fn filter_by_pident(self, min_pident: f64) -> Self {
+        self.retain(|r| r.pident >= min_pident)
+    }
+
+    // Keep hits with an alignment length of at least `min_length`.
+    pub fn filter_by_length(self, min_length: u64) -> Self {
+        self.retain(|r| r.length >= min_length)
+    }
+
+    // Keep hits with an e-value of at most `max_evalue`.
+    pub fn filter_by_evalue(self, max_evalue: f64) -> Self {
+        self.retain(|r| r.evalue <= max_evalue)
+    }
+
+    // Keep hits with a bitscore of at least `min_bitscore`.
+    pub fn filter_by_bitscore(self, min_bitscore: f64) -> Self {
+        self.retain(|r| r.bitscore >= min_bitscore)
+    }
+
+    // Write the table as tab-delimited text, in the default layout
+    // with `qlen` and `slen` appended if any record has them. With
+    // `header` set, a first line of column names is written, which
+    // `BlastTable::from_file` understands.
+    pub fn write_tsv<W: Write>(&self, writer: W, header: bool) -> Result<()> {
+        let with_lengths = self
+            .records
+            .iter()
+            .any(|r| r.qlen.is_some() || r.slen.is_some());
+
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .quote_style(csv::QuoteStyle::Never)
+            .from_writer(writer);
+        if header {
+            let mut fields: Vec<&str> =
+                BlastField::REQUIRED.iter().map(|f| f.specifier()).collect();
+            if with_lengths {
+                fields.extend(["qlen", "slen"]);
+            }
+            wtr.write_record(fields)?;
+        }
+        for record in &self.records {
+            wtr.write_record(record.to_row(with_lengths))?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    // Write one JSON object per record.
+    pub fn write_jsonl<W: Write>(&self, writer: W) -> Result<()> {
+        write_jsonl(&self.records, writer)
+    }
+
+    // Write the table as an Arrow IPC file.
+    pub fn write_arrow<W: Write>(&self, writer: W) -> Result<()> {
+        write_arrow_ipc(&blast_record_batch(self)?, writer)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Self> {
+        let mut reader = BlastReader::new(reader).keep_comments(true);
+        let records = reader.by_ref().collect::<Result<Vec<_>>>()?;
+        let mut table = BlastTable::new(records);
+        table.comments = reader.take_comments();
+        Ok(table)
+    }
+
+    // sort by the start, then end, of the hit on the subject
     pub fn sort_by_alignment_positions(&mut self) {
-        self.0
-            .sort_by(|a, b| a.sstart.partial_cmp(&b.sstart).unwrap());
+        self.records.sort_by_key(|r| r.subject_interval());
+        self.reindex();
     }
 
     pub fn filter_by_query_subject(&self, query: &str, subject: &str) -> Self {
+        let index = self.index();
+        let positions = index
+            .query_ids
+            .get(query)
+            .zip(index.subject_ids.get(subject))
+            .and_then(|(&q, &s)| index.by_pair.get(&(q, s)))
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        self.select(positions)
+    }
+
+    pub fn filter_by_query_name(&self, query: &str) -> Self {
+        let index = self.index();
+        let positions = index
+            .query_ids
+            .get(query)
+            .map(|&q| index.by_query[q].as_slice())
+            .unwrap_or_default();
+        self.select(positions)
+    }
+
+    // One sub-table per query, in order of first appearance.
+    pub fn group_by_query(&self) -> impl Iterator<Item = BlastTable> + '_ {
+        self.index()
+            .by_query
+            .iter()
+            .map(|positions| self.select(positions))
+    }
+
+    pub fn filter_by_strand(&self, strand: Strand) -> Self {
         let filtered: Vec<BlastRecord> = self
-            .0
+            .records
             .iter()
-            .filter(|x| x.qseqid == query && x.sseqid == subject)
+            .filter(|x| x.strand() == strand)
             .cloned()
             .collect();
-        BlastTable(filtered)
+        self.with_records(filtered)
     }
 
-    pub fn filter_by_query_name(&self, query: &str) -> Self {
+    // Hits on `subject` that overlap [start, end] in either orientation.
+    pub fn filter_by_subject_region(&self, subject: &str, start: u64, end: u64) -> Self {
         let filtered: Vec<BlastRecord> = self
-            .0
+            .records
             .iter()
-            .filter(|x| x.qseqid == query)
+            .filter(|x| {
+                let (s, e) = x.subject_interval();
+                x.sseqid == subject && s <= end && e >= start
+            })
             .cloned()
             .collect();
-        BlastTable(filtered)
+        self.with_records(filtered)
     }
 
+    // Lowest e-value first; NaN e-values sort last.
     pub fn sort_by_evalue(&mut self) {
-        // FIXME: sort out this unwrap
-        self.0
-            .sort_by(|a, b| a.evalue.partial_cmp(&b.evalue).unwrap());
+        self.sort_by_keys(&[(SortKey::Evalue, SortOrder::Ascending)]);
+    }
+
+    // Sort by each key in turn, e.g. e-value, then bitscore
+    // descending, then subject. The sort is stable, so records
+    // equal on every key keep their order.
+    pub fn sort_by_keys(&mut self, keys: &[(SortKey, SortOrder)]) {
+        self.records.sort_by(|a, b| compare_by_keys(a, b, keys));
+        self.reindex();
+    }
+
+    // The best `n` hits of each query (lowest e-value, then highest
+    // bitscore), grouped by query in order of first appearance.
+    // Unlike `top_n`, every query keeps its hits.
+    pub fn rank_per_query(&self, n: usize) -> Self {
+        let mut positions = Vec::new();
+        for query in &self.index().by_query {
+            let mut ranked = query.clone();
+            ranked.sort_by(|&a, &b| compare_by_keys(&self.records[a], &self.records[b], &RANKING));
+            positions.extend(ranked.into_iter().take(n));
+        }
+        self.select(&positions)
     }
 
     pub fn sort_by_sequence_start(&mut self) {
-        self.0
-            .sort_by(|a, b| a.sstart.partial_cmp(&b.sstart).unwrap());
+        self.records.sort_by_key(|r| r.subject_interval().0);
+        self.reindex();
+    }
+
+    pub fn top_n(mut self, n: usize) -> Self {
+        self.records.truncate(n);
+        self.reindex();
+        self
+    }
+
+    fn locus(&self, hits: &[&BlastRecord]) -> BlastLocus {
+        let mut locus = BlastLocus::from_hits(hits);
+        if locus.qlen.is_none() {
+            locus.qlen = self.query_lengths.get(&locus.qseqid).copied();
+        }
+        locus
+    }
+
+    // group the hits by query, subject and (optionally) strand,
+    // each group sorted by position on the subject
+    fn group_for_loci(&self, by_strand: bool) -> Vec<Vec<&BlastRecord>> {
+        let mut groups: BTreeMap<(&str, &str, Option<Strand>), Vec<&BlastRecord>> = BTreeMap::new();
+        for record in &self.records {
+            let strand = by_strand.then(|| record.strand());
+            groups
+                .entry((&record.qseqid, &record.sseqid, strand))
+                .or_default()
+                .push(record);
+        }
+        groups
+            .into_values()
+            .map(|mut hits| {
+                hits.sort_by_key(|h| h.subject_interval());
+                hits
+            })
+            .collect()
+    }
+
+    // Collapse hits of the same query on the same subject that
+    // overlap, or are within `max_gap` bases of each other on the
+    // subject, into loci. If `same_strand` is set, hits on opposite
+    // strands are never merged.
+    pub fn merge_hits(&self, max_gap: u64, same_strand: bool) -> Vec<BlastLocus> {
+        let mut loci = Vec::new();
+        for hits in self.group_for_loci(same_strand) {
+            let mut current: Vec<&BlastRecord> = Vec::new();
+            let mut current_end: u64 = 0;
+            for hit in hits {
+                let (start, end) = hit.subject_interval();
+                if !current.is_empty() && start > current_end.saturating_add(max_gap) {
+                    loci.push(self.locus(&current));
+                    current.clear();
+                }
+                current_end = if current.is_empty() {
+                    end
+                } else {
+                    current_end.max(end)
+                };
+                current.push(hit);
+            }
+            if !current.is_empty() {
+                loci.push(self.locus(&current));
+            }
+        }
+        loci
     }
 
-    pub fn top_n(self, n: usize) -> Self {
-        BlastTable(self.0.into_iter().take(n).collect())
+    // Chain hits into loci only when they are colinear: on the same
+    // strand, in the same order on query and subject (reversed on the
+    // minus strand), with gaps of at most `max_gap` and overlaps of at
+    // most `max_overlap` on both sequences. This keeps e.g. two
+    // tandem copies of a TE apart, where `merge_hits` would join them.
+    pub fn chain_hits(&self, max_gap: u64, max_overlap: u64) -> Vec<BlastLocus> {
+        // the gap from `a` to `b`, negative if they overlap
+        fn gap(a_end: u64, b_start: u64) -> i64 {
+            b_start as i64 - a_end as i64 - 1
+        }
+        let fits = |g: i64| g >= -(max_overlap as i64) && g <= max_gap as i64;
+
+        let mut loci = Vec::new();
+        for hits in self.group_for_loci(true) {
+            let mut chains: Vec<Vec<&BlastRecord>> = Vec::new();
+            for hit in hits {
+                let (s_start, _) = hit.subject_interval();
+                let (q_start, q_end) = hit.query_interval();
+
+                // extend the best scoring chain this hit follows on from
+                let chain = chains
+                    .iter_mut()
+                    .filter(|chain| {
+                        let last = chain.last().expect("chains are never empty");
+                        let (_, last_s_end) = last.subject_interval();
+                        let (last_q_start, last_q_end) = last.query_interval();
+                        let query_gap = match hit.strand() {
+                            Strand::Forward => gap(last_q_end, q_start),
+                            Strand::Reverse => gap(q_end, last_q_start),
+                        };
+                        fits(gap(last_s_end, s_start)) && fits(query_gap)
+                    })
+                    .max_by(|a, b| {
+                        let score =
+                            |c: &Vec<&BlastRecord>| c.iter().map(|h| h.bitscore).sum::<f64>();
+                        score(a).total_cmp(&score(b))
+                    });
+
+                match chain {
+                    Some(chain) => chain.push(hit),
+                    None => chains.push(vec![hit]),
+                }
+            }
+            loci.extend(chains.iter().map(|c| self.locus(c)));
+        }
+        loci
     }
 
-    // Extract unique combinations of query and subject
+    // Extract unique combinations of query and subject,
+    // keeping the first hit of each
     pub fn filter_unique_combinations(self) -> Self {
-        let mut unique: Vec<BlastRecord> = Vec::new();
-        for record in self.0 {
-            if !unique
-                .iter()
-                .any(|x| x.qseqid == record.qseqid && x.sseqid == record.sseqid)
-            {
-                unique.push(record);
+        let mut positions: Vec<usize> = self.index().by_pair.values().map(|p| p[0]).collect();
+        positions.sort_unstable();
+        self.select(&positions)
+    }
+
+    // Extract unique combinations of query and subject, keeping
+    // the highest scoring hit of each, in their original order
+    pub fn best_hit_per_query_subject(self) -> Self {
+        let mut positions: Vec<usize> = self
+            .index()
+            .by_pair
+            .values()
+            .map(|p| {
+                *p.iter()
+                    .min_by(|&&a, &&b| {
+                        cmp_f64(
+                            self.records[a].bitscore,
+                            self.records[b].bitscore,
+                            SortOrder::Descending,
+                        )
+                        // prefer the earlier hit on ties
+                        .then(a.cmp(&b))
+                    })
+                    .expect("index entries are never empty")
+            })
+            .collect();
+        positions.sort_unstable();
+        self.select(&positions)
+    }
+}
+
+// The programs whose name starts each comment block of -outfmt 7.
+const BLAST_PROGRAMS: [&str; 5] = ["BLASTN", "BLASTP", "BLASTX", "TBLASTN", "TBLASTX"];
+
+// Read BLAST tabular output one record at a time, so very
+// large hit tables can be processed in bounded memory.
+pub struct BlastReader<R: BufRead> {
+    lines: std::io::Lines<R>,
+    line_no: usize,
+    columns: ColumnMap,
+    // the comment block we are currently in
+    current: Option<BlastComment>,
+    // completed comment blocks, if we are keeping them
+    comments: Vec<BlastComment>,
+    keep_comments: bool,
+}
+
+impl BlastReader<BufReader<File>> {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<R: BufRead> BlastReader<R> {
+    pub fn new(reader: R) -> Self {
+        BlastReader {
+            lines: reader.lines(),
+            line_no: 0,
+            columns: ColumnMap::default(),
+            current: None,
+            comments: Vec::new(),
+            keep_comments: false,
+        }
+    }
+
+    // Keep every comment block, rather than only the current one.
+    pub fn keep_comments(mut self, yes: bool) -> Self {
+        self.keep_comments = yes;
+        self
+    }
+
+    // The comment block of the query currently being read.
+    pub fn comment(&self) -> Option<&BlastComment> {
+        self.current.as_ref()
+    }
+
+    // All comment blocks read so far, if `keep_comments` was set.
+    pub fn take_comments(&mut self) -> Vec<BlastComment> {
+        if let Some(current) = self.current.take() {
+            if self.keep_comments {
+                self.comments.push(current);
+            }
+        }
+        std::mem::take(&mut self.comments)
+    }
+
+    // Group consecutive records by query. BLAST writes all the hits
+    // of a query together, so only one query is held at a time. An
+    // error is returned if a query turns up again later in the file.
+    pub fn by_query(self) -> QueryGroups<R> {
+        QueryGroups {
+            reader: self,
+            pending: None,
+            seen: std::collections::HashSet::new(),
+        }
+    }
+
+    fn read_comment(&mut self, comment: &str) -> Result<()> {
+        // each block starts with the program line, e.g. "TBLASTN 2.14.0+"
+        let new_block = comment
+            .split_whitespace()
+            .next()
+            .is_some_and(|program| BLAST_PROGRAMS.contains(&program));
+        if new_block || self.current.is_none() {
+            if let Some(done) = self.current.take() {
+                if self.keep_comments {
+                    self.comments.push(done);
+                }
+            }
+            self.current = Some(BlastComment::default());
+        }
+        let block = self.current.as_mut().expect("set above");
+
+        if let Some(query) = comment.strip_prefix("Query:") {
+            block.query = Some(query.trim().to_string());
+        } else if let Some(db) = comment.strip_prefix("Database:") {
+            block.database = Some(db.trim().to_string());
+        } else if let Some(fields) = comment.strip_prefix("Fields:") {
+            block.fields = fields.split(',').map(BlastField::from_name).collect();
+            self.columns = ColumnMap::new(block.fields.clone(), self.line_no)?;
+        } else if let Some(hits) = comment.strip_suffix("hits found") {
+            block.hits = hits.trim().parse().ok();
+        } else if new_block {
+            block.program = Some(comment.to_string());
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Iterator for BlastReader<R> {
+    type Item = Result<BlastRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = self.lines.next()?;
+            self.line_no += 1;
+            let line = match line {
+                Ok(l) => l,
+                Err(e) => return Some(Err(e.into())),
+            };
+
+            if let Some(comment) = line.strip_prefix('#') {
+                if let Err(e) = self.read_comment(comment.trim()) {
+                    return Some(Err(e));
+                }
+                continue;
+            }
+            if line.trim().is_empty() {
+                continue;
             }
+
+            let row: Vec<&str> = line.split('\t').collect();
+            // a header line, as written by `BlastTable::write_tsv`
+            if row[0] == "qseqid" {
+                let fields = row.iter().map(|f| BlastField::from_name(f)).collect();
+                match ColumnMap::new(fields, self.line_no) {
+                    Ok(columns) => self.columns = columns,
+                    Err(e) => return Some(Err(e)),
+                }
+                continue;
+            }
+            return Some(BlastRecord::from_row(&row, &self.columns, self.line_no));
+        }
+    }
+}
+
+// Iterator over the hits of one query at a time, see `BlastReader::by_query`.
+pub struct QueryGroups<R: BufRead> {
+    reader: BlastReader<R>,
+    // the first record of the next query
+    pending: Option<BlastRecord>,
+    seen: std::collections::HashSet<String>,
+}
+
+impl<R: BufRead> Iterator for QueryGroups<R> {
+    type Item = Result<BlastTable>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = match self.pending.take() {
+            Some(r) => r,
+            None => match self.reader.next()? {
+                Ok(r) => r,
+                Err(e) => return Some(Err(e)),
+            },
+        };
+
+        if !self.seen.insert(first.qseqid.clone()) {
+            return Some(Err(Error::new(ErrorKind::BlastRow(
+                self.reader.line_no,
+                format!(
+                    "hits for {} are not contiguous; is the output sorted by query?",
+                    first.qseqid
+                ),
+            ))));
+        }
+
+        let mut records = vec![first];
+        for record in self.reader.by_ref() {
+            match record {
+                Ok(r) if r.qseqid == records[0].qseqid => records.push(r),
+                Ok(r) => {
+                    self.pending = Some(r);
+                    break;
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        Some(Ok(BlastTable::new(records)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_outfmt_6() {
+        let table = BlastRecord::from_file("test/intermediate/tempBlastOut.txt".into()).unwrap();
+        assert_eq!(table.len(), 2);
+        assert!(table.comments.is_empty());
+        let first = &table.records()[0];
+        assert_eq!(first.qseqid, "query1");
+        assert_eq!((first.sstart, first.send), (19, 47));
+        assert_eq!(first.evalue, 4.60e-14);
+        assert_eq!(first.qlen, None);
+    }
+
+    #[test]
+    fn test_parse_outfmt_7_custom_fields() {
+        let out = "# BLASTN 2.14.0+
+# Query: query1
+# Database: genome.fa
+# Fields: query acc.ver, subject acc.ver, % identity, alignment length, mismatches, gap opens, q. start, q. end, s. start, s. end, evalue, bit score, query length, subject length
+# 1 hits found
+query1\tchr1\t100.000\t29\t0\t0\t1\t29\t47\t19\t4.60e-14\t54.7\t29\t60
+# BLASTN 2.14.0+
+# Query: query3
+# Database: genome.fa
+# 0 hits found
+";
+        let table = BlastTable::from_reader(out.as_bytes()).unwrap();
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.records()[0].qlen, Some(29));
+        assert_eq!(table.records()[0].slen, Some(60));
+        assert_eq!(table.comments.len(), 2);
+        assert_eq!(table.comments[0].database.as_deref(), Some("genome.fa"));
+        assert_eq!(table.comments[0].fields.len(), 14);
+        assert_eq!(table.comments[1].query.as_deref(), Some("query3"));
+        assert_eq!(table.comments[1].hits, Some(0));
+
+        // reordered columns are mapped by the header
+        let out = "# Fields: subject id, query id, evalue, bit score, % identity, alignment length, mismatches, gap opens, q. start, q. end, s. start, s. end
+chr1\tquery1\t1e-10\t50.0\t99.0\t29\t0\t0\t1\t29\t19\t47
+";
+        let table = BlastTable::from_reader(out.as_bytes()).unwrap();
+        assert_eq!(table.records()[0].qseqid, "query1");
+        assert_eq!(table.records()[0].bitscore, 50.0);
+    }
+
+    #[test]
+    fn test_short_row_errors_with_line_number() {
+        let out = "query1\tchr1\t100.000\t29\t0\t0\t1\t29\t19\t47\t4.60e-14\t54.7\nquery2\tchr1\t100.000\n";
+        let err = BlastTable::from_reader(out.as_bytes()).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::BlastRow(2, _)));
+    }
+
+    #[test]
+    fn test_minus_strand_intervals() {
+        let out = "query1\tchr1\t100\t29\t0\t0\t1\t29\t47\t19\t1e-5\t54.7
+query1\tchr1\t100\t10\t0\t0\t1\t10\t30\t39\t1e-5\t20.0
+";
+        let mut table = BlastTable::from_reader(out.as_bytes()).unwrap();
+        let minus = &table.records()[0];
+        assert_eq!(minus.strand(), Strand::Reverse);
+        assert_eq!(minus.subject_interval(), (19, 47));
+        assert_eq!(minus.query_interval(), (1, 29));
+        assert_eq!(table.records()[1].strand(), Strand::Forward);
+
+        // the minus strand hit starts first on the subject
+        table = BlastTable::new(table.into_records().into_iter().rev().collect());
+        table.sort_by_sequence_start();
+        assert_eq!(table.records()[0].sstart, 47);
+        table = BlastTable::new(table.into_records().into_iter().rev().collect());
+        table.sort_by_alignment_positions();
+        assert_eq!(table.records()[0].sstart, 47);
+
+        assert_eq!(table.filter_by_strand(Strand::Reverse).len(), 1);
+        // only the minus strand hit reaches position 20
+        assert_eq!(table.filter_by_subject_region("chr1", 15, 20).len(), 1);
+        assert_eq!(table.filter_by_subject_region("chr1", 35, 50).len(), 2);
+    }
+
+    fn hit(sseqid: &str, q: (u64, u64), s: (u64, u64), bitscore: f64) -> BlastRecord {
+        BlastRecord {
+            qseqid: "family1".into(),
+            sseqid: sseqid.into(),
+            pident: 90.0,
+            length: q.1 - q.0 + 1,
+            mismatch: 0,
+            gapopen: 0,
+            qstart: q.0,
+            qend: q.1,
+            sstart: s.0,
+            send: s.1,
+            evalue: 1e-10 / bitscore,
+            bitscore,
+            qlen: Some(1000),
+            slen: None,
         }
-        BlastTable(unique)
+    }
+
+    #[test]
+    fn test_merge_and_chain_hits() {
+        let table = BlastTable::new(vec![
+            // one copy on the plus strand, split in two HSPs
+            hit("chr1", (1, 400), (1001, 1400), 500.0),
+            hit("chr1", (421, 1000), (1451, 2030), 700.0),
+            // a tandem copy straight after it
+            hit("chr1", (1, 500), (2051, 2550), 600.0),
+            // a minus strand copy elsewhere, in two HSPs
+            hit("chr1", (501, 1000), (5500, 5001), 600.0),
+            hit("chr1", (1, 480), (6000, 5521), 550.0),
+            hit("chr2", (1, 100), (1, 100), 100.0),
+        ]);
+
+        let merged = table.merge_hits(100, true);
+        // the tandem copy is merged with the first
+        assert_eq!(merged.len(), 3);
+        let first = merged.iter().find(|l| l.sstart == 1001).unwrap();
+        assert_eq!((first.send, first.hits), (2550, 3));
+
+        let chained = table.chain_hits(100, 20);
+        assert_eq!(chained.len(), 4);
+        let first = chained.iter().find(|l| l.sstart == 1001).unwrap();
+        assert_eq!(first.send, 2030);
+        assert_eq!(first.query_covered, 980);
+        assert_eq!(first.query_coverage(), Some(0.98));
+        assert_eq!(first.best_evalue, 1e-10 / 700.0);
+        assert_eq!(first.bitscore, 1200.0);
+
+        let minus = chained.iter().find(|l| l.sstart == 5001).unwrap();
+        assert_eq!(minus.strand, Strand::Reverse);
+        assert_eq!((minus.send, minus.qstart, minus.qend), (6000, 1, 1000));
+        assert_eq!(minus.hits, 2);
+    }
+
+    #[test]
+    fn test_filter_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = dir.path().join("library.fa");
+        std::fs::write(&library, format!(">query1 desc\n{}\n", "A".repeat(30))).unwrap();
+
+        let mut table =
+            BlastRecord::from_file("test/intermediate/tempBlastOut.txt".into()).unwrap();
+        assert_eq!(table.query_coverage(&table.records()[0]), None);
+        table.add_query_lengths_from_fasta(&library).unwrap();
+        assert_eq!(table.query_length(&table.records()[0]), Some(30));
+
+        // query2 has no known length, so it can't pass a coverage filter
+        let covered = table.clone().filter_by_query_coverage(0.8);
+        assert_eq!(covered.len(), 1);
+        assert_eq!(covered.records()[0].qseqid, "query1");
+
+        let filtered = table
+            .clone()
+            .filter_by_pident(99.0)
+            .filter_by_length(30)
+            .filter_by_evalue(1e-10)
+            .filter_by_bitscore(60.0);
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered.records()[0].qseqid, "query2");
+        assert!(table.filter_by_bitscore(100.0).is_empty());
+    }
+
+    #[test]
+    fn test_index_and_unique_pairs() {
+        let table = BlastTable::new(vec![
+            hit("chr1", (1, 100), (1, 100), 50.0),
+            hit("chr2", (1, 100), (1, 100), 80.0),
+            hit("chr1", (1, 100), (501, 600), 90.0),
+        ]);
+        assert_eq!(table.filter_by_query_subject("family1", "chr1").len(), 2);
+        assert_eq!(table.filter_by_query_name("family1").len(), 3);
+        assert!(table.filter_by_query_name("family2").is_empty());
+
+        let first = table.clone().filter_unique_combinations();
+        assert_eq!(first.len(), 2);
+        assert_eq!(first.records()[0].bitscore, 50.0);
+
+        let best = table.clone().best_hit_per_query_subject();
+        assert_eq!(best.len(), 2);
+        assert_eq!(best.records()[0].bitscore, 80.0);
+        assert_eq!(best.records()[1].bitscore, 90.0);
+
+        let mut records = table.into_records();
+        records[1].qseqid = "family2".into();
+        let table = BlastTable::new(records);
+        let groups: Vec<BlastTable> = table.group_by_query().collect();
+        assert_eq!(table.queries(), ["family1", "family2"]);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].len(), 2);
+        assert_eq!(groups[1].records()[0].sseqid, "chr2");
+    }
+
+    #[test]
+    fn test_tsv_round_trip() {
+        let path = "test/intermediate/tempBlastOut.txt";
+        let original = std::fs::read_to_string(path).unwrap();
+        let table = BlastTable::from_file(path).unwrap();
+
+        // without a header the output matches BLAST byte for byte
+        let mut out = Vec::new();
+        table.write_tsv(&mut out, false).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), original);
+
+        // with a header it reads back to the same records
+        let mut out = Vec::new();
+        table.write_tsv(&mut out, true).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("qseqid\tsseqid\tpident"));
+        let again = BlastTable::from_reader(text.as_bytes()).unwrap();
+        assert_eq!(again.records(), table.records());
+
+        // qlen and slen are carried through
+        let out = "# Fields: qseqid, sseqid, pident, length, mismatch, gapopen, qstart, qend, sstart, send, evalue, bitscore, qlen, slen
+q\ts\t98.765\t100\t1\t0\t1\t100\t200\t101\t1.00e-05\t150\t120\t5000
+";
+        let table = BlastTable::from_reader(out.as_bytes()).unwrap();
+        let mut written = Vec::new();
+        table.write_tsv(&mut written, true).unwrap();
+        let again = BlastTable::from_reader(written.as_slice()).unwrap();
+        assert_eq!(again.records(), table.records());
+        assert!(String::from_utf8(written)
+            .unwrap()
+            .ends_with("1.00e-05\t150\t120\t5000\n"));
+
+        let mut jsonl = Vec::new();
+        table.write_jsonl(&mut jsonl).unwrap();
+        let line: serde_json::Value = serde_json::from_slice(&jsonl).unwrap();
+        assert_eq!(line["sstart"], 200);
+        assert_eq!(line["qlen"], 120);
+    }
+
+    #[test]
+    fn test_format_like_blast() {
+        assert_eq!(format_evalue(0.0), "0.0");
+        assert_eq!(format_evalue(3e-120), "3e-120");
+        assert_eq!(format_evalue(1e-5), "1.00e-05");
+        assert_eq!(format_evalue(0.05), "0.050");
+        assert_eq!(format_evalue(0.5), "0.50");
+        assert_eq!(format_evalue(2.3), "2.3");
+        assert_eq!(format_evalue(12.0), "12");
+        assert_eq!(format_bitscore(54.7), "54.7");
+        assert_eq!(format_bitscore(1234.0), "1234");
+        assert_eq!(format_bitscore(123456.0), "1.235e+05");
+    }
+
+    #[test]
+    fn test_nan_safe_sorting_and_ranking() {
+        let mut nan = hit("chr3", (1, 100), (1, 100), 70.0);
+        nan.evalue = f64::NAN;
+        let mut other = hit("chr1", (1, 100), (1, 100), 10.0);
+        other.qseqid = "family2".into();
+        let mut table = BlastTable::new(vec![
+            nan,
+            hit("chr2", (1, 100), (1, 100), 50.0),
+            other,
+            hit("chr1", (1, 100), (1, 100), 50.0),
+            hit("chr1", (1, 100), (501, 600), 90.0),
+        ]);
+
+        table.sort_by_evalue();
+        assert!(table.records().last().unwrap().evalue.is_nan());
+
+        table.sort_by_keys(&[
+            (SortKey::Bitscore, SortOrder::Descending),
+            (SortKey::Subject, SortOrder::Ascending),
+        ]);
+        let order: Vec<(&str, f64)> = table
+            .records()
+            .iter()
+            .map(|r| (r.sseqid.as_str(), r.bitscore))
+            .collect();
+        assert_eq!(
+            order,
+            [
+                ("chr1", 90.0),
+                ("chr3", 70.0),
+                ("chr1", 50.0),
+                ("chr2", 50.0),
+                ("chr1", 10.0)
+            ]
+        );
+
+        let ranked = table.rank_per_query(2);
+        assert_eq!(ranked.len(), 3);
+        // the NaN e-value is never ranked above a real one
+        let family1: Vec<f64> = ranked
+            .filter_by_query_name("family1")
+            .records()
+            .iter()
+            .map(|r| r.bitscore)
+            .collect();
+        assert_eq!(family1, [90.0, 50.0]);
+        assert_eq!(ranked.filter_by_query_name("family2").len(), 1);
+    }
+
+    #[test]
+    fn test_reader_groups_by_query() {
+        let out = "# BLASTN 2.14.0+
+# Query: query1
+# 2 hits found
+query1\tchr1\t100.000\t29\t0\t0\t1\t29\t19\t47\t4.60e-14\t54.7
+query1\tchr2\t90.000\t29\t3\t0\t1\t29\t5\t33\t1e-5\t30.1
+# BLASTN 2.14.0+
+# Query: query2
+# 1 hits found
+query2\tchr1\t100.000\t39\t0\t0\t1\t39\t1\t39\t1.80e-19\t73.1
+# BLAST processed 2 queries
+";
+        let groups = BlastReader::new(out.as_bytes())
+            .by_query()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].len(), 2);
+        assert_eq!(groups[1].records()[0].qseqid, "query2");
+
+        let table = BlastTable::from_reader(out.as_bytes()).unwrap();
+        assert_eq!(table.comments.len(), 2);
+
+        // tblastn blocks start with their own program line
+        let tblastn = "# TBLASTN 2.14.0+
+# Query: protein1
+# Database: genome.fa
+# 1 hits found
+protein1\tchr1\t80.000\t30\t6\t0\t1\t30\t91\t180\t1e-10\t60.2
+# TBLASTN 2.14.0+
+# Query: protein2
+# Database: genome.fa
+# 1 hits found
+protein2\tchr1\t75.000\t20\t5\t0\t1\t20\t301\t360\t1e-6\t40.1
+# BLAST processed 2 queries
+";
+        let table = BlastTable::from_reader(tblastn.as_bytes()).unwrap();
+        assert_eq!(table.comments.len(), 2);
+        assert_eq!(table.comments[1].query.as_deref(), Some("protein2"));
+        assert_eq!(
+            table.comments[0].program.as_deref(),
+            Some("TBLASTN 2.14.0+")
+        );
+        let groups = BlastReader::new(tblastn.as_bytes())
+            .by_query()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(groups.len(), 2);
+
+        // a query that comes back after another is an error
+        let unsorted = "query1\tchr1\t100\t29\t0\t0\t1\t29\t19\t47\t1e-5\t54.7
+query2\tchr1\t100\t29\t0\t0\t1\t29\t19\t47\t1e-5\t54.7
+query1\tchr2\t100\t29\t0\t0\t1\t29\t19\t47\t1e-5\t54.7
+";
+        let result = BlastReader::new(unsorted.as_bytes())
+            .by_query()
+            .collect::<Result<Vec<_>>>();
+        assert!(result.is_err());
     }
 }
@@ -0,0 +1,373 @@
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+
+use serde::Serialize;
+
+use crate::{
+    output::{rmout_record_batch, write_arrow_ipc, write_jsonl},
+    Error, ErrorKind, Result,
+};
+
+// Parse the RepeatMasker .out file. The first three lines
+// are a two line header followed by a blank line, then one
+// whitespace-delimited line per hit:
+//
+// SW  perc perc perc query  position in query  matching repeat class/family  position in repeat  ID
+// score div. del. ins. sequence begin end (left) strand repeat class/family begin end (left) ID [*]
+//
+// For complement (C) hits the repeat positions are given as
+// (left) end begin. A trailing `*` marks a hit that overlaps
+// a higher scoring one.
+
+// Written as + or - in every output (TSV, JSON Lines and Arrow);
+// only the .out itself uses C.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub enum Strand {
+    #[serde(rename = "+")]
+    Forward,
+    #[serde(rename = "-")]
+    Reverse,
+}
+
+impl Strand {
+    // The single character used in GFF3/BED.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Strand::Forward => "+",
+            Strand::Reverse => "-",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RmOutRecord {
+    pub score: u64,
+    pub perc_div: f64,
+    pub perc_del: f64,
+    pub perc_ins: f64,
+    pub query: String,
+    pub query_start: u64,
+    pub query_end: u64,
+    pub query_left: u64,
+    pub strand: Strand,
+    pub repeat: String,
+    pub class_family: String,
+    // always the lower of the two repeat coordinates
+    pub repeat_start: u64,
+    // always the higher of the two repeat coordinates
+    pub repeat_end: u64,
+    pub repeat_left: u64,
+    pub id: u64,
+    pub overlapping: bool,
+}
+
+// strip the brackets from e.g. (1234)
+fn parse_bracketed(field: &str) -> std::result::Result<u64, std::num::ParseIntError> {
+    field.trim_start_matches('(').trim_end_matches(')').parse()
+}
+
+impl RmOutRecord {
+    // Parse a single data line. `line_no` is only used for errors.
+    pub fn from_line(line: &str, line_no: usize) -> Result<Self> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 15 {
+            return Err(Error::new(ErrorKind::RmOutParse(
+                line_no,
+                format!("expected at least 15 fields, found {}", fields.len()),
+            )));
+        }
+
+        let err = |msg: String| Error::new(ErrorKind::RmOutParse(line_no, msg));
+
+        let strand = match fields[8] {
+            "+" => Strand::Forward,
+            "C" | "-" => Strand::Reverse,
+            other => return Err(err(format!("unknown strand '{}'", other))),
+        };
+
+        let (repeat_start, repeat_end, repeat_left) = match strand {
+            Strand::Forward => (
+                fields[11].parse::<u64>(),
+                fields[12].parse::<u64>(),
+                parse_bracketed(fields[13]),
+            ),
+            Strand::Reverse => (
+                fields[13].parse::<u64>(),
+                fields[12].parse::<u64>(),
+                parse_bracketed(fields[11]),
+            ),
+        };
+        let (repeat_start, repeat_end, repeat_left) = (
+            repeat_start.map_err(|e| err(format!("repeat begin: {}", e)))?,
+            repeat_end.map_err(|e| err(format!("repeat end: {}", e)))?,
+            repeat_left.map_err(|e| err(format!("repeat left: {}", e)))?,
+        );
+
+        let query_start = fields[5]
+            .parse()
+            .map_err(|e| err(format!("query begin: {}", e)))?;
+        let query_end = fields[6]
+            .parse()
+            .map_err(|e| err(format!("query end: {}", e)))?;
+        if query_start > query_end {
+            return Err(err(format!(
+                "query begin {} is after query end {}",
+                query_start, query_end
+            )));
+        }
+
+        Ok(RmOutRecord {
+            score: fields[0]
+                .parse()
+                .map_err(|e| err(format!("score: {}", e)))?,
+            perc_div: fields[1]
+                .parse()
+                .map_err(|e| err(format!("perc div: {}", e)))?,
+            perc_del: fields[2]
+                .parse()
+                .map_err(|e| err(format!("perc del: {}", e)))?,
+            perc_ins: fields[3]
+                .parse()
+                .map_err(|e| err(format!("perc ins: {}", e)))?,
+            query: fields[4].to_string(),
+            query_start,
+            query_end,
+            query_left: parse_bracketed(fields[7])
+                .map_err(|e| err(format!("query left: {}", e)))?,
+            strand,
+            repeat: fields[9].to_string(),
+            class_family: fields[10].to_string(),
+            repeat_start: repeat_start.min(repeat_end),
+            repeat_end: repeat_start.max(repeat_end),
+            repeat_left,
+            id: fields[14].parse().map_err(|e| err(format!("ID: {}", e)))?,
+            overlapping: fields.get(15) == Some(&"*"),
+        })
+    }
+
+    // The repeat class, e.g. `LINE` from `LINE/L1`.
+    pub fn class(&self) -> &str {
+        self.class_family
+            .split_once('/')
+            .map(|(c, _)| c)
+            .unwrap_or(&self.class_family)
+    }
+
+    // The repeat family, e.g. `L1` from `LINE/L1`. Classes without
+    // a family (e.g. `Simple_repeat`) return the class.
+    pub fn family(&self) -> &str {
+        self.class_family
+            .split_once('/')
+            .map(|(_, f)| f)
+            .unwrap_or(&self.class_family)
+    }
+
+    // Number of bases of the query covered by this hit.
+    pub fn query_length(&self) -> u64 {
+        self.query_end - self.query_start + 1
+    }
+
+    // The order overlapping hits claim bases in: highest score
+    // first, then the longest, then the leftmost.
+    pub fn overlap_order(&self, other: &Self) -> Ordering {
+        other
+            .score
+            .cmp(&self.score)
+            .then(other.query_length().cmp(&self.query_length()))
+            .then(self.query_start.cmp(&other.query_start))
+    }
+
+    // The length of the repeat consensus, inferred from the
+    // end position and the number of bases left.
+    pub fn consensus_length(&self) -> u64 {
+        self.repeat_end + self.repeat_left
+    }
+
+    pub fn is_simple_or_low_complexity(&self) -> bool {
+        matches!(self.class(), "Simple_repeat" | "Low_complexity")
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RmOutTable(pub Vec<RmOutRecord>);
+
+impl RmOutTable {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        Self::from_reader(reader)
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Self> {
+        let mut records = Vec::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let trimmed = line.trim();
+            // skip the header lines and any blank lines
+            match trimmed.split_whitespace().next() {
+                None => continue,
+                Some(first) if first.parse::<u64>().is_err() => continue,
+                Some(_) => records.push(RmOutRecord::from_line(trimmed, i + 1)?),
+            }
+        }
+        Ok(RmOutTable(records))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    // Write the table as tab-delimited text with a header. Repeat
+    // coordinates are always written start < end, with the strand.
+    pub fn write_tsv<W: Write>(&self, writer: W) -> Result<()> {
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .quote_style(csv::QuoteStyle::Never)
+            .from_writer(writer);
+        wtr.write_record([
+            "score",
+            "perc_div",
+            "perc_del",
+            "perc_ins",
+            "query",
+            "query_start",
+            "query_end",
+            "query_left",
+            "strand",
+            "repeat",
+            "class_family",
+            "repeat_start",
+            "repeat_end",
+            "repeat_left",
+            "id",
+            "overlapping",
+        ])?;
+        for r in &self.0 {
+            wtr.write_record([
+                r.score.to_string(),
+                format!("{:.1}", r.perc_div),
+                format!("{:.1}", r.perc_del),
+                format!("{:.1}", r.perc_ins),
+                r.query.clone(),
+                r.query_start.to_string(),
+                r.query_end.to_string(),
+                r.query_left.to_string(),
+                r.strand.as_str().to_string(),
+                r.repeat.clone(),
+                r.class_family.clone(),
+                r.repeat_start.to_string(),
+                r.repeat_end.to_string(),
+                r.repeat_left.to_string(),
+                r.id.to_string(),
+                r.overlapping.to_string(),
+            ])?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    // Write one JSON object per record.
+    pub fn write_jsonl<W: Write>(&self, writer: W) -> Result<()> {
+        write_jsonl(&self.0, writer)
+    }
+
+    // Write the table as an Arrow IPC file.
+    pub fn write_arrow<W: Write>(&self, writer: W) -> Result<()> {
+        write_arrow_ipc(&rmout_record_batch(self)?, writer)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUT: &str = "   SW   perc perc perc  query     position in query    matching  repeat          position in repeat
+score   div. del. ins.  sequence  begin end  (left)   repeat    class/family    begin  end (left)  ID
+
+  463   26.1  0.0  3.3  chr1          11     72 (928) + (CCCTAA)n Simple_repeat       1    60    (0)  1
+ 1320   15.6  6.2  0.0  chr1         101    330 (670) C MER7A     DNA/MER2_type     (0)   336   103  2
+  250   20.1  1.0  1.0  chr1         300    400 (600) + L1MA1     LINE/L1          5900  6000   (10) 3 *
+";
+
+    #[test]
+    fn test_parse_rmout() {
+        let table = RmOutTable::from_reader(OUT.as_bytes()).unwrap();
+        assert_eq!(table.len(), 3);
+
+        let simple = &table.0[0];
+        assert_eq!(simple.class(), "Simple_repeat");
+        assert_eq!(simple.family(), "Simple_repeat");
+        assert!(simple.is_simple_or_low_complexity());
+        assert_eq!(simple.query_length(), 62);
+
+        let rev = &table.0[1];
+        assert_eq!(rev.strand, Strand::Reverse);
+        assert_eq!((rev.repeat_start, rev.repeat_end), (103, 336));
+        assert_eq!(rev.consensus_length(), 336);
+        assert_eq!(rev.class(), "DNA");
+        assert_eq!(rev.family(), "MER2_type");
+
+        let line = &table.0[2];
+        assert!(line.overlapping);
+        assert_eq!(line.consensus_length(), 6010);
+    }
+
+    #[test]
+    fn test_write_rmout() {
+        let table = RmOutTable::from_reader(OUT.as_bytes()).unwrap();
+
+        let mut tsv = Vec::new();
+        table.write_tsv(&mut tsv).unwrap();
+        let tsv = String::from_utf8(tsv).unwrap();
+        let lines: Vec<&str> = tsv.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[2],
+            "1320\t15.6\t6.2\t0.0\tchr1\t101\t330\t670\t-\tMER7A\tDNA/MER2_type\t103\t336\t0\t2\tfalse"
+        );
+
+        let mut jsonl = Vec::new();
+        table.write_jsonl(&mut jsonl).unwrap();
+        let jsonl = String::from_utf8(jsonl).unwrap();
+        let second: serde_json::Value =
+            serde_json::from_str(jsonl.lines().nth(1).unwrap()).unwrap();
+        assert_eq!(second["strand"], "-");
+        assert_eq!(second["repeat_end"], 336);
+
+        let mut arrow = Vec::new();
+        table.write_arrow(&mut arrow).unwrap();
+        assert!(arrow.starts_with(b"ARROW1"));
+        let batch = crate::output::rmout_record_batch(&table).unwrap();
+        let strands = batch
+            .column_by_name("strand")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<arrow_array::StringArray>()
+            .unwrap()
+            .iter()
+            .map(Option::unwrap)
+            .collect::<Vec<_>>();
+        assert_eq!(strands, vec!["+", "-", "+"]);
+    }
+
+    #[test]
+    fn test_parse_rmout_short_line_errors() {
+        let bad = "  463   26.1  0.0  3.3  chr1  11  72 (928) +\n";
+        let err = RmOutTable::from_reader(bad.as_bytes()).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::RmOutParse(1, _)));
+
+        // the query begin after its end
+        let bad = "  463   26.1  0.0  3.3  chr1  11  72 (928) + L1MA1  LINE/L1    1   62 (938) 1
+  463   26.1  0.0  3.3  chr1  72  11 (928) + L1MA1  LINE/L1    1   62 (938) 2
+";
+        let err = RmOutTable::from_reader(bad.as_bytes()).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::RmOutParse(2, _)));
+    }
+}
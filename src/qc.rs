@@ -0,0 +1,328 @@
+// Sanity checks on curated consensi that depend on their class:
+// - non-LTR retrotransposons end in a poly-A or short simple
+//   repeat tail, which SINEs should have;
+// - autonomous LINEs encode their proteins, so should have
+//   at least one long ORF.
+// Families failing these are flagged for manual review.
+
+use std::io::Write;
+
+use bio::alphabets::dna::revcomp;
+
+use crate::{library::split_id, parse_rmout::Strand, Result};
+
+#[derive(Debug, Clone, Copy)]
+pub struct QcOptions {
+    // the shortest tail reported
+    pub min_tail: usize,
+    // the longest repeat unit of a simple tail, e.g. 3 for (TAA)n
+    pub max_tail_unit: usize,
+    // the shortest ORF reported, in codons
+    pub min_orf: usize,
+}
+
+impl Default for QcOptions {
+    fn default() -> Self {
+        Self {
+            min_tail: 10,
+            max_tail_unit: 4,
+            min_orf: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tail {
+    // the repeat unit, e.g. A or TAA
+    pub motif: String,
+    pub length: usize,
+    // Reverse if the consensus is reverse complemented,
+    // with the tail as poly-T at its start
+    pub strand: Strand,
+}
+
+// The length of the run at the end of `seq` with period `unit`.
+fn periodic_suffix(seq: &[u8], unit: usize) -> usize {
+    if seq.len() < unit {
+        return 0;
+    }
+    let mut start = seq.len() - unit;
+    while start > 0 && seq[start - 1].eq_ignore_ascii_case(&seq[start - 1 + unit]) {
+        start -= 1;
+    }
+    seq.len() - start
+}
+
+fn tail_on_strand(seq: &[u8], strand: Strand, options: &QcOptions) -> Option<Tail> {
+    let mut best: Option<Tail> = None;
+    for unit in 1..=options.max_tail_unit {
+        let length = periodic_suffix(seq, unit);
+        let motif = &seq[seq.len().saturating_sub(unit)..];
+        // a single base repeated is poly-N, not a longer unit
+        let is_homopolymer = motif.iter().all(|c| c.eq_ignore_ascii_case(&motif[0]));
+        if length < options.min_tail
+            || (unit > 1 && is_homopolymer)
+            || motif.iter().any(|c| c.eq_ignore_ascii_case(&b'N'))
+            || best.as_ref().is_some_and(|b| b.length >= length)
+        {
+            continue;
+        }
+        // report the motif in phase with the start of the tail
+        let start = seq.len() - length;
+        best = Some(Tail {
+            motif: String::from_utf8_lossy(&seq[start..start + unit]).to_uppercase(),
+            length,
+            strand,
+        });
+    }
+    best
+}
+
+// A simple tail at the 3' end of the consensus, or at the 5' end
+// of its reverse complement. The longer one wins.
+pub fn find_tail(seq: &[u8], options: &QcOptions) -> Option<Tail> {
+    let forward = tail_on_strand(seq, Strand::Forward, options);
+    let reverse = tail_on_strand(&revcomp(seq), Strand::Reverse, options);
+    match (forward, reverse) {
+        (Some(f), Some(r)) if r.length > f.length => Some(r),
+        (Some(f), _) => Some(f),
+        (None, r) => r,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Orf {
+    // 1-based, inclusive, on the forward strand of the consensus,
+    // including the stop codon if there is one
+    pub start: usize,
+    pub end: usize,
+    pub strand: Strand,
+    // 0-2, the offset of the first codon on its strand
+    pub frame: usize,
+    // codons, excluding the stop
+    pub codons: usize,
+}
+
+fn is_stop(codon: &[u8]) -> bool {
+    let codon = codon.to_ascii_uppercase();
+    matches!(codon.as_slice(), b"TAA" | b"TAG" | b"TGA")
+}
+
+fn is_start(codon: &[u8]) -> bool {
+    codon.eq_ignore_ascii_case(b"ATG")
+}
+
+// ATG to stop ORFs of at least `min_codons` in all six frames.
+// An ORF running off the end of the consensus is kept, as the
+// consensus may be truncated. Sorted longest first.
+pub fn find_orfs(seq: &[u8], min_codons: usize) -> Vec<Orf> {
+    let mut orfs = Vec::new();
+    for (strand, s) in [
+        (Strand::Forward, seq.to_vec()),
+        (Strand::Reverse, revcomp(seq)),
+    ] {
+        for frame in 0..3 {
+            let codons = s.get(frame..).unwrap_or_default().chunks_exact(3);
+            let mut open: Option<usize> = None;
+            for (i, codon) in codons.enumerate() {
+                match open {
+                    None if is_start(codon) => open = Some(i),
+                    Some(first) if is_stop(codon) => {
+                        orfs.push((strand, frame, first, i, true));
+                        open = None;
+                    }
+                    _ => (),
+                }
+            }
+            if let Some(first) = open {
+                let last = (s.len() - frame) / 3;
+                orfs.push((strand, frame, first, last, false));
+            }
+        }
+    }
+
+    let mut orfs = orfs
+        .into_iter()
+        .filter(|&(_, _, first, last, _)| last - first >= min_codons)
+        .map(|(strand, frame, first, last, stopped)| {
+            // bases on the strand, 0-based, end exclusive
+            let from = frame + 3 * first;
+            let to = frame + 3 * last + if stopped { 3 } else { 0 };
+            let (start, end) = match strand {
+                Strand::Forward => (from + 1, to),
+                Strand::Reverse => (seq.len() - to + 1, seq.len() - from),
+            };
+            Orf {
+                start,
+                end,
+                strand,
+                frame,
+                codons: last - first,
+            }
+        })
+        .collect::<Vec<_>>();
+    orfs.sort_by(|a, b| b.codons.cmp(&a.codons).then(a.start.cmp(&b.start)));
+    orfs
+}
+
+#[derive(Debug, Clone)]
+pub struct QcReport {
+    pub family: String,
+    pub length: usize,
+    pub tail: Option<Tail>,
+    pub orfs: Vec<Orf>,
+}
+
+impl QcReport {
+    pub fn new(family: &str, consensus: &[u8], options: &QcOptions) -> Self {
+        QcReport {
+            family: family.to_string(),
+            length: consensus.len(),
+            tail: find_tail(consensus, options),
+            orfs: find_orfs(consensus, options.min_orf),
+        }
+    }
+
+    // Why the family needs a manual look, if it does.
+    pub fn review(&self) -> Option<&'static str> {
+        let class = split_id(&self.family).1.unwrap_or("");
+        if class.starts_with("LINE") && self.orfs.is_empty() {
+            Some("LINE without ORF")
+        } else if class.starts_with("SINE") && self.tail.is_none() {
+            Some("SINE without tail")
+        } else {
+            None
+        }
+    }
+}
+
+// Only the longest ORFs are listed in qc.tsv; the consensus of a
+// LINE can have hundreds over the shortest length.
+const MAX_ORF_COORDINATES: usize = 3;
+
+pub fn write_qc_tsv<W: Write>(reports: &[QcReport], writer: W) -> Result<()> {
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_writer(writer);
+    wtr.write_record([
+        "family",
+        "length",
+        "tail_motif",
+        "tail_length",
+        "tail_strand",
+        "orfs",
+        "longest_orf_codons",
+        "orf_coordinates",
+        "review",
+    ])?;
+    for r in reports {
+        let (motif, length, strand) = match &r.tail {
+            Some(t) => (t.motif.clone(), t.length.to_string(), t.strand.as_str()),
+            None => (String::new(), String::new(), ""),
+        };
+        let coordinates = r
+            .orfs
+            .iter()
+            .take(MAX_ORF_COORDINATES)
+            .map(|o| format!("{}-{}({})", o.start, o.end, o.strand.as_str()))
+            .collect::<Vec<_>>()
+            .join(",");
+        wtr.write_record([
+            r.family.as_str(),
+            &r.length.to_string(),
+            &motif,
+            &length,
+            strand,
+            &r.orfs.len().to_string(),
+            &r.orfs
+                .first()
+                .map(|o| o.codons.to_string())
+                .unwrap_or_default(),
+            &coordinates,
+            r.review().unwrap_or(""),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_tail() {
+        let options = QcOptions::default();
+        let tail = find_tail(b"GGCTCACGCCTGTAATCCCAGCAAAAAAAAAAAAA", &options).unwrap();
+        assert_eq!((tail.motif.as_str(), tail.length), ("A", 13));
+        assert_eq!(tail.strand, Strand::Forward);
+
+        let tail = find_tail(b"CCGGCTCACGTAATAATAATAATAA", &options).unwrap();
+        assert_eq!((tail.motif.as_str(), tail.length), ("TAA", 15));
+
+        // poly-T at the start is a tail on the reverse strand
+        let tail = find_tail(b"TTTTTTTTTTTTGCGGCTCACGCC", &options).unwrap();
+        assert_eq!((tail.motif.as_str(), tail.length), ("A", 12));
+        assert_eq!(tail.strand, Strand::Reverse);
+
+        assert_eq!(find_tail(b"GGCTCACGCCTGTAATCCCAGCAAA", &options), None);
+    }
+
+    #[test]
+    fn test_find_orfs() {
+        // ATG, 4 codons, TAA, in frame 1
+        let seq = b"GATGGCTGCAGCTTAAGG";
+        let orfs = find_orfs(seq, 4);
+        assert_eq!(
+            orfs,
+            vec![Orf {
+                start: 2,
+                end: 16,
+                strand: Strand::Forward,
+                frame: 1,
+                codons: 4
+            }]
+        );
+
+        // the same on the reverse strand
+        let orfs = find_orfs(&revcomp(seq), 4);
+        assert_eq!((orfs[0].start, orfs[0].end), (3, 17));
+        assert_eq!(orfs[0].strand, Strand::Reverse);
+
+        // running off the end
+        let orfs = find_orfs(b"ATGGCTGCAGCA", 4);
+        assert_eq!((orfs[0].start, orfs[0].end, orfs[0].codons), (1, 12, 4));
+        assert!(find_orfs(seq, 5).is_empty());
+    }
+
+    #[test]
+    fn test_review() {
+        let options = QcOptions::default();
+        let line = QcReport::new("rnd-1_family-5#LINE/L1", b"ACGTACGT", &options);
+        assert_eq!(line.review(), Some("LINE without ORF"));
+        let sine = QcReport::new("rnd-1_family-6#SINE/Alu", b"ACGTACGT", &options);
+        assert_eq!(sine.review(), Some("SINE without tail"));
+        let dna = QcReport::new("rnd-1_family-7#DNA/hAT", b"ACGTACGT", &options);
+        assert_eq!(dna.review(), None);
+    }
+
+    #[test]
+    fn test_qc_tsv_lists_the_longest_orfs() {
+        let options = QcOptions {
+            min_orf: 2,
+            ..Default::default()
+        };
+        let seq = b"ATGAAATAGATGAAAAAATAAATGAAAAAAAAATGAATGAAATAA";
+        let report = QcReport::new("rnd-1_family-5#LINE/L1", seq, &options);
+        assert!(report.orfs.len() > MAX_ORF_COORDINATES);
+
+        let mut tsv = Vec::new();
+        write_qc_tsv(std::slice::from_ref(&report), &mut tsv).unwrap();
+        let tsv = String::from_utf8(tsv).unwrap();
+        let row = tsv.lines().nth(1).unwrap().split('\t').collect::<Vec<_>>();
+        assert_eq!(row[5], report.orfs.len().to_string());
+        assert_eq!(row[7].split(',').count(), MAX_ORF_COORDINATES);
+        let longest = &report.orfs[0];
+        assert!(row[7].starts_with(&format!("{}-{}", longest.start, longest.end)));
+    }
+}
@@ -1,8 +1,81 @@
 use std::process::Command;
-use std::{fs, path::PathBuf};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 use walkdir::WalkDir;
 
-use crate::{CliArgs, CommandRunner, Error, ErrorKind, Result, DATA};
+use crate::{merge::merged_library_path, CliArgs, CommandRunner, Error, ErrorKind, Result, DATA};
+
+// RepeatMasker writes <genome file name>.out into the -dir
+// directory, which is data/repeatmasker in our layout.
+pub fn repeatmasker_out_path(matches: &CliArgs) -> Result<PathBuf> {
+    let mut data_path = matches.configure.clone().ok_or_else(|| {
+        Error::new(ErrorKind::GenericCli(
+            "A configure directory is required to find the RepeatMasker output".into(),
+        ))
+    })?;
+    data_path.push(DATA);
+    data_path.push("repeatmasker");
+
+    let fasta_name = matches
+        .fasta_file
+        .file_name()
+        .ok_or_else(|| Error::new(ErrorKind::GenericCli("FASTA path has no file name".into())))?;
+    data_path.push(format!("{}.out", fasta_name.to_string_lossy()));
+
+    Ok(data_path)
+}
+
+// we need to find the consensi.fa.classified
+// inside the configured directory
+pub(crate) fn find_consensi(configure: &Path) -> Option<PathBuf> {
+    let file_to_find = "consensi.fa.classified";
+    for entry in WalkDir::new(configure)
+        .follow_links(true)
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        let f_name = entry.file_name().to_string_lossy();
+
+        if f_name == file_to_find {
+            return fs::canonicalize(entry.path()).ok();
+        }
+    }
+    None
+}
+
+// The library to mask with. A library merged by `rep library merge`
+// takes the place of the RepeatModeler library, unless RepeatModeler
+// has been re-run since, when the merge is stale and is ignored.
+pub(crate) fn masking_library(configure: &Path) -> Result<PathBuf> {
+    let consensi = find_consensi(configure);
+    let merged = merged_library_path(configure);
+    let modified = |p: &Path| fs::metadata(p).and_then(|m| m.modified()).ok();
+    match (merged.exists(), consensi) {
+        (true, Some(consensi))
+            if modified(&merged)
+                .zip(modified(&consensi))
+                .is_some_and(|(m, c)| m < c) =>
+        {
+            eprintln!(
+                "Ignoring {}, which is older than {}; re-run `rep library merge` to use it",
+                merged.display(),
+                consensi.display()
+            );
+            Ok(consensi)
+        }
+        (true, _) => {
+            eprintln!("Masking with the merged library {}", merged.display());
+            Ok(merged)
+        }
+        (false, Some(consensi)) => Ok(consensi),
+        (false, None) => Err(Error::new(ErrorKind::GenericCli(
+            "No consensi.fa.classified found in the data directory. Did you run RepeatModeler?"
+                .to_string(),
+        ))),
+    }
+}
 
 pub fn run_repeatmasker(matches: CliArgs, runner: &dyn CommandRunner) -> Result<()> {
     // get the data path again
@@ -11,32 +84,7 @@ pub fn run_repeatmasker(matches: CliArgs, runner: &dyn CommandRunner) -> Result<
     // and go into the repeatmasker dir
     data_path.push("repeatmasker");
 
-    // we need to find the consensi.fa.classified
-    // inside the data directory
-    let file_to_find = "consensi.fa.classified";
-    let consensi_finder = || -> Option<PathBuf> {
-        for entry in WalkDir::new(matches.configure.clone().unwrap())
-            .follow_links(true)
-            .into_iter()
-            .filter_map(|e| e.ok())
-        {
-            let f_name = entry.file_name().to_string_lossy();
-
-            if f_name == file_to_find {
-                return Some(fs::canonicalize(entry.path()).unwrap());
-            }
-        }
-        None
-    };
-
-    let full_consensi_path =
-        match consensi_finder() {
-            Some(p) => p,
-            None => return Err(Error::new(ErrorKind::GenericCli(
-                "No consensi.fa.classified found in the data directory. Did you run RepeatModeler?"
-                    .to_string(),
-            ))),
-        };
+    let full_consensi_path = masking_library(matches.configure.as_ref().unwrap())?;
 
     eprintln!("Data path: {:?}", data_path);
 
@@ -136,4 +184,39 @@ mod tests {
         let runner = MockRunner;
         assert!(run_repeatmasker(args, &runner).is_ok());
     }
+
+    #[test]
+    fn test_stale_merged_library_is_ignored() {
+        let tmp = tempdir().unwrap();
+        let modeler = tmp.path().join("data").join("repeatmodeler");
+        std::fs::create_dir_all(&modeler).unwrap();
+        let consensi = modeler.join("consensi.fa.classified");
+        write(&consensi, ">repeat\nACGT").unwrap();
+        assert_eq!(
+            masking_library(tmp.path()).unwrap(),
+            fs::canonicalize(&consensi).unwrap()
+        );
+
+        let merged = merged_library_path(tmp.path());
+        std::fs::create_dir_all(merged.parent().unwrap()).unwrap();
+        write(&merged, ">repeat\nACGT").unwrap();
+        let set_age = |path: &Path, secs: u64| {
+            std::fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs))
+                .unwrap();
+        };
+        set_age(&consensi, 1000);
+        set_age(&merged, 2000);
+        assert_eq!(masking_library(tmp.path()).unwrap(), merged);
+
+        // RepeatModeler was re-run after the merge
+        set_age(&consensi, 3000);
+        assert_eq!(
+            masking_library(tmp.path()).unwrap(),
+            fs::canonicalize(&consensi).unwrap()
+        );
+    }
 }
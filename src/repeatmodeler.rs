@@ -53,8 +53,7 @@ pub fn run_repeatmodeler(matches: CliArgs, runner: &dyn CommandRunner) -> Result
         .arg("-database")
         .arg(matches.database.unwrap())
         .arg("-threads")
-        .arg(matches.rmo_threads.to_string())
-        .spawn()?;
+        .arg(matches.rmo_threads.to_string());
 
     let out = runner.run(&mut run_repeat_modeler)?;
     if !out.status.success() {
@@ -0,0 +1,334 @@
+// Clean up the RepeatMasker annotation into full-length copies.
+//
+// RepeatMasker splits one insertion into several lines sharing an
+// ID (e.g. when interrupted by a younger insertion), and reports
+// hits that overlap each other. Similar to the "one code to find
+// them all" approach we:
+// 1. resolve overlaps by score, trimming the lower scoring hit
+//    so each base belongs to only one hit;
+// 2. merge the remaining fragments by ID into elements;
+// 3. report each element's coverage of its consensus, so full
+//    length copies can be counted.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+use crate::{
+    intervals::IntervalSet,
+    parse_rmout::{RmOutRecord, RmOutTable, Strand},
+    repeatmasker::repeatmasker_out_path,
+    staged_genome, CliArgs, Error, ErrorKind, Result, RESULTS,
+};
+
+// The fraction of the consensus an element must cover to
+// be counted as a full-length copy.
+pub const FULL_LENGTH_COVERAGE: f64 = 0.8;
+
+#[derive(Debug, Clone)]
+pub struct RepeatElement {
+    pub query: String,
+    pub id: u64,
+    pub repeat: String,
+    pub class_family: String,
+    pub strand: Strand,
+    // span of the element on the query, over all fragments
+    pub start: u64,
+    pub end: u64,
+    // bases of the query assigned to this element
+    pub bases: u64,
+    pub fragments: usize,
+    // summed over the fragments
+    pub score: u64,
+    // weighted by fragment length
+    pub perc_div: f64,
+    pub consensus_length: u64,
+    // bases of the consensus covered by the fragments
+    pub consensus_covered: u64,
+}
+
+impl RepeatElement {
+    pub fn consensus_coverage(&self) -> f64 {
+        if self.consensus_length == 0 {
+            0.0
+        } else {
+            self.consensus_covered as f64 / self.consensus_length as f64
+        }
+    }
+
+    pub fn is_full_length(&self, min_coverage: f64) -> bool {
+        self.consensus_coverage() >= min_coverage
+    }
+}
+
+// Cut a hit down to the query interval [start, end], moving the
+// repeat coordinates by the same number of bases. This ignores
+// indels, so the repeat coordinates are approximate.
+fn trim_hit(hit: &RmOutRecord, start: u64, end: u64) -> RmOutRecord {
+    let left = start - hit.query_start;
+    let right = hit.query_end - end;
+    let mut trimmed = hit.clone();
+    trimmed.query_start = start;
+    trimmed.query_end = end;
+    trimmed.query_left += right;
+    // the piece gets its share of the score, so the pieces of a
+    // split hit add up to it again
+    trimmed.score =
+        (hit.score as f64 * (end - start + 1) as f64 / hit.query_length() as f64).round() as u64;
+
+    // on the reverse strand, the left of the query is
+    // the right of the consensus
+    let (from_start, from_end) = match hit.strand {
+        Strand::Forward => (left, right),
+        Strand::Reverse => (right, left),
+    };
+    trimmed.repeat_start = (hit.repeat_start + from_start).min(hit.repeat_end);
+    trimmed.repeat_end = hit
+        .repeat_end
+        .saturating_sub(from_end)
+        .max(trimmed.repeat_start);
+    trimmed.repeat_left += hit.repeat_end - trimmed.repeat_end;
+    trimmed
+}
+
+// Assign each base to the highest scoring hit covering it. Lower
+// scoring hits are trimmed, split around higher scoring ones, or
+// dropped if they are completely covered.
+pub fn resolve_overlaps(table: &RmOutTable) -> RmOutTable {
+    let mut by_query: BTreeMap<&str, Vec<&RmOutRecord>> = BTreeMap::new();
+    for record in &table.0 {
+        by_query.entry(&record.query).or_default().push(record);
+    }
+
+    let mut resolved = Vec::new();
+    for (_, mut hits) in by_query {
+        hits.sort_by(|a, b| a.overlap_order(b));
+
+        let mut covered = IntervalSet::new();
+        let mut kept = Vec::new();
+        for hit in hits {
+            for (start, end) in covered.uncovered(hit.query_start, hit.query_end) {
+                let mut piece = trim_hit(hit, start, end);
+                piece.overlapping = false;
+                kept.push(piece);
+            }
+            covered.insert(hit.query_start, hit.query_end);
+        }
+        kept.sort_by_key(|r| r.query_start);
+        resolved.extend(kept);
+    }
+
+    RmOutTable(resolved)
+}
+
+// Merge fragments sharing a RepeatMasker ID into elements.
+pub fn merge_fragments(table: &RmOutTable) -> Vec<RepeatElement> {
+    let mut by_id: HashMap<(&str, u64), Vec<&RmOutRecord>> = HashMap::new();
+    for record in &table.0 {
+        by_id
+            .entry((&record.query, record.id))
+            .or_default()
+            .push(record);
+    }
+
+    let mut elements: Vec<RepeatElement> = by_id
+        .into_iter()
+        .map(|((query, id), fragments)| {
+            // the highest scoring fragment names the element
+            let best = fragments
+                .iter()
+                .max_by_key(|f| f.score)
+                .expect("at least one fragment");
+
+            let mut consensus = IntervalSet::new();
+            let mut bases = 0;
+            let mut weighted_div = 0.0;
+            for f in &fragments {
+                consensus.insert(f.repeat_start, f.repeat_end);
+                bases += f.query_length();
+                weighted_div += f.perc_div * f.query_length() as f64;
+            }
+
+            RepeatElement {
+                query: query.to_string(),
+                id,
+                repeat: best.repeat.clone(),
+                class_family: best.class_family.clone(),
+                strand: best.strand,
+                start: fragments.iter().map(|f| f.query_start).min().unwrap_or(0),
+                end: fragments.iter().map(|f| f.query_end).max().unwrap_or(0),
+                bases,
+                fragments: fragments.len(),
+                score: fragments.iter().map(|f| f.score).sum(),
+                perc_div: if bases == 0 {
+                    0.0
+                } else {
+                    weighted_div / bases as f64
+                },
+                consensus_length: fragments
+                    .iter()
+                    .map(|f| f.consensus_length())
+                    .max()
+                    .unwrap_or(0),
+                consensus_covered: consensus.covered(),
+            }
+        })
+        .collect();
+
+    elements.sort_by(|a, b| a.query.cmp(&b.query).then(a.start.cmp(&b.start)));
+    elements
+}
+
+// Resolve overlaps and merge fragments in one go.
+pub fn resolve_elements(table: &RmOutTable) -> Vec<RepeatElement> {
+    merge_fragments(&resolve_overlaps(table))
+}
+
+pub fn write_elements_tsv<W: Write>(
+    elements: &[RepeatElement],
+    min_coverage: f64,
+    writer: W,
+) -> Result<()> {
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_writer(writer);
+    wtr.write_record([
+        "query",
+        "start",
+        "end",
+        "strand",
+        "repeat",
+        "class_family",
+        "id",
+        "fragments",
+        "bases",
+        "score",
+        "perc_div",
+        "consensus_length",
+        "consensus_coverage",
+        "full_length",
+    ])?;
+    for e in elements {
+        wtr.write_record([
+            e.query.as_str(),
+            &e.start.to_string(),
+            &e.end.to_string(),
+            e.strand.as_str(),
+            &e.repeat,
+            &e.class_family,
+            &e.id.to_string(),
+            &e.fragments.to_string(),
+            &e.bases.to_string(),
+            &e.score.to_string(),
+            &format!("{:.2}", e.perc_div),
+            &e.consensus_length.to_string(),
+            &format!("{:.4}", e.consensus_coverage()),
+            &e.is_full_length(min_coverage).to_string(),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+// Resolve the RepeatMasker output of a pipeline run into
+// elements, written to results/<genome>.elements.tsv.
+pub fn resolve_repeatmasker(matches: &CliArgs) -> Result<()> {
+    let out_path = repeatmasker_out_path(matches)?;
+    if !out_path.exists() {
+        return Err(Error::new(ErrorKind::GenericCli(format!(
+            "RepeatMasker output {} not found. Did RepeatMasker run?",
+            out_path.display()
+        ))));
+    }
+
+    let table = RmOutTable::from_file(&out_path)?;
+    let elements = resolve_elements(&table);
+
+    let stem = staged_genome(matches)?
+        .file_name()
+        .map(|f| f.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut tsv = matches.configure.clone().unwrap();
+    tsv.push(RESULTS);
+    tsv.push(format!("{}.elements.tsv", stem));
+    write_elements_tsv(
+        &elements,
+        FULL_LENGTH_COVERAGE,
+        BufWriter::new(File::create(&tsv)?),
+    )?;
+
+    eprintln!(
+        "Resolved {} hits into {} elements ({} full length). Written to {}",
+        table.len(),
+        elements.len(),
+        elements
+            .iter()
+            .filter(|e| e.is_full_length(FULL_LENGTH_COVERAGE))
+            .count(),
+        tsv.display()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an L1 interrupted by an Alu, with an overlapping low scoring hit
+    const OUT: &str = "
+ 5000   10.0  0.0  0.0  chr1    1  3000 (9000) + L1MA1  LINE/L1    1 3000 (1000) 1
+ 2000   12.0  0.0  0.0  chr1 3001  3300 (8700) + AluY   SINE/Alu   1  300    (0) 2
+ 3000    8.0  0.0  0.0  chr1 3301  4300 (7700) + L1MA1  LINE/L1 3001 4000    (0) 1
+  200   30.0  0.0  0.0  chr1 4201  4400 (7600) C MER5A  DNA/hAT  (10)  200    1 3 *
+";
+
+    #[test]
+    fn test_resolve_elements() {
+        let table = RmOutTable::from_reader(OUT.as_bytes()).unwrap();
+
+        let resolved = resolve_overlaps(&table);
+        assert_eq!(resolved.len(), 4);
+        let mer = resolved.0.iter().find(|r| r.repeat == "MER5A").unwrap();
+        // trimmed to where the L1 ends
+        assert_eq!((mer.query_start, mer.query_end), (4301, 4400));
+        // the left of the query is the right of the consensus
+        assert_eq!((mer.repeat_start, mer.repeat_end), (1, 100));
+        assert!(!mer.overlapping);
+
+        let elements = merge_fragments(&resolved);
+        assert_eq!(elements.len(), 3);
+
+        let l1 = &elements[0];
+        assert_eq!(l1.repeat, "L1MA1");
+        assert_eq!(l1.fragments, 2);
+        assert_eq!((l1.start, l1.end), (1, 4300));
+        assert_eq!(l1.bases, 4000);
+        assert_eq!(l1.score, 8000);
+        assert_eq!(l1.consensus_length, 4000);
+        assert!(l1.is_full_length(FULL_LENGTH_COVERAGE));
+
+        let mer = &elements[2];
+        assert_eq!(mer.consensus_length, 210);
+        // half of it was trimmed
+        assert_eq!(mer.score, 100);
+        assert!(!mer.is_full_length(FULL_LENGTH_COVERAGE));
+
+        // a hit split around a higher scoring insertion scores no
+        // more than it did whole
+        let split = RmOutTable::from_reader(
+            " 5000   10.0  0.0  0.0  chr2    1  4000    (0) + L1MA1  LINE/L1    1 4000    (0) 1
+ 6000    5.0  0.0  0.0  chr2 2001  2300 (1700) + AluY   SINE/Alu   1  300    (0) 2
+"
+            .as_bytes(),
+        )
+        .unwrap();
+        let elements = resolve_elements(&split);
+        let l1 = elements.iter().find(|e| e.repeat == "L1MA1").unwrap();
+        assert_eq!(l1.fragments, 2);
+        assert_eq!(l1.bases, 3700);
+        assert_eq!(l1.score, 4625);
+    }
+}
@@ -0,0 +1,354 @@
+// Structural evidence for the class of a family: terminal inverted
+// repeats (DNA transposons), long terminal repeats (LTR
+// retrotransposons) and the target site duplications either side
+// of an insertion.
+//
+// The terminal repeats are found on the consensus by extending an
+// ungapped match from its two ends, with an X-drop so the match
+// stops once it has run into unrelated sequence. The TSDs need the
+// copies with their flanks, so come from the aligned copies and the
+// edges of the element in the alignment.
+
+use std::{collections::HashMap, io::Write};
+
+use bio::alphabets::dna::complement;
+
+use crate::{edges::Edges, Result};
+
+#[derive(Debug, Clone, Copy)]
+pub struct StructureOptions {
+    pub min_terminal: usize,
+    pub max_terminal: usize,
+    pub min_identity: f64,
+    // how far the score may fall below its best before the
+    // extension stops
+    pub xdrop: i64,
+    pub min_tsd: usize,
+    pub max_tsd: usize,
+}
+
+impl Default for StructureOptions {
+    fn default() -> Self {
+        Self {
+            min_terminal: 10,
+            max_terminal: 5000,
+            min_identity: 0.8,
+            xdrop: 10,
+            min_tsd: 2,
+            max_tsd: 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TerminalKind {
+    Tir,
+    Ltr,
+}
+
+impl TerminalKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TerminalKind::Tir => "TIR",
+            TerminalKind::Ltr => "LTR",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TerminalRepeat {
+    pub kind: TerminalKind,
+    // of each repeat
+    pub length: usize,
+    pub identity: f64,
+}
+
+// Extend an ungapped match over `pairs`, scoring +1 for a match
+// and -2 for a mismatch. Returns the best scoring length and the
+// number of matches in it.
+fn xdrop_extend<I: Iterator<Item = (u8, u8)>>(pairs: I, xdrop: i64) -> (usize, usize) {
+    let (mut score, mut best) = (0i64, 0i64);
+    let (mut matches, mut best_len, mut best_matches) = (0, 0, 0);
+    for (i, (a, b)) in pairs.enumerate() {
+        if a.eq_ignore_ascii_case(&b) && !a.eq_ignore_ascii_case(&b'N') {
+            score += 1;
+            matches += 1;
+        } else {
+            score -= 2;
+        }
+        if score > best {
+            best = score;
+            best_len = i + 1;
+            best_matches = matches;
+        } else if score < best - xdrop {
+            break;
+        }
+    }
+    (best_len, best_matches)
+}
+
+fn terminal_repeat(
+    kind: TerminalKind,
+    (length, matches): (usize, usize),
+    options: &StructureOptions,
+) -> Option<TerminalRepeat> {
+    if length < options.min_terminal {
+        return None;
+    }
+    let identity = matches as f64 / length as f64;
+    (identity >= options.min_identity).then_some(TerminalRepeat {
+        kind,
+        length,
+        identity,
+    })
+}
+
+// The start of the sequence against the reverse complement of its end.
+pub fn find_tir(seq: &[u8], options: &StructureOptions) -> Option<TerminalRepeat> {
+    let half = (seq.len() / 2).min(options.max_terminal);
+    let pairs = (0..half).map(|i| (seq[i], complement(seq[seq.len() - 1 - i])));
+    terminal_repeat(
+        TerminalKind::Tir,
+        xdrop_extend(pairs, options.xdrop),
+        options,
+    )
+}
+
+// The start of the sequence against a direct repeat ending at its
+// end. The length of the LTR isn't known, so each is tried and the
+// longest match kept. The match has to run to the end of the
+// sequence, bar the few bases the x-drop lets go unmatched, or it
+// is a repeat inside the element rather than a terminal one.
+pub fn find_ltr(seq: &[u8], options: &StructureOptions) -> Option<TerminalRepeat> {
+    let half = (seq.len() / 2).min(options.max_terminal);
+    let tolerance = (options.xdrop / 2).max(0) as usize;
+    let mut best = (0, 0);
+    for ltr in options.min_terminal..=half {
+        let right = seq.len() - ltr;
+        let pairs = (0..ltr).map(|i| (seq[i], seq[right + i]));
+        let extended = xdrop_extend(pairs, options.xdrop);
+        if extended.0 + tolerance >= ltr && extended.0 > best.0 {
+            best = extended;
+        }
+    }
+    terminal_repeat(TerminalKind::Ltr, best, options)
+}
+
+// The longest exact duplication of the end of `left` at the start
+// of `right`.
+pub fn find_tsd<'a>(left: &[u8], right: &'a [u8], options: &StructureOptions) -> Option<&'a [u8]> {
+    let max = options.max_tsd.min(left.len()).min(right.len());
+    (options.min_tsd..=max)
+        .rev()
+        .find(|&k| left[left.len() - k..].eq_ignore_ascii_case(&right[..k]))
+        .map(|k| &right[..k])
+}
+
+// The bases of an aligned copy before and after the element.
+pub fn copy_flanks(aligned: &[u8], edges: &Edges) -> (Vec<u8>, Vec<u8>) {
+    let bases = |columns: &[u8]| {
+        columns
+            .iter()
+            .filter(|c| !matches!(c, b'-' | b'.'))
+            .map(|c| c.to_ascii_uppercase())
+            .collect::<Vec<_>>()
+    };
+    (bases(&aligned[..edges.start]), bases(&aligned[edges.end..]))
+}
+
+// The TSDs of the copies of a family.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TsdSummary {
+    pub copies: usize,
+    pub with_tsd: usize,
+    // the most common TSD length
+    pub length: Option<usize>,
+    // the distinct TSDs of that length, most common first
+    pub sequences: Vec<String>,
+}
+
+pub fn summarise_tsds(
+    alignment: &[Vec<u8>],
+    edges: &Edges,
+    options: &StructureOptions,
+) -> TsdSummary {
+    let mut tsds = Vec::new();
+    for aligned in alignment {
+        let (left, right) = copy_flanks(aligned, edges);
+        if let Some(tsd) = find_tsd(&left, &right, options) {
+            tsds.push(String::from_utf8_lossy(tsd).to_string());
+        }
+    }
+
+    let mut lengths: HashMap<usize, usize> = HashMap::new();
+    for tsd in &tsds {
+        *lengths.entry(tsd.len()).or_default() += 1;
+    }
+    // ties go to the longer TSD, as short ones match by chance
+    let length = lengths
+        .iter()
+        .max_by_key(|(&len, &n)| (n, len))
+        .map(|(&len, _)| len);
+
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for tsd in tsds.iter().filter(|t| Some(t.len()) == length) {
+        *counts.entry(tsd).or_default() += 1;
+    }
+    let mut sequences = counts.into_iter().collect::<Vec<_>>();
+    sequences.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+    TsdSummary {
+        copies: alignment.len(),
+        with_tsd: tsds.len(),
+        length,
+        sequences: sequences.into_iter().map(|(s, _)| s.to_string()).collect(),
+    }
+}
+
+// The structural evidence for one family.
+#[derive(Debug, Clone)]
+pub struct StructureReport {
+    pub family: String,
+    pub length: usize,
+    pub tir: Option<TerminalRepeat>,
+    pub ltr: Option<TerminalRepeat>,
+    pub tsd: Option<TsdSummary>,
+}
+
+impl StructureReport {
+    pub fn new(
+        family: &str,
+        consensus: &[u8],
+        tsd: Option<TsdSummary>,
+        options: &StructureOptions,
+    ) -> Self {
+        StructureReport {
+            family: family.to_string(),
+            length: consensus.len(),
+            tir: find_tir(consensus, options),
+            ltr: find_ltr(consensus, options),
+            tsd,
+        }
+    }
+
+    // The structure the evidence points to. LTRs win over TIRs, as
+    // an LTR that starts TG and ends CA is itself a short TIR.
+    pub fn structure(&self) -> Option<TerminalKind> {
+        self.ltr.or(self.tir).map(|t| t.kind)
+    }
+}
+
+pub fn write_structure_tsv<W: Write>(reports: &[StructureReport], writer: W) -> Result<()> {
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_writer(writer);
+    wtr.write_record([
+        "family",
+        "length",
+        "tir_length",
+        "tir_identity",
+        "ltr_length",
+        "ltr_identity",
+        "copies",
+        "copies_with_tsd",
+        "tsd_length",
+        "tsds",
+        "structure",
+    ])?;
+
+    let length = |t: Option<TerminalRepeat>| t.map(|t| t.length.to_string()).unwrap_or_default();
+    let identity =
+        |t: Option<TerminalRepeat>| t.map(|t| format!("{:.3}", t.identity)).unwrap_or_default();
+    for r in reports {
+        let tsd = r.tsd.clone().unwrap_or_default();
+        wtr.write_record([
+            r.family.as_str(),
+            &r.length.to_string(),
+            &length(r.tir),
+            &identity(r.tir),
+            &length(r.ltr),
+            &identity(r.ltr),
+            &tsd.copies.to_string(),
+            &tsd.with_tsd.to_string(),
+            &tsd.length.map(|l| l.to_string()).unwrap_or_default(),
+            &tsd.sequences.join(","),
+            r.structure().map(|s| s.as_str()).unwrap_or(""),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bio::alphabets::dna::revcomp;
+
+    const INTERNAL: &[u8] = b"GATTACAGATTACACCCGGGTTTAAACCCGGGTTTAAAGATTACA";
+
+    #[test]
+    fn test_terminal_repeats() {
+        let options = StructureOptions::default();
+        let terminal = b"CAGGGTATCCGTACTTGA";
+
+        let dna = [terminal.as_slice(), INTERNAL, &revcomp(terminal)].concat();
+        let tir = find_tir(&dna, &options).unwrap();
+        assert_eq!(tir.length, terminal.len());
+        assert_eq!(tir.identity, 1.0);
+        assert_eq!(find_ltr(&dna, &options), None);
+
+        // one mismatch in the second LTR
+        let mut right = terminal.to_vec();
+        right[5] = b'A';
+        let ltr = [terminal.as_slice(), INTERNAL, &right].concat();
+        let found = find_ltr(&ltr, &options).unwrap();
+        assert_eq!(found.kind, TerminalKind::Ltr);
+        assert_eq!(found.length, terminal.len());
+        assert!((found.identity - 17.0 / 18.0).abs() < 1e-9);
+        assert_eq!(find_tir(&ltr, &options), None);
+
+        // a copy of the 5' end inside the element is not an LTR
+        let tandem = [
+            terminal.as_slice(),
+            INTERNAL,
+            terminal,
+            b"TCTCTCTCTCTCTCTCTCTC",
+        ]
+        .concat();
+        assert_eq!(find_ltr(&tandem, &options), None);
+
+        assert_eq!(find_tir(INTERNAL, &options), None);
+    }
+
+    #[test]
+    fn test_tsds() {
+        let options = StructureOptions::default();
+        assert_eq!(
+            find_tsd(b"ACGTTTAGGC", b"TTAGGCAC", &options),
+            Some(b"TTAGGC".as_slice())
+        );
+        assert_eq!(find_tsd(b"ACGT", b"GGGG", &options), None);
+
+        // element in columns 6-11, copies with TSDs of 5, 5 and 2
+        let alignment = vec![
+            b"GATTACTGTACAATTACGT".to_vec(),
+            b"-GGCATTGTACAGGCAT--".to_vec(),
+            b"AAAATGTGTACATGCCCCC".to_vec(),
+        ];
+        let edges = Edges {
+            start: 6,
+            end: 12,
+            left_drop: true,
+            right_drop: true,
+        };
+        assert_eq!(
+            copy_flanks(&alignment[1], &edges),
+            (b"GGCAT".to_vec(), b"GGCAT".to_vec())
+        );
+        let summary = summarise_tsds(&alignment, &edges, &options);
+        assert_eq!(summary.copies, 3);
+        assert_eq!(summary.with_tsd, 3);
+        assert_eq!(summary.length, Some(5));
+        assert_eq!(summary.sequences, vec!["ATTAC", "GGCAT"]);
+    }
+}
@@ -0,0 +1,265 @@
+// Compute a repeat summary equivalent to RepeatMasker's .tbl,
+// but from our own parse of the .out file, so downstream tools
+// can read TSV/JSON rather than scraping the .tbl text.
+//
+// Overlapping hits are resolved by score: each base is assigned
+// to the highest scoring hit that covers it, so bases are never
+// counted twice. Element counts are the number of distinct
+// RepeatMasker IDs (fragments of one insertion share an ID).
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use bio::io::fasta;
+use serde::Serialize;
+
+use crate::{
+    intervals::IntervalSet,
+    parse_rmout::{RmOutRecord, RmOutTable},
+    repeatmasker::repeatmasker_out_path,
+    staged_genome, CliArgs, Error, ErrorKind, Result, RESULTS,
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SummaryRow {
+    pub name: String,
+    pub elements: u64,
+    pub bases_masked: u64,
+    pub percent_genome: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RepeatSummary {
+    // genome length excluding Ns
+    pub genome_length: u64,
+    pub bases_masked: u64,
+    pub percent_masked: f64,
+    pub classes: Vec<SummaryRow>,
+    pub families: Vec<SummaryRow>,
+}
+
+#[derive(Default)]
+struct Tally {
+    elements: HashSet<(String, u64)>,
+    bases: u64,
+}
+
+fn percent(bases: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        bases as f64 / total as f64 * 100.0
+    }
+}
+
+// Count the bases in a FASTA file, ignoring N/n.
+pub fn genome_length_excluding_n<P: AsRef<Path> + std::fmt::Debug>(path: P) -> Result<u64> {
+    let reader = fasta::Reader::from_file(path)?;
+    let mut total = 0;
+    for record in reader.records() {
+        let record = record?;
+        total += record
+            .seq()
+            .iter()
+            .filter(|b| !matches!(b, b'N' | b'n'))
+            .count() as u64;
+    }
+    Ok(total)
+}
+
+impl RepeatSummary {
+    pub fn new(table: &RmOutTable, genome_length: u64) -> Self {
+        // group the hits by sequence
+        let mut by_query: HashMap<&str, Vec<&RmOutRecord>> = HashMap::new();
+        for record in &table.0 {
+            by_query.entry(&record.query).or_default().push(record);
+        }
+
+        let mut classes: BTreeMap<String, Tally> = BTreeMap::new();
+        let mut families: BTreeMap<String, Tally> = BTreeMap::new();
+        let mut bases_masked = 0;
+
+        for (query, mut hits) in by_query {
+            // highest score first, so it claims contested bases
+            hits.sort_by(|a, b| a.overlap_order(b));
+
+            let mut covered = IntervalSet::new();
+            for hit in hits {
+                let new_bases = covered.insert(hit.query_start, hit.query_end);
+                let key = (query.to_string(), hit.id);
+
+                let class = classes.entry(hit.class().to_string()).or_default();
+                class.bases += new_bases;
+                class.elements.insert(key.clone());
+
+                let family = families.entry(hit.class_family.clone()).or_default();
+                family.bases += new_bases;
+                family.elements.insert(key);
+            }
+            bases_masked += covered.covered();
+        }
+
+        let to_rows = |tallies: BTreeMap<String, Tally>| {
+            tallies
+                .into_iter()
+                .map(|(name, tally)| SummaryRow {
+                    name,
+                    elements: tally.elements.len() as u64,
+                    bases_masked: tally.bases,
+                    percent_genome: percent(tally.bases, genome_length),
+                })
+                .collect()
+        };
+
+        RepeatSummary {
+            genome_length,
+            bases_masked,
+            percent_masked: percent(bases_masked, genome_length),
+            classes: to_rows(classes),
+            families: to_rows(families),
+        }
+    }
+
+    // Write the summary as a TSV, with a `level` column
+    // distinguishing class rows from family rows.
+    pub fn write_tsv<W: Write>(&self, writer: W) -> Result<()> {
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_writer(writer);
+        wtr.write_record([
+            "level",
+            "name",
+            "elements",
+            "bases_masked",
+            "percent_genome",
+        ])?;
+
+        let rows = self
+            .classes
+            .iter()
+            .map(|r| ("class", r))
+            .chain(self.families.iter().map(|r| ("family", r)));
+        for (level, row) in rows {
+            wtr.write_record([
+                level,
+                &row.name,
+                &row.elements.to_string(),
+                &row.bases_masked.to_string(),
+                &format!("{:.4}", row.percent_genome),
+            ])?;
+        }
+        wtr.write_record([
+            "total",
+            "total",
+            &self
+                .classes
+                .iter()
+                .map(|r| r.elements)
+                .sum::<u64>()
+                .to_string(),
+            &self.bases_masked.to_string(),
+            &format!("{:.4}", self.percent_masked),
+        ])?;
+        wtr.flush()?;
+        Ok(())
+    }
+
+    pub fn write_json<W: Write>(&self, writer: W) -> Result<()> {
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+}
+
+// Summarise the RepeatMasker output of a pipeline run, writing
+// <genome>.summary.tsv and <genome>.summary.json to the results dir.
+pub fn summarise_repeatmasker(matches: &CliArgs) -> Result<()> {
+    let out_path = repeatmasker_out_path(matches)?;
+    if !out_path.exists() {
+        return Err(Error::new(ErrorKind::GenericCli(format!(
+            "RepeatMasker output {} not found. Did RepeatMasker run?",
+            out_path.display()
+        ))));
+    }
+
+    let table = RmOutTable::from_file(&out_path)?;
+    let genome = staged_genome(matches)?;
+    let genome_length = genome_length_excluding_n(&genome)?;
+    let summary = RepeatSummary::new(&table, genome_length);
+
+    let mut results = matches.configure.clone().unwrap();
+    results.push(RESULTS);
+    let stem = genome
+        .file_name()
+        .map(|f| f.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let tsv = results.join(format!("{}.summary.tsv", stem));
+    summary.write_tsv(BufWriter::new(File::create(&tsv)?))?;
+    let json = results.join(format!("{}.summary.json", stem));
+    summary.write_json(BufWriter::new(File::create(&json)?))?;
+
+    eprintln!(
+        "Masked {} of {} bp ({:.2}%). Summary written to {}",
+        summary.bases_masked,
+        summary.genome_length,
+        summary.percent_masked,
+        tsv.display()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUT: &str = "
+  500   10.0  0.0  0.0  chr1    1   100 (900) + L1MA1   LINE/L1   1 100 (5900) 1
+  300   10.0  0.0  0.0  chr1   51   150 (850) + AluY    SINE/Alu  1 100  (200) 2
+  400   10.0  0.0  0.0  chr1  201   250 (750) + L1MA1   LINE/L1 101 150 (5850) 1
+  100   10.0  0.0  0.0  chr2    1    50 (950) + (CA)n   Simple_repeat 1 50 (0) 3
+";
+
+    #[test]
+    fn test_summary_resolves_overlaps() {
+        let table = RmOutTable::from_reader(OUT.as_bytes()).unwrap();
+        let summary = RepeatSummary::new(&table, 2000);
+
+        // 1..150 and 201..250 on chr1, 1..50 on chr2
+        assert_eq!(summary.bases_masked, 250);
+        assert_eq!(summary.percent_masked, 12.5);
+
+        let line = summary
+            .families
+            .iter()
+            .find(|r| r.name == "LINE/L1")
+            .unwrap();
+        // the two fragments share an ID so form one element
+        assert_eq!(line.elements, 1);
+        assert_eq!(line.bases_masked, 150);
+
+        // the Alu loses the 50 bases it shares with the higher scoring L1
+        let alu = summary.classes.iter().find(|r| r.name == "SINE").unwrap();
+        assert_eq!(alu.bases_masked, 50);
+        assert_eq!(alu.percent_genome, 2.5);
+
+        let mut tsv = Vec::new();
+        summary.write_tsv(&mut tsv).unwrap();
+        let tsv = String::from_utf8(tsv).unwrap();
+        assert!(tsv.starts_with("level\tname\telements\tbases_masked\tpercent_genome\n"));
+        assert!(tsv.contains("family\tLINE/L1\t1\t150\t7.5000\n"));
+        assert!(tsv.ends_with("total\ttotal\t3\t250\t12.5000\n"));
+    }
+
+    #[test]
+    fn test_genome_length_excludes_n() {
+        let dir = tempfile::tempdir().unwrap();
+        let fasta = dir.path().join("genome.fa");
+        std::fs::write(&fasta, ">a\nACGTNNNN\n>b\nnnACG\n").unwrap();
+        assert_eq!(genome_length_excluding_n(&fasta).unwrap(), 7);
+    }
+}